@@ -0,0 +1,232 @@
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use spin::Once;
+use x86_64::PhysAddr;
+use crate::memory;
+
+// This file finds and parses the tables of the Advanced Configuration and Power Interface (ACPI),
+// through which the firmware describes the hardware of the machine:
+//
+//  RSDP ---> RSDT/XSDT ---> MADT (CPUs, I/O APICs and how ISA IRQs are wired to them)
+//  (found in              > FADT (power management registers, reset register) ---> DSDT
+//   the BIOS area)        > HPET (High Precision Event Timer)
+//                         > MCFG (memory mapped PCI configuration space)
+//
+// All tables live in physical memory that the bootloader maps at the physical memory offset, so
+// they are read through `memory::phys_to_virt`. Fields are read byte by byte since the tables are
+// packed and not necessarily aligned.
+
+pub mod madt;
+pub mod fadt;
+pub mod hpet;
+pub mod mcfg;
+
+pub use madt::Madt;
+pub use fadt::Fadt;
+pub use hpet::Hpet;
+pub use mcfg::Mcfg;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_V1_LENGTH: usize = 20;
+const RSDP_V2_LENGTH: usize = 36;
+
+// The RSDP is either in the first KiB of the Extended BIOS Data Area (EBDA), whose segment is
+// stored at 0x40e, or in the BIOS ROM area between 0xe0000 and 0xfffff. It is 16 byte aligned
+const EBDA_SEGMENT_POINTER: u64 = 0x40e;
+const BIOS_AREA_START: u64 = 0xe0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+/// Length of the header every System Description Table (SDT) starts with
+pub const SDT_HEADER_LENGTH: usize = 36;
+
+static ACPI: Once<AcpiTables> = Once::new();
+
+#[derive(Debug)]
+pub enum AcpiError {
+    /// No valid Root System Description Pointer (RSDP) was found
+    NoRsdp,
+    /// The root table (RSDT or XSDT) the RSDP points to is not valid
+    InvalidRootTable,
+}
+
+/// The ACPI tables the kernel knows about, parsed into typed structures
+#[derive(Debug)]
+pub struct AcpiTables {
+    /// ACPI revision of the RSDP, 0 for ACPI 1.0 and 2 for ACPI 2.0 and later
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    /// Signatures of all tables listed in the RSDT/XSDT, including those not parsed
+    pub signatures: Vec<[u8; 4]>,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    pub mcfg: Option<Mcfg>,
+}
+
+/// The Generic Address Structure (GAS) ACPI uses to describe the location of registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    /// 0 undefined, 1 byte, 2 word, 3 dword, 4 qword access
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+impl GenericAddress {
+    /// Parses the 12 byte Generic Address Structure at the start of `bytes`
+    fn parse(bytes: &[u8]) -> GenericAddress {
+        GenericAddress {
+            address_space: match bytes[0] {
+                0 => AddressSpace::SystemMemory,
+                1 => AddressSpace::SystemIo,
+                2 => AddressSpace::PciConfig,
+                other => AddressSpace::Other(other),
+            },
+            bit_width: bytes[1],
+            bit_offset: bytes[2],
+            access_size: bytes[3],
+            address: read_u64(bytes, 4),
+        }
+    }
+}
+
+/// Finds and parses the ACPI tables. Needs the heap and `memory::init`
+///
+/// Only the first call does any work, later calls return the same tables.
+pub fn init() -> Result<&'static AcpiTables, AcpiError> {
+    if let Some(tables) = ACPI.r#try() {
+        return Ok(tables);
+    }
+    let tables = unsafe { parse_tables() }?;
+    Ok(ACPI.call_once(|| tables))
+}
+
+/// Returns the ACPI tables if `init` found them
+pub fn tables() -> Option<&'static AcpiTables> {
+    ACPI.r#try()
+}
+
+unsafe fn parse_tables() -> Result<AcpiTables, AcpiError> {
+    let rsdp = find_rsdp().ok_or(AcpiError::NoRsdp)?;
+    let revision = rsdp[15];
+    let oem_id = rsdp[9..15].try_into().unwrap();
+
+    // ACPI 2.0 added the XSDT, which holds 64 bit table addresses instead of 32 bit ones
+    let (root, entry_size) = if revision >= 2 && read_u64(rsdp, 24) != 0 {
+        (PhysAddr::new(read_u64(rsdp, 24)), 8)
+    } else {
+        (PhysAddr::new(u64::from(read_u32(rsdp, 16))), 4)
+    };
+    let root = sdt(root).ok_or(AcpiError::InvalidRootTable)?;
+
+    let mut tables = AcpiTables {
+        revision,
+        oem_id,
+        signatures: Vec::new(),
+        madt: None,
+        fadt: None,
+        hpet: None,
+        mcfg: None,
+    };
+    // A truncated last entry is ignored
+    for entry in root[SDT_HEADER_LENGTH..].chunks_exact(entry_size) {
+        let address = if entry_size == 8 {
+            read_u64(entry, 0)
+        } else {
+            u64::from(read_u32(entry, 0))
+        };
+        let table = match sdt(PhysAddr::new(address)) {
+            Some(table) => table,
+            None => continue, // skip tables with a bad checksum
+        };
+        let signature: [u8; 4] = table[0..4].try_into().unwrap();
+        match &signature {
+            b"APIC" => tables.madt = Some(Madt::parse(table)),
+            b"FACP" => tables.fadt = Some(Fadt::parse(table)),
+            b"HPET" => tables.hpet = Some(Hpet::parse(table)),
+            b"MCFG" => tables.mcfg = Some(Mcfg::parse(table)),
+            _ => {}
+        }
+        tables.signatures.push(signature);
+    }
+    Ok(tables)
+}
+
+/// Searches the EBDA and the BIOS area for the RSDP and returns its bytes
+unsafe fn find_rsdp() -> Option<&'static [u8]> {
+    let ebda = u64::from(read_u16(physical_bytes(PhysAddr::new(EBDA_SEGMENT_POINTER), 2), 0)) << 4;
+    let areas = [(ebda, ebda + 1024), (BIOS_AREA_START, BIOS_AREA_END)];
+    for &(start, end) in areas.iter().filter(|&&(start, _)| start != 0) {
+        for address in (start..end).step_by(16) {
+            let candidate = physical_bytes(PhysAddr::new(address), RSDP_V1_LENGTH);
+            if &candidate[0..8] != RSDP_SIGNATURE || !checksum_ok(candidate) {
+                continue;
+            }
+            if candidate[15] >= 2 {
+                // ACPI 2.0 extended the RSDP, with a checksum covering the whole structure
+                let extended = physical_bytes(PhysAddr::new(address), RSDP_V2_LENGTH);
+                if checksum_ok(extended) {
+                    return Some(extended);
+                }
+            } else {
+                return Some(candidate);
+            }
+        }
+    }
+    None
+}
+
+/// Returns the bytes of the System Description Table at `address` if its checksum is valid
+///
+/// # Safety
+///
+/// `address` must point to an ACPI table, and `memory::init` must have been called.
+pub unsafe fn sdt(address: PhysAddr) -> Option<&'static [u8]> {
+    if address.as_u64() == 0 {
+        return None;
+    }
+    let header = physical_bytes(address, SDT_HEADER_LENGTH);
+    let length = read_u32(header, 4) as usize;
+    if length < SDT_HEADER_LENGTH {
+        return None;
+    }
+    let table = physical_bytes(address, length);
+    if checksum_ok(table) { Some(table) } else { None }
+}
+
+unsafe fn physical_bytes(address: PhysAddr, length: usize) -> &'static [u8] {
+    core::slice::from_raw_parts(memory::phys_to_virt(address).as_ptr(), length)
+}
+
+/// All bytes of an ACPI structure, including its checksum byte, must add up to 0
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[test_case]
+fn test_checksum() {
+    assert!(checksum_ok(&[0x10, 0x20, 0xd0]));
+    assert!(!checksum_ok(&[0x10, 0x20, 0xd1]));
+}
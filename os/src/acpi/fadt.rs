@@ -0,0 +1,87 @@
+use x86_64::PhysAddr;
+use super::{read_u16, read_u32, read_u64, GenericAddress};
+
+// The Fixed ACPI Description Table (FADT, signature "FACP") describes the fixed power management
+// hardware: where the PM1 control registers used to enter sleep states are, how to switch the
+// machine into ACPI mode, and the reset register. It also points to the DSDT, the AML code that
+// describes the rest of the hardware. The table grew with every ACPI revision, so fields past the
+// end of an older table read as 0.
+
+const FADT_MAX_LENGTH: usize = 276; // ACPI 6.4
+
+const FLAG_RESET_REG_SUPPORTED: u32 = 1 << 10;
+
+// IA-PC boot architecture flags
+const BOOT_ARCH_8042: u16 = 1 << 1; // a PS/2 controller is present
+
+#[derive(Debug, Clone)]
+pub struct Fadt {
+    /// Physical address of the Differentiated System Description Table (DSDT)
+    pub dsdt: PhysAddr,
+    /// ISA IRQ of the System Control Interrupt (SCI)
+    pub sci_interrupt: u16,
+    /// Port to write `acpi_enable` to, to switch the machine from legacy into ACPI mode. 0 if the
+    /// machine is always in ACPI mode
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    /// I/O ports of the PM1 event and control register blocks. The b blocks are optional (0)
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    /// I/O port of the 24 or 32 bit ACPI power management timer, 0 if there is none
+    pub pm_timer_block: u32,
+    /// CMOS register of the RTC century, 0 if the RTC has none
+    pub century_register: u8,
+    pub boot_architecture_flags: u16,
+    pub flags: u32,
+    /// Writing `reset_value` to this register resets the machine
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    /// Parses the FADT from the bytes of the table, including its header
+    pub(super) fn parse(table: &[u8]) -> Fadt {
+        // Copy the table into a buffer of the latest size so missing fields read as 0
+        let mut bytes = [0u8; FADT_MAX_LENGTH];
+        let length = table.len().min(FADT_MAX_LENGTH);
+        bytes[..length].copy_from_slice(&table[..length]);
+
+        // ACPI 2.0 added a 64 bit DSDT address that takes precedence over the 32 bit one
+        let dsdt = match read_u64(&bytes, 140) {
+            0 => u64::from(read_u32(&bytes, 40)),
+            x_dsdt => x_dsdt,
+        };
+        let flags = read_u32(&bytes, 112);
+        Fadt {
+            dsdt: PhysAddr::new(dsdt),
+            sci_interrupt: read_u16(&bytes, 46),
+            smi_command_port: read_u32(&bytes, 48),
+            acpi_enable: bytes[52],
+            acpi_disable: bytes[53],
+            pm1a_event_block: read_u32(&bytes, 56),
+            pm1b_event_block: read_u32(&bytes, 60),
+            pm1a_control_block: read_u32(&bytes, 64),
+            pm1b_control_block: read_u32(&bytes, 68),
+            pm_timer_block: read_u32(&bytes, 76),
+            century_register: bytes[108],
+            boot_architecture_flags: read_u16(&bytes, 109),
+            flags,
+            reset_register: if flags & FLAG_RESET_REG_SUPPORTED != 0 {
+                Some(GenericAddress::parse(&bytes[116..128]))
+            } else {
+                None
+            },
+            reset_value: bytes[128],
+        }
+    }
+
+    /// Returns whether the machine has a PS/2 (8042) controller
+    ///
+    /// ACPI 1.0 tables do not have this flag, so their machines are assumed to have one.
+    pub fn has_8042(&self) -> bool {
+        self.boot_architecture_flags == 0 || self.boot_architecture_flags & BOOT_ARCH_8042 != 0
+    }
+}
@@ -0,0 +1,37 @@
+use super::{read_u16, read_u32, GenericAddress, SDT_HEADER_LENGTH};
+
+// The HPET table describes a High Precision Event Timer: a counter running at 10 MHz or more
+// with a number of comparators that can raise interrupts, meant to replace the PIT and RTC timers.
+
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub hardware_revision: u8,
+    pub comparator_count: u8,
+    /// Whether the main counter is 64 bits wide instead of 32 bits
+    pub counter_64_bit: bool,
+    /// Whether the HPET can take over IRQ 0 and IRQ 8 from the PIT and RTC
+    pub legacy_replacement: bool,
+    pub pci_vendor_id: u16,
+    /// Location of the memory mapped HPET registers
+    pub address: GenericAddress,
+    pub hpet_number: u8,
+    /// Minimum number of counter ticks for a periodic interrupt without losing interrupts
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    /// Parses the HPET table from the bytes of the table, including its header
+    pub(super) fn parse(table: &[u8]) -> Hpet {
+        let id = read_u32(table, SDT_HEADER_LENGTH);
+        Hpet {
+            hardware_revision: (id & 0xff) as u8,
+            comparator_count: ((id >> 8) & 0x1f) as u8 + 1,
+            counter_64_bit: id & (1 << 13) != 0,
+            legacy_replacement: id & (1 << 15) != 0,
+            pci_vendor_id: (id >> 16) as u16,
+            address: GenericAddress::parse(&table[SDT_HEADER_LENGTH + 4..SDT_HEADER_LENGTH + 16]),
+            hpet_number: table[SDT_HEADER_LENGTH + 16],
+            minimum_tick: read_u16(table, SDT_HEADER_LENGTH + 17),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use alloc::vec::Vec;
+use super::{read_u16, read_u32, read_u64, SDT_HEADER_LENGTH};
+
+// The Multiple APIC Description Table (MADT, signature "APIC") lists the local APIC of every CPU,
+// the I/O APICs and the differences between the ISA IRQ numbers and the I/O APIC inputs (Global
+// System Interrupts, GSIs) they are wired to. It is followed by a list of variable length entries.
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const ENTRY_LOCAL_X2APIC: u8 = 9;
+
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+const PROCESSOR_ONLINE_CAPABLE: u32 = 1 << 1;
+const PCAT_COMPAT: u32 = 1 << 0; // the machine also has 8259 PICs
+
+#[derive(Debug)]
+pub struct Madt {
+    /// Physical address of the local APIC of each CPU
+    pub local_apic_address: u64,
+    /// Whether the machine also has the legacy 8259 PICs, which must be masked to use the APIC
+    pub has_8259_pics: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub interrupt_overrides: Vec<InterruptSourceOverride>,
+    pub local_apic_nmis: Vec<LocalApicNmi>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    /// ID the ACPI namespace uses for the processor
+    pub processor_id: u32,
+    pub apic_id: u32,
+    /// Whether the CPU can be used. If not, it may still be `online_capable` (hot-pluggable)
+    pub enabled: bool,
+    pub online_capable: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    /// First Global System Interrupt handled by this I/O APIC
+    pub gsi_base: u32,
+}
+
+/// Describes an ISA IRQ that is not wired to the I/O APIC input of the same number, or that does
+/// not use the ISA defaults of active high, edge triggered signals
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    BusDefault,
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    BusDefault,
+    Edge,
+    Level,
+}
+
+/// Describes which local APIC interrupt input (LINT0 or LINT1) is connected to the NMI line
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    /// Processor the entry applies to, or `None` for all processors
+    pub processor_id: Option<u8>,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+    pub lint: u8,
+}
+
+impl Madt {
+    /// Parses the MADT from the bytes of the table, including its header
+    pub(super) fn parse(table: &[u8]) -> Madt {
+        let mut madt = Madt {
+            local_apic_address: u64::from(read_u32(table, SDT_HEADER_LENGTH)),
+            has_8259_pics: read_u32(table, SDT_HEADER_LENGTH + 4) & PCAT_COMPAT != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            interrupt_overrides: Vec::new(),
+            local_apic_nmis: Vec::new(),
+        };
+
+        let mut offset = SDT_HEADER_LENGTH + 8;
+        while offset + 2 <= table.len() {
+            let length = usize::from(table[offset + 1]);
+            if length < 2 || offset + length > table.len() {
+                break; // malformed entry, ignore the rest of the table
+            }
+            let entry = &table[offset..offset + length];
+            match entry[0] {
+                ENTRY_LOCAL_APIC if length >= 8 => {
+                    let flags = read_u32(entry, 4);
+                    madt.processors.push(Processor {
+                        processor_id: u32::from(entry[2]),
+                        apic_id: u32::from(entry[3]),
+                        enabled: flags & PROCESSOR_ENABLED != 0,
+                        online_capable: flags & PROCESSOR_ONLINE_CAPABLE != 0,
+                    });
+                }
+                ENTRY_IO_APIC if length >= 12 => madt.io_apics.push(IoApic {
+                    id: entry[2],
+                    address: read_u32(entry, 4),
+                    gsi_base: read_u32(entry, 8),
+                }),
+                ENTRY_INTERRUPT_SOURCE_OVERRIDE if length >= 10 => {
+                    let flags = read_u16(entry, 8);
+                    madt.interrupt_overrides.push(InterruptSourceOverride {
+                        irq: entry[3],
+                        gsi: read_u32(entry, 4),
+                        polarity: polarity(flags),
+                        trigger_mode: trigger_mode(flags),
+                    });
+                }
+                ENTRY_LOCAL_APIC_NMI if length >= 6 => {
+                    let flags = read_u16(entry, 3);
+                    madt.local_apic_nmis.push(LocalApicNmi {
+                        processor_id: if entry[2] == 0xff { None } else { Some(entry[2]) },
+                        polarity: polarity(flags),
+                        trigger_mode: trigger_mode(flags),
+                        lint: entry[5],
+                    });
+                }
+                ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE if length >= 12 => {
+                    madt.local_apic_address = read_u64(entry, 4);
+                }
+                ENTRY_LOCAL_X2APIC if length >= 16 => {
+                    let flags = read_u32(entry, 8);
+                    madt.processors.push(Processor {
+                        processor_id: read_u32(entry, 12),
+                        apic_id: read_u32(entry, 4),
+                        enabled: flags & PROCESSOR_ENABLED != 0,
+                        online_capable: flags & PROCESSOR_ONLINE_CAPABLE != 0,
+                    });
+                }
+                _ => {}
+            }
+            offset += length;
+        }
+        madt
+    }
+
+    /// Returns the Global System Interrupt, polarity and trigger mode of an ISA IRQ
+    pub fn isa_irq(&self, irq: u8) -> (u32, Polarity, TriggerMode) {
+        match self.interrupt_overrides.iter().find(|entry| entry.irq == irq) {
+            Some(entry) => (entry.gsi, entry.polarity, entry.trigger_mode),
+            None => (u32::from(irq), Polarity::BusDefault, TriggerMode::BusDefault),
+        }
+    }
+}
+
+// Bits 0-1 of the MPS INTI flags
+fn polarity(flags: u16) -> Polarity {
+    match flags & 0b11 {
+        0b01 => Polarity::ActiveHigh,
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::BusDefault,
+    }
+}
+
+// Bits 2-3 of the MPS INTI flags
+fn trigger_mode(flags: u16) -> TriggerMode {
+    match (flags >> 2) & 0b11 {
+        0b01 => TriggerMode::Edge,
+        0b11 => TriggerMode::Level,
+        _ => TriggerMode::BusDefault,
+    }
+}
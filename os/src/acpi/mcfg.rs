@@ -0,0 +1,42 @@
+use alloc::vec::Vec;
+use super::{read_u16, read_u64, SDT_HEADER_LENGTH};
+
+// The MCFG table lists where the PCI Express Enhanced Configuration Access Mechanism (ECAM) maps
+// the configuration space of each PCI segment group into memory. Each function gets 4 KiB at
+// base_address + (bus << 20 | device << 15 | function << 12).
+
+const ENTRY_LENGTH: usize = 16;
+
+#[derive(Debug)]
+pub struct Mcfg {
+    pub entries: Vec<McfgEntry>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct McfgEntry {
+    /// Physical address of the configuration space of bus 0 (even if `start_bus` is not 0)
+    pub base_address: u64,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl Mcfg {
+    /// Parses the MCFG from the bytes of the table, including its header. Invalid entries are
+    /// left out
+    pub(super) fn parse(table: &[u8]) -> Mcfg {
+        // The entries follow 8 reserved bytes after the header
+        let entries = table.get(SDT_HEADER_LENGTH + 8..).unwrap_or(&[])
+            .chunks_exact(ENTRY_LENGTH)
+            .map(|entry| McfgEntry {
+                base_address: read_u64(entry, 0),
+                segment_group: read_u16(entry, 8),
+                start_bus: entry[10],
+                end_bus: entry[11],
+            })
+            // Firmware bugs: a region without an address, or with its buses the wrong way round
+            .filter(|entry| entry.base_address != 0 && entry.start_bus <= entry.end_bus)
+            .collect();
+        Mcfg { entries }
+    }
+}
@@ -1,5 +1,6 @@
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
+use crate::sync::{IrqSpinLock, IrqSpinLockGuard};
 use bump::BumpAllocator;
 use linked_list::LinkedListAllocator;
 use fixed_size_block::FixedSizeBlockAllocator;
@@ -13,7 +14,8 @@ use x86_64::{
 
 pub const HEAP_START: usize = 0x_4444_4444_0000; // Create easily recognizable pointer to virtual
                                                  // memory range for our heap region
-pub const HEAP_SIZE: usize = 100 * 1024; // 100KiB
+pub const HEAP_SIZE: usize = 2 * 1024 * 1024; // 2MiB, the scrollback of each of the six
+                                               // consoles takes ~80KiB
 
 pub mod bump;
 pub mod linked_list;
@@ -33,19 +35,27 @@ pub mod fixed_size_block;
 static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
 
 
-/// A wrapper around spin::Mutex to permit trait implementations
+/// Returns how many bytes of the heap are in use and how big it is
+pub fn heap_usage() -> (usize, usize) {
+    let allocator = ALLOCATOR.lock();
+    (allocator.used(), allocator.size())
+}
+
+/// A wrapper around IrqSpinLock to permit trait implementations
+///
+/// Interrupt handlers allocate too, so the lock disables interrupts while it is held.
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: IrqSpinLock<A>,
 }
 
 impl <A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked{
-            inner: spin::Mutex::new(inner),
+            inner: IrqSpinLock::new(inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    pub fn lock(&self) -> IrqSpinLockGuard<A> {
         self.inner.lock()
     }
 }
@@ -44,6 +44,17 @@ impl FixedSizeBlockAllocator {
         self.fallback_allocator.init(heap_start, heap_size);
     }
 
+    /// Returns how many bytes of the heap are in use, including freed blocks kept in the lists
+    /// for reuse
+    pub fn used(&self) -> usize {
+        self.fallback_allocator.used()
+    }
+
+    /// Returns the size of the heap in bytes
+    pub fn size(&self) -> usize {
+        self.fallback_allocator.size()
+    }
+
     /// Allocates using the fallback allocator
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
         match self.fallback_allocator.allocate_first_fit(layout) {
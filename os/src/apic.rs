@@ -0,0 +1,308 @@
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, Size4KiB};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::interrupts::{self, InterruptIndex};
+use crate::acpi::madt::{Polarity, TriggerMode};
+use crate::{acpi, memory, time};
+use crate::sync::IrqSpinLock;
+
+// This file drives the Advanced Programmable Interrupt Controller (APIC), which replaces the
+// legacy 8259 PICs. Every CPU core has a local APIC that receives interrupts, is told when they
+// are handled (End Of Interrupt) and contains a timer. The I/O APIC receives the interrupt lines
+// of the devices and forwards them to the local APICs according to its redirection table.
+//
+//  Keyboard ------> |         |                  |            |
+//  Mouse ---------> | I/O APIC|----------------> | Local APIC |---> CPU
+//  Serial Port ---> |         |     Timer -----> |            |
+//
+// ISA IRQ n is routed to the same vector the remapped PICs used (PIC_1_OFFSET + n), so the
+// interrupt handlers work with both controllers. IRQ 0 (the PIT) stays masked because the local
+// APIC timer takes over the timer interrupt. The ACPI MADT tells us where the I/O APIC is and which
+// of its inputs each ISA IRQ is wired to; without ACPI, the PC defaults are assumed.
+
+/// Interrupt vector of the local APIC spurious interrupt. Must not be acknowledged with an EOI
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Physical address of the I/O APIC on PCs without an ACPI MADT
+pub const IO_APIC_DEFAULT_ADDRESS: u64 = 0xfec0_0000;
+
+const IA32_APIC_BASE_MSR: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+const CPUID_FEATURES_EDX_APIC: u32 = 1 << 9;
+
+// Local APIC registers, as offsets from its base address. Each one is 32 bits wide, 16 byte aligned
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS_VECTOR: usize = 0xf0;
+const LAPIC_ICR_LOW: usize = 0x300; // Interrupt Command Register, sends Inter-Processor Interrupts
+const LAPIC_ICR_HIGH: usize = 0x310;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL_COUNT: usize = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE_CONFIG: usize = 0x3e0;
+
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8; // in the spurious vector register
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+// Number of PIT ticks the local APIC timer is measured against
+const CALIBRATION_TICKS: u64 = 10;
+
+// I/O APIC registers are accessed indirectly: the register number is written to IOREGSEL and the
+// value is then read from or written to IOWIN
+const IOAPIC_IOREGSEL: usize = 0x00;
+const IOAPIC_IOWIN: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10; // two registers per entry
+const IOAPIC_ACTIVE_LOW: u32 = 1 << 13;
+const IOAPIC_LEVEL_TRIGGERED: u32 = 1 << 15;
+
+// ISA IRQ 2 is where the secondary PIC is chained to the primary one, no device raises it
+const ISA_CASCADE_IRQ: u8 = 2;
+
+/// Number of interrupt lines of the ISA bus (and the 8259 PICs)
+pub const ISA_IRQS: u8 = 16;
+
+// Virtual address of the local APIC registers, or 0 while the 8259 PICs are in use
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+static IO_APIC: IrqSpinLock<Option<IoApic>> = IrqSpinLock::new(None);
+
+#[derive(Debug)]
+pub enum ApicError {
+    /// The CPU does not have a local APIC
+    NotSupported,
+    /// The ACPI MADT does not list an I/O APIC
+    NoIoApic,
+    /// The APIC registers could not be mapped into the address space
+    Mapping(MapToError<Size4KiB>),
+}
+
+/// Returns whether the CPU has a local APIC, according to CPUID
+pub fn is_supported() -> bool {
+    __cpuid(1).edx & CPUID_FEATURES_EDX_APIC != 0
+}
+
+/// Returns whether interrupts are delivered through the APIC instead of the 8259 PICs
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.load(Ordering::Relaxed) != 0
+}
+
+/// Switches interrupt delivery from the 8259 PICs to the local APIC and I/O APIC
+///
+/// The local APIC timer is calibrated against the PIT and then takes over the timer interrupt at
+/// the same rate, so `time::ticks` keeps counting at `time::tick_hz`. Must be called after
+/// `crate::init`, with interrupts enabled, and after `acpi::init` to use the MADT. Of the ISA IRQs,
+/// only those with a handler registered through `interrupts::register_irq` are unmasked.
+pub fn init(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), ApicError> {
+    if !is_supported() {
+        return Err(ApicError::NotSupported);
+    }
+
+    let mut apic_base = Msr::new(IA32_APIC_BASE_MSR);
+    let base = unsafe { apic_base.read() };
+    unsafe { apic_base.write(base | APIC_BASE_ENABLE) };
+    let local_apic = memory::map_physical_region(PhysAddr::new(base & APIC_BASE_ADDRESS_MASK),
+                                                 4096, mapper, frame_allocator)
+        .map_err(ApicError::Mapping)?;
+    let madt = acpi::tables().and_then(|tables| tables.madt.as_ref());
+    let (io_apic_address, gsi_base) = match madt {
+        Some(madt) => {
+            let io_apic = madt.io_apics.first().ok_or(ApicError::NoIoApic)?;
+            (u64::from(io_apic.address), io_apic.gsi_base)
+        }
+        None => (IO_APIC_DEFAULT_ADDRESS, 0),
+    };
+    let io_apic = memory::map_physical_region(PhysAddr::new(io_apic_address), 4096, mapper,
+                                              frame_allocator)
+        .map_err(ApicError::Mapping)?;
+
+    enable_local_apic(local_apic);
+    let counts_per_tick = calibrate_timer(local_apic);
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // From here on the PICs must not deliver interrupts anymore
+        unsafe { interrupts::PICS.lock().disable() };
+
+        let mut io_apic = IoApic { base: io_apic, isa_inputs: [None; ISA_IRQS as usize] };
+        let destination = (mmio_read(local_apic, LAPIC_ID) >> 24) as u8;
+        let inputs = io_apic.redirection_entries();
+        for irq in (0..ISA_IRQS).filter(|&irq| irq != ISA_CASCADE_IRQ) {
+            let (gsi, polarity, trigger_mode) = match madt {
+                Some(madt) => madt.isa_irq(irq),
+                None => (u32::from(irq), Polarity::BusDefault, TriggerMode::BusDefault),
+            };
+            let input = match gsi.checked_sub(gsi_base) {
+                Some(input) if input < u32::from(inputs) => input as u8,
+                _ => continue, // wired to another I/O APIC, which we don't support
+            };
+            // ISA interrupts are active high and edge triggered unless the MADT says otherwise
+            let mut flags = if interrupts::has_irq_handler(irq) { 0 } else { LVT_MASKED };
+            if polarity == Polarity::ActiveLow {
+                flags |= IOAPIC_ACTIVE_LOW;
+            }
+            if trigger_mode == TriggerMode::Level {
+                flags |= IOAPIC_LEVEL_TRIGGERED;
+            }
+            io_apic.set_redirection(input, interrupts::PIC_1_OFFSET + irq, destination, flags);
+            io_apic.isa_inputs[usize::from(irq)] = Some(input);
+        }
+        *IO_APIC.lock() = Some(io_apic);
+
+        mmio_write(local_apic, LAPIC_LVT_TIMER,
+                   LVT_TIMER_PERIODIC | u32::from(InterruptIndex::Timer.as_u8()));
+        mmio_write(local_apic, LAPIC_TIMER_INITIAL_COUNT, counts_per_tick);
+        LOCAL_APIC.store(local_apic.as_u64(), Ordering::Relaxed);
+    });
+    Ok(())
+}
+
+/// Enables the local APIC of the calling CPU. Used by all CPUs but the boot CPU, after `init`
+///
+/// The local APIC timer is left off, the timer interrupt only comes to the boot CPU.
+pub fn init_cpu() {
+    let local_apic = LOCAL_APIC.load(Ordering::Relaxed);
+    if local_apic != 0 {
+        enable_local_apic(VirtAddr::new(local_apic));
+    }
+}
+
+/// Accepts all interrupts and enables the local APIC at `local_apic`
+fn enable_local_apic(local_apic: VirtAddr) {
+    mmio_write(local_apic, LAPIC_TASK_PRIORITY, 0);
+    mmio_write(local_apic, LAPIC_SPURIOUS_VECTOR,
+               LAPIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+}
+
+/// Returns the ID of the local APIC of the calling CPU, or 0 before `init`
+pub fn local_apic_id() -> u32 {
+    match LOCAL_APIC.load(Ordering::Relaxed) {
+        0 => 0,
+        local_apic => mmio_read(VirtAddr::new(local_apic), LAPIC_ID) >> 24,
+    }
+}
+
+/// Sends an INIT Inter-Processor Interrupt, which resets the CPU with the given local APIC ID
+pub fn send_init(apic_id: u32) {
+    send_ipi(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Sends a Startup Inter-Processor Interrupt (SIPI), which starts the CPU with the given local
+/// APIC ID in real mode at physical address `page << 12`
+pub fn send_startup(apic_id: u32, page: u8) {
+    send_ipi(apic_id, ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | u32::from(page));
+}
+
+fn send_ipi(apic_id: u32, command: u32) {
+    let local_apic = VirtAddr::new(LOCAL_APIC.load(Ordering::Relaxed));
+    if local_apic.is_null() {
+        return;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // Writing the low half of the command register sends the interrupt
+        mmio_write(local_apic, LAPIC_ICR_HIGH, apic_id << 24);
+        mmio_write(local_apic, LAPIC_ICR_LOW, command);
+        while mmio_read(local_apic, LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+/// Measures how far the local APIC timer counts down during one PIT tick
+fn calibrate_timer(local_apic: VirtAddr) -> u32 {
+    mmio_write(local_apic, LAPIC_TIMER_DIVIDE_CONFIG, TIMER_DIVIDE_BY_16);
+    mmio_write(local_apic, LAPIC_LVT_TIMER, LVT_MASKED);
+
+    // Start counting right at the beginning of a tick
+    let start = time::ticks();
+    while time::ticks() == start {
+        x86_64::instructions::hlt();
+    }
+    mmio_write(local_apic, LAPIC_TIMER_INITIAL_COUNT, u32::MAX);
+    let start = time::ticks();
+    while time::ticks() < start + CALIBRATION_TICKS {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = u32::MAX - mmio_read(local_apic, LAPIC_TIMER_CURRENT_COUNT);
+    mmio_write(local_apic, LAPIC_TIMER_INITIAL_COUNT, 0); // stop the timer
+
+    (elapsed / CALIBRATION_TICKS as u32).max(1)
+}
+
+/// Signals the local APIC that the current interrupt was handled
+pub fn end_of_interrupt() {
+    let local_apic = LOCAL_APIC.load(Ordering::Relaxed);
+    if local_apic != 0 {
+        mmio_write(VirtAddr::new(local_apic), LAPIC_EOI, 0);
+    }
+}
+
+/// Masks or unmasks the given ISA IRQ in the I/O APIC. Does nothing before `init`
+pub fn set_irq_masked(irq: u8, masked: bool) {
+    if let Some(io_apic) = IO_APIC.lock().as_mut() {
+        io_apic.set_masked(irq, masked);
+    }
+}
+
+/// Reads a 32 bit memory mapped register
+fn mmio_read(base: VirtAddr, register: usize) -> u32 {
+    unsafe { core::ptr::read_volatile((base + register).as_ptr::<u32>()) }
+}
+
+/// Writes a 32 bit memory mapped register
+fn mmio_write(base: VirtAddr, register: usize, value: u32) {
+    unsafe { core::ptr::write_volatile((base + register).as_mut_ptr::<u32>(), value) }
+}
+
+struct IoApic {
+    base: VirtAddr,
+    isa_inputs: [Option<u8>; ISA_IRQS as usize], // I/O APIC input each ISA IRQ is wired to
+}
+
+impl IoApic {
+    fn read(&mut self, register: u32) -> u32 {
+        mmio_write(self.base, IOAPIC_IOREGSEL, register);
+        mmio_read(self.base, IOAPIC_IOWIN)
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        mmio_write(self.base, IOAPIC_IOREGSEL, register);
+        mmio_write(self.base, IOAPIC_IOWIN, value);
+    }
+
+    /// Returns the number of interrupt lines the I/O APIC has
+    fn redirection_entries(&mut self) -> u8 {
+        ((self.read(IOAPIC_VERSION) >> 16) & 0xff) as u8 + 1
+    }
+
+    /// Routes I/O APIC input `input` to `vector` on the local APIC with ID `destination`
+    ///
+    /// Uses fixed delivery, `flags` holds the mask, polarity and trigger mode bits.
+    fn set_redirection(&mut self, input: u8, vector: u8, destination: u8, flags: u32) {
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * u32::from(input);
+        self.write(register + 1, u32::from(destination) << 24);
+        self.write(register, u32::from(vector) | flags);
+    }
+
+    fn set_masked(&mut self, irq: u8, masked: bool) {
+        let input = match self.isa_inputs.get(usize::from(irq)) {
+            Some(&Some(input)) => input,
+            _ => return,
+        };
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * u32::from(input);
+        let low = self.read(register);
+        self.write(register, if masked { low | LVT_MASKED } else { low & !LVT_MASKED });
+    }
+}
@@ -0,0 +1,523 @@
+use alloc::string::String;
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::task::Poll;
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::driver::{self, Device, DeviceKind, Driver, DriverError, IsaDevice, Match};
+use crate::interrupts::{self, IrqHandlerId};
+use crate::sync::{block_on, AtomicWaker};
+use crate::time;
+
+// This file drives ATA disks on the primary IDE channel in PIO mode, where the CPU moves every
+// word of data through the data port itself. The channel is two blocks of I/O ports shared by
+// its two drives (master and slave); the drive select register says which one a command is for:
+//
+//  0x1f0  data (16 bits)        0x1f4  LBA bits 8-15
+//  0x1f1  error                 0x1f5  LBA bits 16-23
+//  0x1f2  sector count          0x1f6  drive select, LBA bits 24-27
+//  0x1f3  LBA bits 0-7          0x1f7  status (read) / command (write)
+//  0x3f6  alternate status (read, doesn't acknowledge the IRQ) / device control (write)
+//
+// LBA28 commands reach the first 128 GiB; past that the LBA48 commands are used, which take the
+// sector count and the upper LBA bytes through the same registers, written first.
+//
+// Once a read command is sent, the drive raises IRQ 14 whenever it has a sector ready in its
+// buffer. A write command waits for the first sector, then raises the IRQ once the drive has
+// taken each one. The IRQ handler reads the status (which acknowledges the interrupt) and wakes
+// the request, which halts the CPU meanwhile:
+//
+//  request ---> command ---> drive ---> IRQ 14 ---> interrupt_handler: status ---> request
+
+// Command block registers, as offsets from the base port
+const DATA: u16 = 0;
+const ERROR: u16 = 1;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE_SELECT: u16 = 6;
+const STATUS: u16 = 7; // read
+const COMMAND: u16 = 7; // write
+
+// Device control: the drive doesn't raise its IRQ (nIEN)
+const INTERRUPTS_DISABLED: u8 = 1 << 1;
+
+// Status
+const STATUS_ERROR: u8 = 1 << 0;
+const STATUS_DATA_REQUEST: u8 = 1 << 3;
+const STATUS_DRIVE_FAULT: u8 = 1 << 5;
+const STATUS_BUSY: u8 = 1 << 7;
+// What a channel without a controller reads as
+const FLOATING_BUS: u8 = 0xff;
+
+// Drive select: bits 5 and 7 are set for old drives, bit 6 selects LBA addressing
+const SELECT_LBA: u8 = 0xe0;
+const SELECT_SLAVE: u8 = 1 << 4;
+
+// Commands
+const IDENTIFY: u8 = 0xec;
+const READ_SECTORS: u8 = 0x20;
+const READ_SECTORS_EXT: u8 = 0x24;
+const WRITE_SECTORS: u8 = 0x30;
+const WRITE_SECTORS_EXT: u8 = 0x34;
+const FLUSH_CACHE: u8 = 0xe7;
+const FLUSH_CACHE_EXT: u8 = 0xea;
+
+// Words of the data IDENTIFY returns
+const IDENTIFY_WORDS: usize = 256;
+const IDENTIFY_SERIAL: core::ops::Range<usize> = 10..20;
+const IDENTIFY_MODEL: core::ops::Range<usize> = 27..47;
+const IDENTIFY_CAPABILITIES: usize = 49;
+const IDENTIFY_LBA28_SECTORS: usize = 60;
+const IDENTIFY_COMMAND_SETS: usize = 83;
+const IDENTIFY_LBA48_SECTORS: usize = 100;
+const CAPABILITY_LBA: u16 = 1 << 9;
+const COMMAND_SET_LBA48: u16 = 1 << 10;
+
+/// Sectors LBA28 commands can address
+const LBA28_SECTORS: u64 = 1 << 28;
+
+// Most sectors one command transfers. LBA48 commands could take 65536, but the request would
+// hold the channel for longer
+const MAX_SECTORS_PER_COMMAND: usize = 256;
+
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+const IDENTIFY_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// An IDE channel: the registers two drives share, and the state of the command running on it
+struct Channel {
+    base: u16,
+    control: u16,
+    irq: u8,
+    /// Held for the whole of a command, the registers can't be shared between two
+    lock: Mutex<()>,
+    irq_received: AtomicBool,
+    irq_status: AtomicU8, // the status read by the IRQ handler
+    waker: AtomicWaker,
+    handler: Mutex<Option<IrqHandlerId>>,
+}
+
+static PRIMARY: Channel = Channel::new(0x1f0, 0x3f6, 14);
+
+static DRIVES: [AtaDrive; 2] = [
+    AtaDrive::new("ata0", &PRIMARY, false),
+    AtaDrive::new("ata1", &PRIMARY, true),
+];
+
+fn interrupt_handler() {
+    PRIMARY.interrupt();
+}
+
+impl Channel {
+    const fn new(base: u16, control: u16, irq: u8) -> Channel {
+        Channel {
+            base,
+            control,
+            irq,
+            lock: Mutex::new(()),
+            irq_received: AtomicBool::new(false),
+            irq_status: AtomicU8::new(0),
+            waker: AtomicWaker::new(),
+            handler: Mutex::new(None),
+        }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+
+    /// Reads the status without acknowledging the IRQ
+    fn alternate_status(&self) -> u8 {
+        unsafe { Port::new(self.control).read() }
+    }
+
+    fn set_interrupts(&self, enabled: bool) {
+        let control = if enabled { 0 } else { INTERRUPTS_DISABLED };
+        unsafe { Port::new(self.control).write(control) }
+    }
+
+    /// Returns whether nothing answers on the channel's ports
+    fn is_floating(&self) -> bool {
+        self.read(STATUS) == FLOATING_BUS
+    }
+
+    /// Selects a drive, and gives it the 400 ns it needs to put its status on the bus
+    fn select(&self, value: u8) {
+        self.write(DRIVE_SELECT, value);
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+    }
+
+    /// Records the status and wakes the request waiting for the IRQ. Reading the status
+    /// register acknowledges the interrupt
+    fn interrupt(&self) {
+        self.irq_status.store(self.read(STATUS), Ordering::Relaxed);
+        self.irq_received.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+
+    /// Waits for the IRQ of the command running and returns the status read then
+    fn wait_for_irq(&self) -> Result<u8, BlockError> {
+        let start = time::uptime();
+        block_on(poll_fn(|context| {
+            // Register first, so an IRQ coming in after the check still wakes us
+            self.waker.register(context.waker());
+            if self.irq_received.swap(false, Ordering::Acquire) {
+                Poll::Ready(Ok(self.irq_status.load(Ordering::Relaxed)))
+            } else if time::uptime() - start > COMMAND_TIMEOUT {
+                Poll::Ready(Err(BlockError::Timeout))
+            } else {
+                Poll::Pending
+            }
+        }))
+    }
+
+    /// Polls the status until the drive is no longer busy and `condition` holds for it
+    fn poll_status(
+        &self,
+        timeout: Duration,
+        condition: impl Fn(u8) -> bool,
+    ) -> Result<u8, BlockError> {
+        let start = time::uptime();
+        loop {
+            let status = self.alternate_status();
+            if status & STATUS_BUSY == 0 && condition(status) {
+                return Ok(status);
+            }
+            if time::uptime() - start > timeout {
+                return Err(BlockError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Turns an error status into the error the drive reported
+    fn check(&self, status: u8) -> Result<(), BlockError> {
+        if status & (STATUS_ERROR | STATUS_DRIVE_FAULT) != 0 {
+            Err(BlockError::Device(self.read(ERROR)))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Moves a sector from the drive's buffer into `sector`
+    fn read_data(&self, sector: &mut [u8]) {
+        let mut data: Port<u16> = Port::new(self.base + DATA);
+        for bytes in sector.chunks_exact_mut(2) {
+            bytes.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+        }
+    }
+
+    /// Moves `sector` into the drive's buffer
+    fn write_data(&self, sector: &[u8]) {
+        let mut data: Port<u16> = Port::new(self.base + DATA);
+        for bytes in sector.chunks_exact(2) {
+            unsafe { data.write(u16::from_le_bytes([bytes[0], bytes[1]])) };
+        }
+    }
+
+    /// Sends IDENTIFY to a drive, with the channel's interrupts disabled. Returns `None` if
+    /// there is no ATA drive there (ATAPI drives like CD-ROMs abort the command)
+    fn identify(&self, slave: bool) -> Result<Option<[u16; IDENTIFY_WORDS]>, BlockError> {
+        self.select(if slave { SELECT_LBA | SELECT_SLAVE } else { SELECT_LBA });
+        for &register in [SECTOR_COUNT, LBA_LOW, LBA_MID, LBA_HIGH].iter() {
+            self.write(register, 0);
+        }
+        self.write(COMMAND, IDENTIFY);
+        if self.alternate_status() == 0 {
+            return Ok(None);
+        }
+        self.poll_status(IDENTIFY_TIMEOUT, |_| true)?;
+        // ATAPI and SATA devices put their signature there
+        if self.read(LBA_MID) != 0 || self.read(LBA_HIGH) != 0 {
+            return Ok(None);
+        }
+        let status = self.poll_status(IDENTIFY_TIMEOUT, |status| {
+            status & (STATUS_DATA_REQUEST | STATUS_ERROR) != 0
+        })?;
+        if status & STATUS_ERROR != 0 {
+            return Ok(None);
+        }
+        let mut words = [0; IDENTIFY_WORDS];
+        let mut data: Port<u16> = Port::new(self.base + DATA);
+        for word in words.iter_mut() {
+            *word = unsafe { data.read() };
+        }
+        Ok(Some(words))
+    }
+}
+
+/// What IDENTIFY tells about a drive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DriveInfo {
+    pub model: String,
+    pub serial: String,
+    /// Number of sectors that can be addressed
+    pub sectors: u64,
+    /// Whether the drive takes LBA48 commands
+    pub lba48: bool,
+}
+
+impl DriveInfo {
+    /// Decodes the data IDENTIFY returns. Returns `None` for a drive that can only be addressed
+    /// by cylinder, head and sector
+    fn parse(words: &[u16; IDENTIFY_WORDS]) -> Option<DriveInfo> {
+        if words[IDENTIFY_CAPABILITIES] & CAPABILITY_LBA == 0 {
+            return None;
+        }
+        let lba48 = words[IDENTIFY_COMMAND_SETS] & COMMAND_SET_LBA48 != 0;
+        let sectors = if lba48 {
+            words[IDENTIFY_LBA48_SECTORS..IDENTIFY_LBA48_SECTORS + 4].iter().rev()
+                .fold(0, |sectors, &word| sectors << 16 | u64::from(word))
+        } else {
+            u64::from(words[IDENTIFY_LBA28_SECTORS + 1]) << 16
+                | u64::from(words[IDENTIFY_LBA28_SECTORS])
+        };
+        Some(DriveInfo {
+            model: identify_string(&words[IDENTIFY_MODEL]),
+            serial: identify_string(&words[IDENTIFY_SERIAL]),
+            sectors,
+            lba48,
+        })
+    }
+}
+
+/// Decodes a string of the IDENTIFY data: two characters per word, the first in the high byte,
+/// padded with spaces
+fn identify_string(words: &[u16]) -> String {
+    let bytes = words.iter().flat_map(|word| word.to_be_bytes());
+    let string: String = bytes.map(|byte| if byte.is_ascii_graphic() { byte as char } else { ' ' })
+        .collect();
+    String::from(string.trim())
+}
+
+/// A drive on an IDE channel, the block device the driver registers for it
+pub struct AtaDrive {
+    name: &'static str,
+    channel: &'static Channel,
+    slave: bool,
+    info: Mutex<Option<DriveInfo>>, // `None` while the driver isn't attached or there's no drive
+}
+
+impl AtaDrive {
+    const fn new(name: &'static str, channel: &'static Channel, slave: bool) -> AtaDrive {
+        AtaDrive { name, channel, slave, info: Mutex::new(None) }
+    }
+
+    /// Returns what IDENTIFY told about the drive, if it is attached
+    pub fn info(&self) -> Option<DriveInfo> {
+        self.info.lock().clone()
+    }
+
+    /// Checks that the drive is attached and can take a request for `length` bytes at `lba`.
+    /// Must be called with the channel locked, so the drive can't be detached meanwhile
+    fn check_request(&self, lba: u64, length: usize) -> Result<(), BlockError> {
+        if self.info.lock().is_none() {
+            return Err(BlockError::NotPresent);
+        }
+        block::check_request(self, lba, length).map(drop)
+    }
+
+    /// Selects the drive and sends a read or write command for `count` sectors at `lba`. Must
+    /// be called with the channel locked
+    fn start(&self, lba: u64, count: usize, write: bool) -> Result<(), BlockError> {
+        let channel = self.channel;
+        let task = TaskFile::new(lba, count, write, self.slave);
+        channel.select(task.select);
+        channel.poll_status(COMMAND_TIMEOUT, |_| true)?;
+        // The high bytes go first, the registers keep the last two values written
+        for values in task.high.iter().chain(Some(&task.low)) {
+            for (&register, &value) in TASK_REGISTERS.iter().zip(values.iter()) {
+                channel.write(register, value);
+            }
+        }
+        channel.irq_received.store(false, Ordering::Relaxed);
+        channel.write(COMMAND, task.command);
+        Ok(())
+    }
+}
+
+// The registers TaskFile::high and TaskFile::low are written to
+const TASK_REGISTERS: [u16; 4] = [SECTOR_COUNT, LBA_LOW, LBA_MID, LBA_HIGH];
+
+/// What `AtaDrive::start` writes to the registers for a read or write command
+#[derive(Debug, PartialEq, Eq)]
+struct TaskFile {
+    select: u8,
+    high: Option<[u8; 4]>, // written first, only for LBA48 commands
+    low: [u8; 4],
+    command: u8,
+}
+
+impl TaskFile {
+    /// Uses LBA28 commands where they reach, LBA48 ones beyond
+    fn new(lba: u64, count: usize, write: bool, slave: bool) -> TaskFile {
+        let slave = if slave { SELECT_SLAVE } else { 0 };
+        let lba48 = lba + count as u64 > LBA28_SECTORS;
+        let (select, high) = if lba48 {
+            let high =
+                [(count >> 8) as u8, (lba >> 24) as u8, (lba >> 32) as u8, (lba >> 40) as u8];
+            (SELECT_LBA | slave, Some(high))
+        } else {
+            (SELECT_LBA | slave | (lba >> 24) as u8 & 0x0f, None)
+        };
+        TaskFile {
+            select,
+            high,
+            // A count of 256 is written as 0 for LBA28
+            low: [count as u8, lba as u8, (lba >> 8) as u8, (lba >> 16) as u8],
+            command: match (write, lba48) {
+                (false, false) => READ_SECTORS,
+                (false, true) => READ_SECTORS_EXT,
+                (true, false) => WRITE_SECTORS,
+                (true, true) => WRITE_SECTORS_EXT,
+            },
+        }
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.info.lock().as_ref().map_or(0, |info| info.sectors)
+    }
+
+    fn read(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let _guard = self.channel.lock.lock();
+        self.check_request(lba, buffer.len())?;
+        let chunks = buffer.chunks_mut(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE);
+        for (chunk_lba, chunk) in (lba..).step_by(MAX_SECTORS_PER_COMMAND).zip(chunks) {
+            self.start(chunk_lba, chunk.len() / SECTOR_SIZE, false)?;
+            for sector in chunk.chunks_mut(SECTOR_SIZE) {
+                let status = self.channel.wait_for_irq()?;
+                self.channel.check(status)?;
+                self.channel.read_data(sector);
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        let _guard = self.channel.lock.lock();
+        self.check_request(lba, buffer.len())?;
+        let chunks = buffer.chunks(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE);
+        for (chunk_lba, chunk) in (lba..).step_by(MAX_SECTORS_PER_COMMAND).zip(chunks) {
+            self.start(chunk_lba, chunk.len() / SECTOR_SIZE, true)?;
+            // The first sector is asked for without an IRQ
+            let mut status = self.channel.poll_status(COMMAND_TIMEOUT, |status| {
+                status & (STATUS_DATA_REQUEST | STATUS_ERROR) != 0
+            })?;
+            for sector in chunk.chunks(SECTOR_SIZE) {
+                self.channel.check(status)?;
+                self.channel.write_data(sector);
+                status = self.channel.wait_for_irq()?;
+            }
+            self.channel.check(status)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let _guard = self.channel.lock.lock();
+        let lba48 = self.info.lock().as_ref().ok_or(BlockError::NotPresent)?.lba48;
+        self.channel.select(if self.slave { SELECT_LBA | SELECT_SLAVE } else { SELECT_LBA });
+        self.channel.poll_status(COMMAND_TIMEOUT, |_| true)?;
+        self.channel.irq_received.store(false, Ordering::Relaxed);
+        self.channel.write(COMMAND, if lba48 { FLUSH_CACHE_EXT } else { FLUSH_CACHE });
+        let status = self.channel.wait_for_irq()?;
+        self.channel.check(status)
+    }
+}
+
+/// Returns the drives the driver found
+pub fn drives() -> impl Iterator<Item = &'static AtaDrive> {
+    DRIVES.iter().filter(|drive| drive.info.lock().is_some())
+}
+
+/// The driver of the primary IDE channel, registering a block device for each ATA drive on it
+pub struct AtaDriver;
+
+impl Driver for AtaDriver {
+    fn name(&self) -> &'static str {
+        "ata"
+    }
+
+    fn matches(&self) -> &'static [Match] {
+        &[Match::Isa("ide0")]
+    }
+
+    fn probe(&self, _device: &Device) -> Result<(), DriverError> {
+        if PRIMARY.is_floating() {
+            Err(DriverError::NotSupported)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn attach(&self, device: &Device) -> Result<(), DriverError> {
+        // IDENTIFY is polled, its IRQ would come in before there is anyone to take it
+        PRIMARY.set_interrupts(false);
+        for drive in DRIVES.iter() {
+            // A drive that doesn't answer in time is taken as missing, the other may still work
+            let words = PRIMARY.identify(drive.slave).ok().flatten();
+            *drive.info.lock() = words.as_ref().and_then(DriveInfo::parse);
+        }
+        let handler = interrupts::register_irq(PRIMARY.irq, interrupt_handler)
+            .map_err(DriverError::Irq)?;
+        *PRIMARY.handler.lock() = Some(handler);
+        // Reading the status clears an IRQ left pending from before
+        PRIMARY.read(STATUS);
+        PRIMARY.set_interrupts(true);
+        for drive in drives() {
+            block::register(drive);
+            let node = IsaDevice { name: drive.name, port: PRIMARY.base, irq: Some(PRIMARY.irq) };
+            driver::add_device(device.id, drive.name, DeviceKind::Isa(node));
+        }
+        Ok(())
+    }
+
+    fn detach(&self, _device: &Device) -> Result<(), DriverError> {
+        for drive in DRIVES.iter() {
+            block::unregister(drive.name);
+            // Wait for a request still running on the drive
+            let _guard = PRIMARY.lock.lock();
+            *drive.info.lock() = None;
+        }
+        PRIMARY.set_interrupts(false);
+        if let Some(handler) = PRIMARY.handler.lock().take() {
+            interrupts::unregister_irq(handler);
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_task_file() {
+    // The last sectors LBA28 reaches
+    let task = TaskFile::new(LBA28_SECTORS - 256, 256, false, true);
+    assert_eq!(task, TaskFile {
+        select: SELECT_LBA | SELECT_SLAVE | 0x0f,
+        high: None,
+        low: [0, 0x00, 0xff, 0xff],
+        command: READ_SECTORS,
+    });
+    // One sector more needs LBA48
+    let task = TaskFile::new(LBA28_SECTORS - 256, 257, true, false);
+    assert_eq!(task, TaskFile {
+        select: SELECT_LBA,
+        high: Some([1, 0x0f, 0, 0]),
+        low: [1, 0x00, 0xff, 0xff],
+        command: WRITE_SECTORS_EXT,
+    });
+}
@@ -0,0 +1,96 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+
+// Block devices are storage addressed in fixed-size sectors, like disks. Drivers register the
+// ones they find here under a name ("ata0", ...), and file systems look them up and read and
+// write whole sectors through the `BlockDevice` trait without knowing the hardware behind it.
+//
+// Requests are synchronous: they return once the data has been transferred. A driver may halt
+// the CPU while it waits for its device (see `sync::block_on`), so they must not be made from
+// interrupt handlers.
+
+/// The sector size of every device the kernel supports so far
+pub const SECTOR_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The buffer is not a whole, non-zero number of sectors long
+    BufferSize(usize),
+    /// The request goes past the last sector of the device
+    OutOfRange { lba: u64, count: u64 },
+    /// The device is no longer attached
+    NotPresent,
+    /// The device did not complete the request in time
+    Timeout,
+    /// The device failed the request, with its own error code
+    Device(u8),
+}
+
+/// A device made of sectors that can be read and written
+pub trait BlockDevice: Sync {
+    /// The name the device is registered under, e.g. "ata0"
+    fn name(&self) -> &'static str;
+
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    /// Number of sectors on the device, 0 once it is detached
+    fn sector_count(&self) -> u64;
+
+    /// Reads the sectors starting at `lba` into `buffer`, which is a whole number of sectors long
+    fn read(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buffer`, a whole number of sectors, to the sectors starting at `lba`
+    fn write(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError>;
+
+    /// Makes sure the sectors written so far are stored, not just in the device's cache
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+/// Returns the number of sectors a request for `length` bytes at `lba` covers, if the device can
+/// take it
+pub fn check_request(
+    device: &dyn BlockDevice,
+    lba: u64,
+    length: usize,
+) -> Result<u64, BlockError> {
+    let sector_size = device.sector_size();
+    if length == 0 || !length.is_multiple_of(sector_size) {
+        return Err(BlockError::BufferSize(length));
+    }
+    let count = (length / sector_size) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= device.sector_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange { lba, count }),
+    }
+}
+
+static DEVICES: Mutex<Vec<&'static dyn BlockDevice>> = Mutex::new(Vec::new());
+
+/// Makes a block device available under its name, replacing one registered with the same name
+pub fn register(device: &'static dyn BlockDevice) {
+    let mut devices = DEVICES.lock();
+    devices.retain(|other| other.name() != device.name());
+    devices.push(device);
+}
+
+/// Removes the device registered under `name`. Returns false if there was none
+pub fn unregister(name: &str) -> bool {
+    let mut devices = DEVICES.lock();
+    let count = devices.len();
+    devices.retain(|device| device.name() != name);
+    devices.len() != count
+}
+
+/// Returns the registered block devices, in the order they were registered
+pub fn devices() -> Vec<&'static dyn BlockDevice> {
+    DEVICES.lock().clone()
+}
+
+/// Returns the block device registered under `name`
+pub fn find(name: &str) -> Option<&'static dyn BlockDevice> {
+    DEVICES.lock().iter().find(|device| device.name() == name).copied()
+}
@@ -0,0 +1,351 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use spin::{Mutex, Once};
+use crate::interrupts::IrqError;
+use crate::pci::{self, PciDevice};
+use crate::{ata, keyboard, mouse, ps2, serial};
+
+// This file ties the drivers to the hardware they handle. The buses put the devices they find
+// into a tree, drivers say which devices they handle with a match table, and each device is
+// bound to the first driver that matches it and whose `probe` accepts it:
+//
+//  system
+//  +-- isa: the legacy devices at fixed ports
+//  |   +-- ps2 [ps2]                     the PS/2 driver adds the devices behind the controller
+//  |   |   +-- keyboard [keyboard]
+//  |   |   +-- mouse [mouse]
+//  |   +-- com1 [serial]
+//  |   +-- ide0 [ata]                    the ATA driver adds the drives it finds
+//  |       +-- ata0
+//  +-- pci: the functions `pci::init` found
+//      +-- 0000:00:00.0 host bridge
+//      ...
+//
+// Binding happens whenever a device or a driver is added, so it doesn't matter which comes
+// first. The tree only grows; detaching a device unbinds its driver (and those of the devices
+// below it) but keeps it in the tree, so it can be bound again. A device whose driver fails to
+// let go of it stays bound, and so do the devices above it, which it may still be using.
+
+/// Identifies a device in the tree, its index in the order devices were added
+pub type DeviceId = usize;
+
+/// The root of the tree
+pub const ROOT: DeviceId = 0;
+
+/// A device the driver of a match table entry handles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Match {
+    /// A PCI function with the given vendor and device id
+    Pci { vendor_id: u16, device_id: u16 },
+    /// A PCI function of the given class and subclass
+    PciClass { class: u8, subclass: u8 },
+    /// A legacy device with the given name
+    Isa(&'static str),
+}
+
+/// A legacy device, found at a fixed port rather than by scanning a bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaDevice {
+    pub name: &'static str,
+    /// The first of its I/O ports
+    pub port: u16,
+    pub irq: Option<u8>,
+}
+
+/// What kind of node of the tree a device is
+#[derive(Debug, Clone, Copy)]
+pub enum DeviceKind {
+    /// A node that only groups the devices below it
+    Bus,
+    Pci(&'static PciDevice),
+    Isa(IsaDevice),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DriverError {
+    /// The device is not one the driver handles, or is not there
+    NotSupported,
+    /// There is no device with that id
+    NoDevice(DeviceId),
+    Irq(IrqError),
+    /// The device failed, as described by its driver
+    Device(String),
+}
+
+impl DriverError {
+    /// Wraps the error of a driver's own module
+    pub fn device(error: impl fmt::Debug) -> DriverError {
+        DriverError::Device(format!("{:?}", error))
+    }
+}
+
+/// A driver for one kind of device
+///
+/// `attach` and `detach` are never called for the same device at the same time, but a driver
+/// handling several devices has to cope with calls for different ones.
+pub trait Driver: Sync {
+    /// A short name for the driver, e.g. "serial"
+    fn name(&self) -> &'static str;
+
+    /// The devices the driver may handle
+    fn matches(&self) -> &'static [Match];
+
+    /// Checks that a matching device is really there and one the driver handles, without
+    /// taking it over
+    fn probe(&self, _device: &Device) -> Result<(), DriverError> {
+        Ok(())
+    }
+
+    /// Takes the device over: sets it up, registers its IRQ handler and adds the devices behind
+    /// it with `add_device`
+    fn attach(&self, device: &Device) -> Result<(), DriverError>;
+
+    /// Lets go of the device, undoing `attach`. The devices below it are detached first
+    fn detach(&self, device: &Device) -> Result<(), DriverError>;
+}
+
+/// A node of the device tree
+#[derive(Clone)]
+pub struct Device {
+    pub id: DeviceId,
+    pub parent: Option<DeviceId>,
+    pub name: String,
+    pub kind: DeviceKind,
+    driver: Option<&'static dyn Driver>,
+    /// Why binding a driver to the device, or detaching it, last failed
+    pub error: Option<DriverError>,
+}
+
+impl Device {
+    /// Returns whether the device is the one described by a match table entry
+    pub fn matches(&self, entry: &Match) -> bool {
+        match (self.kind, *entry) {
+            (DeviceKind::Pci(device), Match::Pci { vendor_id, device_id }) => {
+                device.vendor_id == vendor_id && device.device_id == device_id
+            }
+            (DeviceKind::Pci(device), Match::PciClass { class, subclass }) => {
+                device.class == class && device.subclass == subclass
+            }
+            (DeviceKind::Isa(device), Match::Isa(name)) => device.name == name,
+            _ => false,
+        }
+    }
+
+    /// Returns the name of the driver bound to the device
+    pub fn driver(&self) -> Option<&'static str> {
+        self.driver.map(|driver| driver.name())
+    }
+
+    /// Returns a short description of the device, e.g. its PCI class
+    pub fn description(&self) -> String {
+        match self.kind {
+            DeviceKind::Bus => String::new(),
+            DeviceKind::Pci(device) => format!("{:04x}:{:04x} {}", device.vendor_id,
+                                               device.device_id, device.class_name()),
+            DeviceKind::Isa(IsaDevice { port, irq: Some(irq), .. }) => {
+                format!("port {:#x}, IRQ {}", port, irq)
+            }
+            DeviceKind::Isa(IsaDevice { port, irq: None, .. }) => format!("port {:#x}", port),
+        }
+    }
+}
+
+impl fmt::Debug for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Device")
+            .field("id", &self.id)
+            .field("parent", &self.parent)
+            .field("name", &self.name)
+            .field("kind", &self.kind)
+            .field("driver", &self.driver())
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+/// The legacy devices every PC may have. Their drivers' `probe` tells whether they are there
+const ISA_DEVICES: [IsaDevice; 6] = [
+    IsaDevice { name: "ps2", port: 0x60, irq: None },
+    IsaDevice { name: "com1", port: 0x3f8, irq: Some(4) },
+    IsaDevice { name: "com2", port: 0x2f8, irq: Some(3) },
+    IsaDevice { name: "com3", port: 0x3e8, irq: Some(4) },
+    IsaDevice { name: "com4", port: 0x2e8, irq: Some(3) },
+    IsaDevice { name: "ide0", port: 0x1f0, irq: Some(14) },
+];
+
+/// The drivers built into the kernel, registered by `init`
+const BUILTIN_DRIVERS: [&dyn Driver; 5] = [
+    &ps2::Ps2Driver,
+    &keyboard::KeyboardDriver,
+    &mouse::MouseDriver,
+    &serial::SerialDriver,
+    &ata::AtaDriver,
+];
+
+static DRIVERS: Mutex<Vec<&'static dyn Driver>> = Mutex::new(Vec::new());
+static DEVICES: Mutex<Vec<Device>> = Mutex::new(Vec::new());
+static INITIALIZED: Once<()> = Once::new();
+
+/// Builds the device tree from the legacy devices and the PCI functions, and binds the built-in
+/// drivers to them. Needs the heap, and `pci::init` for the PCI functions
+///
+/// Only the first call does anything.
+pub fn init() {
+    INITIALIZED.call_once(|| {
+        let root = add_node(None, "system", DeviceKind::Bus);
+        let isa = add_node(Some(root), "isa", DeviceKind::Bus);
+        for device in ISA_DEVICES.iter() {
+            add_node(Some(isa), device.name, DeviceKind::Isa(*device));
+        }
+        let bus = add_node(Some(root), "pci", DeviceKind::Bus);
+        for device in pci::devices() {
+            add_node(Some(bus), &device.address.to_string(), DeviceKind::Pci(device));
+        }
+        for &driver in BUILTIN_DRIVERS.iter() {
+            register(driver);
+        }
+    });
+}
+
+/// Adds a device to the tree without trying to bind it
+fn add_node(parent: Option<DeviceId>, name: &str, kind: DeviceKind) -> DeviceId {
+    let mut devices = DEVICES.lock();
+    let id = devices.len();
+    devices.push(Device { id, parent, name: name.to_string(), kind, driver: None, error: None });
+    id
+}
+
+/// Adds a driver to the registry and binds it to the devices no driver handles yet
+pub fn register(driver: &'static dyn Driver) {
+    DRIVERS.lock().push(driver);
+    // Devices attaching may add more devices, which get bound as they are added
+    let unbound: Vec<DeviceId> = DEVICES.lock().iter()
+        .filter(|device| device.driver.is_none())
+        .filter(|device| driver.matches().iter().any(|entry| device.matches(entry)))
+        .map(|device| device.id)
+        .collect();
+    for id in unbound {
+        let _ = try_bind(id, driver);
+    }
+}
+
+/// Adds a device below `parent` and binds a driver to it if one handles it
+///
+/// A device below `parent` with the same name, left from before the parent was detached, is
+/// bound again instead.
+pub fn add_device(parent: DeviceId, name: &str, kind: DeviceKind) -> DeviceId {
+    let existing = DEVICES.lock().iter()
+        .find(|device| device.parent == Some(parent) && device.name == name)
+        .map(|device| device.id);
+    let id = existing.unwrap_or_else(|| add_node(Some(parent), name, kind));
+    let _ = bind(id);
+    id
+}
+
+/// Binds the first registered driver that handles the device to it, returning the driver's name
+pub fn bind(id: DeviceId) -> Result<&'static str, DriverError> {
+    let device = device(id).ok_or(DriverError::NoDevice(id))?;
+    if let Some(name) = device.driver() {
+        return Ok(name);
+    }
+    let drivers: Vec<&'static dyn Driver> = DRIVERS.lock().iter()
+        .filter(|driver| driver.matches().iter().any(|entry| device.matches(entry)))
+        .copied()
+        .collect();
+    let mut result = Err(DriverError::NotSupported);
+    for driver in drivers {
+        result = try_bind(id, driver).map(|()| driver.name());
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
+
+/// Probes and attaches one driver. A failed attach is recorded in the device, a failed probe
+/// only means the device is not one for the driver
+fn try_bind(id: DeviceId, driver: &'static dyn Driver) -> Result<(), DriverError> {
+    let device = device(id).ok_or(DriverError::NoDevice(id))?;
+    // The tree is not locked while the driver runs, it may add devices
+    driver.probe(&device)?;
+    let result = driver.attach(&device);
+    let mut devices = DEVICES.lock();
+    let device = &mut devices[id];
+    match &result {
+        Ok(()) => {
+            device.driver = Some(driver);
+            device.error = None;
+        }
+        Err(error) => device.error = Some(error.clone()),
+    }
+    result
+}
+
+/// Detaches the devices below a device, then the device itself, from their drivers
+///
+/// A failure doesn't stop the other devices from being detached, except for the ones above the
+/// device that failed, which stay bound. Returns the error of every device that failed, which is
+/// also recorded in the device.
+pub fn detach(id: DeviceId) -> Result<(), Vec<(DeviceId, DriverError)>> {
+    let mut errors = Vec::new();
+    detach_subtree(id, &mut errors);
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
+
+/// Detaches a device and the devices below it, adding the failures to `errors`. Returns whether
+/// the device is no longer bound
+fn detach_subtree(id: DeviceId, errors: &mut Vec<(DeviceId, DriverError)>) -> bool {
+    let children: Vec<DeviceId> = DEVICES.lock().iter()
+        .filter(|device| device.parent == Some(id))
+        .map(|device| device.id)
+        .collect();
+    let mut children_detached = true;
+    for child in children {
+        children_detached &= detach_subtree(child, errors);
+    }
+    let Some(device) = device(id) else {
+        errors.push((id, DriverError::NoDevice(id)));
+        return false;
+    };
+    let Some(driver) = device.driver else {
+        return children_detached;
+    };
+    if !children_detached {
+        return false;
+    }
+    let result = driver.detach(&device);
+    let mut devices = DEVICES.lock();
+    match result {
+        Ok(()) => {
+            devices[id].driver = None;
+            true
+        }
+        Err(error) => {
+            devices[id].error = Some(error.clone());
+            errors.push((id, error));
+            false
+        }
+    }
+}
+
+/// Returns a copy of a device of the tree
+pub fn device(id: DeviceId) -> Option<Device> {
+    DEVICES.lock().get(id).cloned()
+}
+
+/// Returns the first device with the given name
+pub fn find_device(name: &str) -> Option<Device> {
+    DEVICES.lock().iter().find(|device| device.name == name).cloned()
+}
+
+/// Returns a copy of the device tree, parents before their children
+pub fn devices() -> Vec<Device> {
+    DEVICES.lock().clone()
+}
+
+/// Returns the names of the registered drivers
+pub fn drivers() -> Vec<&'static str> {
+    DRIVERS.lock().iter().map(|driver| driver.name()).collect()
+}
@@ -0,0 +1,385 @@
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::{mapper::MapToError, FrameAllocator, OffsetPageTable, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::memory;
+use crate::pci::{self, Bar};
+
+// This file defines a pixel framebuffer and the two ways we can get one without BIOS calls:
+// - Bochs VBE (also provided by QEMU's std VGA), which gives a linear 32 bit per pixel framebuffer
+//   at any resolution through the "DISPI" registers
+// - VGA mode 13h, 320x200 with a 256 color palette, set up by programming the VGA registers
+//   directly
+
+pub mod font;
+pub mod console;
+
+/// A 24 bit color
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl Rgb {
+    pub const fn new(red: u8, green: u8, blue: u8) -> Rgb {
+        Rgb { red, green, blue }
+    }
+}
+
+/// How a pixel is stored in framebuffer memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 4 bytes per pixel: blue, green, red, unused
+    Bgrx32,
+    /// 1 byte per pixel, an index into the palette set up by `init_mode_13h`
+    Indexed8,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Bgrx32 => 4,
+            PixelFormat::Indexed8 => 1,
+        }
+    }
+}
+
+/// A linear framebuffer
+pub struct Framebuffer {
+    base: *mut u8, // virtual address of the top left pixel
+    width: usize,
+    height: usize,
+    pitch: usize, // bytes from the start of one line to the start of the next
+    format: PixelFormat,
+}
+
+// The framebuffer memory is only reachable through this struct, so it can be moved between
+// threads like any other owned value
+unsafe impl Send for Framebuffer {}
+
+impl Framebuffer {
+    /// Creates a framebuffer for the memory at `base`
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that `height * pitch` bytes
+    /// starting at `base` are mapped framebuffer memory and that nothing else accesses them.
+    pub unsafe fn new(base: VirtAddr, width: usize, height: usize, pitch: usize,
+                      format: PixelFormat) -> Framebuffer {
+        Framebuffer {
+            base: base.as_mut_ptr(),
+            width,
+            height,
+            pitch,
+            format,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// Sets the pixel at (`x`, `y`). Pixels outside of the framebuffer are ignored
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: Rgb) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let offset = y * self.pitch + x * self.format.bytes_per_pixel();
+        // Framebuffer memory is read by the graphics card, so writes must not be optimized away
+        unsafe {
+            match self.format {
+                PixelFormat::Bgrx32 => {
+                    let value = u32::from(color.red) << 16 | u32::from(color.green) << 8
+                        | u32::from(color.blue);
+                    (self.base.add(offset) as *mut u32).write_volatile(value);
+                }
+                PixelFormat::Indexed8 => {
+                    self.base.add(offset).write_volatile(palette_index(color));
+                }
+            }
+        }
+    }
+
+    /// Fills the `width` x `height` rectangle whose top left corner is (`x`, `y`)
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Rgb) {
+        let x_end = (x + width).min(self.width);
+        let y_end = (y + height).min(self.height);
+        for py in y..y_end {
+            for px in x..x_end {
+                self.set_pixel(px, py, color);
+            }
+        }
+    }
+
+    /// Fills the whole framebuffer
+    pub fn clear(&mut self, color: Rgb) {
+        self.fill_rect(0, 0, self.width, self.height, color);
+    }
+
+    /// Copies a `width` x `height` image, stored line by line in `pixels`, to (`x`, `y`)
+    ///
+    /// Parts of the image outside of the framebuffer are clipped.
+    pub fn blit(&mut self, x: usize, y: usize, width: usize, height: usize, pixels: &[Rgb]) {
+        for (row, line) in pixels.chunks(width).take(height).enumerate() {
+            for (col, color) in line.iter().enumerate() {
+                self.set_pixel(x + col, y + row, *color);
+            }
+        }
+    }
+}
+
+// Bochs VBE ("DISPI") registers are accessed through an index and a data port
+const VBE_DISPI_IOPORT_INDEX: u16 = 0x01ce;
+const VBE_DISPI_IOPORT_DATA: u16 = 0x01cf;
+
+const VBE_DISPI_INDEX_ID: u16 = 0;
+const VBE_DISPI_INDEX_XRES: u16 = 1;
+const VBE_DISPI_INDEX_YRES: u16 = 2;
+const VBE_DISPI_INDEX_BPP: u16 = 3;
+const VBE_DISPI_INDEX_ENABLE: u16 = 4;
+
+const VBE_DISPI_ID0: u16 = 0xb0c0; // the interface versions go from 0xb0c0 to 0xb0c5
+const VBE_DISPI_ID5: u16 = 0xb0c5;
+const VBE_DISPI_DISABLED: u16 = 0x00;
+const VBE_DISPI_ENABLED: u16 = 0x01;
+const VBE_DISPI_LFB_ENABLED: u16 = 0x40;
+
+// PCI vendor and device id of the Bochs/QEMU std VGA card, whose first BAR is the framebuffer
+const BOCHS_VGA_VENDOR_ID: u16 = 0x1234;
+const BOCHS_VGA_DEVICE_ID: u16 = 0x1111;
+
+fn vbe_read(index: u16) -> u16 {
+    let mut index_port: Port<u16> = Port::new(VBE_DISPI_IOPORT_INDEX);
+    let mut data_port: Port<u16> = Port::new(VBE_DISPI_IOPORT_DATA);
+    unsafe {
+        index_port.write(index);
+        data_port.read()
+    }
+}
+
+fn vbe_write(index: u16, value: u16) {
+    let mut index_port: Port<u16> = Port::new(VBE_DISPI_IOPORT_INDEX);
+    let mut data_port: Port<u16> = Port::new(VBE_DISPI_IOPORT_DATA);
+    unsafe {
+        index_port.write(index);
+        data_port.write(value);
+    }
+}
+
+/// Returns true if the Bochs VBE extensions are available
+pub fn bochs_vbe_available() -> bool {
+    (VBE_DISPI_ID0..=VBE_DISPI_ID5).contains(&vbe_read(VBE_DISPI_INDEX_ID))
+}
+
+/// Returns the physical address of the Bochs VGA linear framebuffer (BAR0 of the card)
+fn bochs_vga_framebuffer_address() -> Option<PhysAddr> {
+    let card = pci::find_device(BOCHS_VGA_VENDOR_ID, BOCHS_VGA_DEVICE_ID)?;
+    match card.bars[0]? {
+        Bar::Memory { address, .. } => Some(PhysAddr::new(address)),
+        Bar::Io { .. } => None,
+    }
+}
+
+/// Errors that can occur while switching to a graphics mode
+#[derive(Debug)]
+pub enum FramebufferError {
+    /// The graphics card does not support the Bochs VBE extensions
+    NoBochsVbe,
+    /// The framebuffer could not be mapped into the address space
+    Mapping(MapToError<Size4KiB>),
+}
+
+/// Switches to the best framebuffer the graphics card offers: a `width` x `height` Bochs VBE mode
+/// if the card has the extensions, VGA mode 13h otherwise. Needs `pci::init` to find the card
+pub fn init(
+    width: u16,
+    height: u16,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<Framebuffer, FramebufferError> {
+    match init_bochs_vbe(width, height, mapper, frame_allocator) {
+        Err(FramebufferError::NoBochsVbe) => Ok(init_mode_13h()),
+        result => result,
+    }
+}
+
+/// Switches to a `width` x `height` 32 bit per pixel mode through the Bochs VBE extensions. Needs
+/// `pci::init` to find the card
+pub fn init_bochs_vbe(
+    width: u16,
+    height: u16,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<Framebuffer, FramebufferError> {
+    if !bochs_vbe_available() {
+        return Err(FramebufferError::NoBochsVbe);
+    }
+    let phys = bochs_vga_framebuffer_address().ok_or(FramebufferError::NoBochsVbe)?;
+    let pitch = usize::from(width) * PixelFormat::Bgrx32.bytes_per_pixel();
+    let size = (pitch * usize::from(height)) as u64;
+    let virt = memory::map_physical_region(phys, size, mapper, frame_allocator)
+        .map_err(FramebufferError::Mapping)?;
+
+    // The mode can only be changed while the extensions are disabled
+    vbe_write(VBE_DISPI_INDEX_ENABLE, VBE_DISPI_DISABLED);
+    vbe_write(VBE_DISPI_INDEX_XRES, width);
+    vbe_write(VBE_DISPI_INDEX_YRES, height);
+    vbe_write(VBE_DISPI_INDEX_BPP, 32);
+    vbe_write(VBE_DISPI_INDEX_ENABLE, VBE_DISPI_ENABLED | VBE_DISPI_LFB_ENABLED);
+
+    Ok(unsafe {
+        Framebuffer::new(virt, usize::from(width), usize::from(height), pitch,
+                         PixelFormat::Bgrx32)
+    })
+}
+
+// Register values for VGA mode 13h (320x200, 256 colors, chained), in the order they are written
+const MODE_13H_MISC: u8 = 0x63;
+const MODE_13H_SEQUENCER: [u8; 5] = [0x03, 0x01, 0x0f, 0x00, 0x0e];
+const MODE_13H_CRTC: [u8; 25] = [
+    0x5f, 0x4f, 0x50, 0x82, 0x54, 0x80, 0xbf, 0x1f, 0x00, 0x41, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x9c, 0x0e, 0x8f, 0x28, 0x40, 0x96, 0xb9, 0xa3, 0xff,
+];
+const MODE_13H_GRAPHICS: [u8; 9] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x05, 0x0f, 0xff];
+const MODE_13H_ATTRIBUTE: [u8; 21] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+    0x41, 0x00, 0x0f, 0x00, 0x00,
+];
+
+const MODE_13H_WIDTH: usize = 320;
+const MODE_13H_HEIGHT: usize = 200;
+const MODE_13H_ADDRESS: u64 = 0xa0000;
+
+/// The 16 colors of VGA text mode, also the first 16 entries of the mode 13h palette
+pub const VGA_PALETTE: [Rgb; 16] = [
+    Rgb::new(0x00, 0x00, 0x00), Rgb::new(0x00, 0x00, 0xaa), Rgb::new(0x00, 0xaa, 0x00),
+    Rgb::new(0x00, 0xaa, 0xaa), Rgb::new(0xaa, 0x00, 0x00), Rgb::new(0xaa, 0x00, 0xaa),
+    Rgb::new(0xaa, 0x55, 0x00), Rgb::new(0xaa, 0xaa, 0xaa), Rgb::new(0x55, 0x55, 0x55),
+    Rgb::new(0x55, 0x55, 0xff), Rgb::new(0x55, 0xff, 0x55), Rgb::new(0x55, 0xff, 0xff),
+    Rgb::new(0xff, 0x55, 0x55), Rgb::new(0xff, 0x55, 0xff), Rgb::new(0xff, 0xff, 0x55),
+    Rgb::new(0xff, 0xff, 0xff),
+];
+
+// After the 16 VGA colors the mode 13h palette holds a 6x6x6 color cube, then a gray ramp
+const PALETTE_CUBE_START: u8 = 16;
+const PALETTE_GRAY_START: u8 = PALETTE_CUBE_START + 6 * 6 * 6;
+const PALETTE_GRAY_STEPS: u8 = 24;
+
+/// Returns the mode 13h palette entry closest to `color`
+fn palette_index(color: Rgb) -> u8 {
+    if let Some(index) = VGA_PALETTE.iter().position(|&entry| entry == color) {
+        return index as u8;
+    }
+    let level = |component: u8| ((u16::from(component) * 5 + 127) / 255) as u8;
+    let cube = PALETTE_CUBE_START + level(color.red) * 36 + level(color.green) * 6
+        + level(color.blue);
+    let average = (u16::from(color.red) + u16::from(color.green) + u16::from(color.blue)) / 3;
+    let gray = PALETTE_GRAY_START
+        + ((average * u16::from(PALETTE_GRAY_STEPS - 1) + 127) / 255) as u8;
+
+    let distance = |index: u8| {
+        let entry = palette_color(index);
+        let delta = |a: u8, b: u8| (i32::from(a) - i32::from(b)).pow(2);
+        delta(entry.red, color.red) + delta(entry.green, color.green)
+            + delta(entry.blue, color.blue)
+    };
+    if distance(gray) < distance(cube) { gray } else { cube }
+}
+
+/// Returns the color of the given mode 13h palette entry
+fn palette_color(index: u8) -> Rgb {
+    if index < PALETTE_CUBE_START {
+        VGA_PALETTE[usize::from(index)]
+    } else if index < PALETTE_GRAY_START {
+        let cube = index - PALETTE_CUBE_START;
+        let level = |value: u8| (u16::from(value) * 255 / 5) as u8;
+        Rgb::new(level(cube / 36), level(cube / 6 % 6), level(cube % 6))
+    } else {
+        let step = index - PALETTE_GRAY_START;
+        let gray = (u16::from(step) * 255 / u16::from(PALETTE_GRAY_STEPS - 1)) as u8;
+        Rgb::new(gray, gray, gray)
+    }
+}
+
+/// Switches to VGA mode 13h by programming the VGA registers directly
+///
+/// Mode 13h overwrites the font stored in the card, so there is no way back to text mode.
+pub fn init_mode_13h() -> Framebuffer {
+    let mut misc: Port<u8> = Port::new(0x3c2);
+    let mut sequencer_index: Port<u8> = Port::new(0x3c4);
+    let mut sequencer_data: Port<u8> = Port::new(0x3c5);
+    let mut crtc_index: Port<u8> = Port::new(0x3d4);
+    let mut crtc_data: Port<u8> = Port::new(0x3d5);
+    let mut graphics_index: Port<u8> = Port::new(0x3ce);
+    let mut graphics_data: Port<u8> = Port::new(0x3cf);
+    let mut attribute: Port<u8> = Port::new(0x3c0); // index and data share the same port
+    let mut input_status: Port<u8> = Port::new(0x3da); // reading resets the attribute flip-flop
+    let mut dac_index: Port<u8> = Port::new(0x3c8);
+    let mut dac_data: Port<u8> = Port::new(0x3c9);
+
+    unsafe {
+        misc.write(MODE_13H_MISC);
+        for (index, value) in MODE_13H_SEQUENCER.iter().enumerate() {
+            sequencer_index.write(index as u8);
+            sequencer_data.write(*value);
+        }
+
+        // CRTC registers 0-7 are write protected by bit 7 of register 0x11
+        let mut crtc = MODE_13H_CRTC;
+        crtc[0x03] |= 0x80;
+        crtc[0x11] &= !0x80;
+        crtc_index.write(0x11);
+        let protect = crtc_data.read();
+        crtc_data.write(protect & !0x80);
+        for (index, value) in crtc.iter().enumerate() {
+            crtc_index.write(index as u8);
+            crtc_data.write(*value);
+        }
+
+        for (index, value) in MODE_13H_GRAPHICS.iter().enumerate() {
+            graphics_index.write(index as u8);
+            graphics_data.write(*value);
+        }
+
+        for (index, value) in MODE_13H_ATTRIBUTE.iter().enumerate() {
+            input_status.read();
+            attribute.write(index as u8);
+            attribute.write(*value);
+        }
+        // Give the palette back to the display, which turns the screen on again
+        input_status.read();
+        attribute.write(0x20);
+
+        // The DAC takes 6 bit color components, starting at the selected palette entry
+        dac_index.write(0);
+        for index in 0..=255u8 {
+            let color = palette_color(index);
+            dac_data.write(color.red >> 2);
+            dac_data.write(color.green >> 2);
+            dac_data.write(color.blue >> 2);
+        }
+    }
+
+    let base = memory::phys_to_virt(PhysAddr::new(MODE_13H_ADDRESS));
+    unsafe {
+        Framebuffer::new(base, MODE_13H_WIDTH, MODE_13H_HEIGHT, MODE_13H_WIDTH,
+                         PixelFormat::Indexed8)
+    }
+}
+
+#[test_case]
+fn test_palette_index_round_trip() { // palette lookups return the exact entry for palette colors
+    for index in 0..=255u8 {
+        assert_eq!(palette_color(palette_index(palette_color(index))), palette_color(index));
+    }
+    assert_eq!(palette_index(Rgb::new(0xff, 0xff, 0x55)), 14);
+}
@@ -0,0 +1,98 @@
+// This file defines a text console drawn to a pixel framebuffer
+//
+// It shows the same grid of characters as the VGA text buffer, so a virtual console can be shown
+// either in text mode or on a framebuffer without knowing the difference.
+
+use super::font::{glyph, GLYPH_HEIGHT, GLYPH_WIDTH};
+use super::{Framebuffer, VGA_PALETTE};
+use crate::vga_buffer::{ColorCode, BUFFER_HEIGHT, BUFFER_WIDTH};
+
+// Number of glyph lines at the bottom of a cell that make up the cursor
+const CURSOR_LINES: usize = 1;
+
+pub struct TextConsole {
+    framebuffer: Framebuffer,
+    scale_x: usize, // every glyph pixel is drawn as a scale_x by scale_y block
+    scale_y: usize,
+    origin_x: usize, // top left pixel of the character grid, which is centered on screen
+    origin_y: usize,
+    drawn: [[Option<(u8, ColorCode)>; BUFFER_WIDTH]; BUFFER_HEIGHT], // what each cell currently
+                                                                      // shows, so unchanged cells
+                                                                      // are not drawn again
+    cursor: (usize, usize),
+}
+
+impl TextConsole {
+    /// Creates a console that renders to `framebuffer`
+    ///
+    /// Glyphs are scaled up as far as the framebuffer allows, but at most to twice as high as
+    /// wide. A framebuffer smaller than 640x200 (like mode 13h) only shows part of the grid.
+    pub fn new(framebuffer: Framebuffer) -> TextConsole {
+        let grid_width = BUFFER_WIDTH * GLYPH_WIDTH;
+        let grid_height = BUFFER_HEIGHT * GLYPH_HEIGHT;
+        let scale_x = (framebuffer.width() / grid_width).max(1);
+        let scale_y = (framebuffer.height() / grid_height).clamp(1, 2 * scale_x);
+        let origin_x = framebuffer.width().saturating_sub(grid_width * scale_x) / 2;
+        let origin_y = framebuffer.height().saturating_sub(grid_height * scale_y) / 2;
+
+        let mut console = TextConsole {
+            framebuffer,
+            scale_x,
+            scale_y,
+            origin_x,
+            origin_y,
+            drawn: [[None; BUFFER_WIDTH]; BUFFER_HEIGHT],
+            cursor: (BUFFER_HEIGHT - 1, 0),
+        };
+        console.framebuffer.clear(VGA_PALETTE[0]);
+        console
+    }
+
+    /// Draws `character` with the given colors into the cell at (`row`, `col`)
+    pub fn draw_char(&mut self, row: usize, col: usize, character: u8, color_code: ColorCode) {
+        if row >= BUFFER_HEIGHT || col >= BUFFER_WIDTH {
+            return;
+        }
+        if self.drawn[row][col] == Some((character, color_code)) {
+            return;
+        }
+        self.drawn[row][col] = Some((character, color_code));
+        self.render_cell(row, col);
+    }
+
+    /// Moves the cursor, shown as an underline in the foreground color, to (`row`, `col`)
+    pub fn set_cursor(&mut self, row: usize, col: usize) {
+        let previous = self.cursor;
+        self.cursor = (row.min(BUFFER_HEIGHT - 1), col.min(BUFFER_WIDTH - 1));
+        if previous != self.cursor {
+            self.render_cell(previous.0, previous.1);
+        }
+        self.render_cell(self.cursor.0, self.cursor.1);
+    }
+
+    fn render_cell(&mut self, row: usize, col: usize) {
+        let (character, color_code) = match self.drawn[row][col] {
+            Some(cell) => cell,
+            None => return,
+        };
+        let attribute = color_code.as_u8();
+        let foreground = VGA_PALETTE[usize::from(attribute & 0x0f)];
+        let background = VGA_PALETTE[usize::from(attribute >> 4)];
+        let mut lines = glyph(character);
+        if (row, col) == self.cursor {
+            for line in lines.iter_mut().rev().take(CURSOR_LINES) {
+                *line = 0xff;
+            }
+        }
+
+        let x = self.origin_x + col * GLYPH_WIDTH * self.scale_x;
+        let y = self.origin_y + row * GLYPH_HEIGHT * self.scale_y;
+        for (line_index, line) in lines.iter().enumerate() {
+            for bit in 0..GLYPH_WIDTH {
+                let color = if line & (1 << bit) != 0 { foreground } else { background };
+                self.framebuffer.fill_rect(x + bit * self.scale_x, y + line_index * self.scale_y,
+                                           self.scale_x, self.scale_y, color);
+            }
+        }
+    }
+}
@@ -0,0 +1,35 @@
+// This file maps code page 437 characters (the character set of the VGA text buffer) to the
+// 8x8 bitmap glyphs of the public domain font8x8 font, which is embedded into the kernel
+//
+// Every glyph is 8 bytes, one per line from top to bottom. Bit 0 of a line is its leftmost pixel.
+
+use font8x8::legacy::{BASIC_LEGACY, BLOCK_LEGACY, BOX_LEGACY};
+
+pub const GLYPH_WIDTH: usize = 8;
+pub const GLYPH_HEIGHT: usize = 8;
+
+/// ■, shown for characters the font has no glyph for. `Writer::write_string` also uses it for
+/// anything it cannot print
+const SQUARE: [u8; GLYPH_HEIGHT] = [0x00, 0x00, 0x3c, 0x3c, 0x3c, 0x3c, 0x00, 0x00];
+
+/// Returns the glyph for the given code page 437 character
+pub fn glyph(character: u8) -> [u8; GLYPH_HEIGHT] {
+    // BOX_LEGACY starts at U+2500 and BLOCK_LEGACY at U+2580
+    match character {
+        0x20..=0x7e => BASIC_LEGACY[usize::from(character)],
+        0xb3 => BOX_LEGACY[0x02], // │
+        0xba => BOX_LEGACY[0x51], // ║
+        0xbb => BOX_LEGACY[0x57], // ╗
+        0xbc => BOX_LEGACY[0x5d], // ╝
+        0xbf => BOX_LEGACY[0x10], // ┐
+        0xc0 => BOX_LEGACY[0x14], // └
+        0xc4 => BOX_LEGACY[0x00], // ─
+        0xc8 => BOX_LEGACY[0x5a], // ╚
+        0xc9 => BOX_LEGACY[0x54], // ╔
+        0xcd => BOX_LEGACY[0x50], // ═
+        0xd9 => BOX_LEGACY[0x18], // ┘
+        0xda => BOX_LEGACY[0x0c], // ┌
+        0xdb => BLOCK_LEGACY[0x08], // █
+        _ => SQUARE,
+    }
+}
@@ -1,3 +1,5 @@
+use alloc::boxed::Box;
+use alloc::vec;
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
@@ -5,26 +7,62 @@ use lazy_static::lazy_static;
 
 // Global Descriptor Table: Used for switching between kernel space and user space, and loading a
 // Task State Segment Structure
+//
+// Every CPU needs its own TSS, since the TSS holds the stacks the CPU switches to on interrupts,
+// and thereby its own GDT. The GDT and TSS of the boot CPU are statics since they are loaded
+// before the heap exists, those of the other CPUs are allocated when they start.
 
+/// Loads the GDT and TSS of the boot CPU
 pub fn init() {
+    load(&GDT);
+}
+
+/// Creates and loads a GDT and TSS for the calling CPU. Used for all CPUs but the boot CPU
+pub fn init_cpu() {
+    use x86_64::instructions::segmentation::{Segment, DS, ES, SS};
+
+    let stack = Box::leak(vec![0u8; DOUBLE_FAULT_STACK_SIZE].into_boxed_slice());
+    let stack_end = VirtAddr::from_ptr(stack.as_ptr()) + DOUBLE_FAULT_STACK_SIZE;
+    let tss = Box::leak(Box::new(new_tss(stack_end)));
+    let gdt = Box::leak(Box::new(new_gdt(tss)));
+    load(gdt);
+    unsafe {
+        // The segment registers may still hold selectors of the GDT the CPU was started with
+        let null = SegmentSelector(0);
+        SS::set_reg(null);
+        DS::set_reg(null);
+        ES::set_reg(null);
+    }
+}
+
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
     use x86_64::instructions::tables::load_tss;
     use x86_64::instructions::segmentation::{CS, Segment};
-    GDT.0.load();
+    gdt.0.load();
     unsafe {
         // Reload the code segment register since we changed our GDT
-        CS::set_reg(GDT.1.code_selector);
+        CS::set_reg(gdt.1.code_selector);
         // Load the TSS (tell the CPU that it should use that TSS)
-        load_tss(GDT.1.tss_selector);
+        load_tss(gdt.1.tss_selector);
     }
 }
 
+fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (gdt, Selectors {code_selector, tss_selector})
+}
+
+/// Creates a TSS whose double fault stack ends at `double_fault_stack_end`
+fn new_tss(double_fault_stack_end: VirtAddr) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack_end;
+    tss
+}
+
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors {code_selector, tss_selector})
-    };
+    static ref GDT: (GlobalDescriptorTable, Selectors) = new_gdt(&TSS);
 }
 
 struct Selectors {
@@ -33,21 +71,17 @@ struct Selectors {
 }
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
 
 // Use lazy_static because Rust's const evaluator is not powerful enough to do this initialization
 // at compile time
 lazy_static! {
     static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
         // Note: this double fault stack has no guard page to protect against stack overflow
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            // stacks on x86_64 grow downwards, i.e. from high addresses to low addresses
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
+        static mut STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+        // stacks on x86_64 grow downwards, i.e. from high addresses to low addresses
+        let stack_start = VirtAddr::from_ptr(&raw const STACK);
+        let stack_end = stack_start + DOUBLE_FAULT_STACK_SIZE;
+        new_tss(stack_end)
     };
 }
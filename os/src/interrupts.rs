@@ -1,9 +1,13 @@
-use x86_64::structures::idt::{InterruptDescriptorTable,InterruptStackFrame};
-use crate::{println,print};
+use alloc::vec::Vec;
+use x86_64::structures::idt::{HandlerFunc,InterruptDescriptorTable,InterruptStackFrame};
+use crate::println;
 use lazy_static::lazy_static;
-use crate::{gdt,hlt_loop};
+use crate::{apic,gdt,hlt_loop,percpu,time};
+use crate::sync::{IrqSpinLock,RcuCell};
+use core::sync::atomic::{AtomicU64, Ordering};
 use pic8259::ChainedPics;
-use spin;
+use spin::Once;
+use x86_64::instructions::port::Port;
 use x86_64::structures::idt::PageFaultErrorCode;
 
 // This file defines how the OS should handle various interrupts
@@ -33,8 +37,12 @@ lazy_static! {
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        // All other ISA IRQs go to the handlers drivers register with `register_irq`
+        for irq in 1..apic::ISA_IRQS {
+            idt[usize::from(PIC_1_OFFSET + irq)].set_handler_fn(IRQ_STUBS[usize::from(irq)]);
+        }
         idt.page_fault.set_handler_fn(page_fault_handler);
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
@@ -63,7 +71,7 @@ fn test_breakpoint_exception() {
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(
+pub static PICS: IrqSpinLock<ChainedPics> = IrqSpinLock::new(
     unsafe {ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)});
 
 #[derive(Debug, Clone, Copy)]
@@ -74,7 +82,7 @@ pub enum InterruptIndex {
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
 
@@ -84,45 +92,226 @@ impl InterruptIndex {
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
-    // Send End Of Interrupt (EOI) signal
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+    percpu::enter_interrupt();
+    count_interrupt(InterruptIndex::Timer.as_u8());
+    time::tick();
+    end_of_interrupt(InterruptIndex::Timer);
+    percpu::leave_interrupt();
+}
+
+/// Sends the End Of Interrupt (EOI) signal to whichever interrupt controller is in use
+pub fn end_of_interrupt(index: InterruptIndex) {
+    end_of_vector(index.as_u8());
+}
+
+fn end_of_vector(vector: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(vector);
+        }
+    }
+}
+
+// The local APIC raises a spurious interrupt when an interrupt goes away before it could be
+// delivered. It is not a real interrupt, so it must not be acknowledged
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    count_interrupt(apic::SPURIOUS_VECTOR);
+}
+
+// Number of interrupts received on each vector, and of spurious IRQs from the PICs
+static INTERRUPT_COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+static SPURIOUS_IRQS: AtomicU64 = AtomicU64::new(0);
+
+fn count_interrupt(vector: u8) {
+    INTERRUPT_COUNTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns how many interrupts arrived on the given vector since boot
+pub fn interrupt_count(vector: u8) -> u64 {
+    INTERRUPT_COUNTS[usize::from(vector)].load(Ordering::Relaxed)
+}
+
+/// Returns how many interrupts arrived on the given ISA IRQ since boot, not counting spurious ones
+pub fn irq_count(irq: u8) -> u64 {
+    interrupt_count(PIC_1_OFFSET + irq)
+}
+
+/// Returns how many spurious IRQ 7 and IRQ 15 the PICs raised since boot
+pub fn spurious_irq_count() -> u64 {
+    SPURIOUS_IRQS.load(Ordering::Relaxed)
+}
+
+// Handlers of the ISA IRQs 1 to 15. The timer (IRQ 0) and the cascade from the secondary PIC
+// (IRQ 2) can't have handlers. An IRQ line can be shared by several devices, so every IRQ has a
+// list of handlers, which are all called one after another; each one checks whether its device
+// raised the interrupt. The lists are read by every interrupt and rarely change, so they live in
+// an `RcuCell`, which interrupt handlers read without taking a lock. It is created by the first
+// registration, since it needs the heap.
+
+/// A function handling an IRQ. Runs in interrupt context, the EOI is sent after it returns
+pub type IrqHandler = fn();
+
+type IrqHandlers = [Vec<(u64, IrqHandler)>; apic::ISA_IRQS as usize];
+
+static IRQ_HANDLERS: Once<RcuCell<IrqHandlers>> = Once::new();
+static NEXT_HANDLER_ID: AtomicU64 = AtomicU64::new(0);
+// Held across a change to the handlers and the matching mask change, so a concurrent
+// `unregister_irq` can't mask an IRQ that `register_irq` just gave a handler
+static IRQ_REGISTRATION: spin::Mutex<()> = spin::Mutex::new(());
+
+const TIMER_IRQ: u8 = 0;
+const CASCADE_IRQ: u8 = 2;
+
+/// Identifies a registered IRQ handler, to unregister it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqHandlerId {
+    irq: u8,
+    id: u64,
+}
+
+impl IrqHandlerId {
+    pub fn irq(&self) -> u8 {
+        self.irq
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IrqError {
+    /// There are only 16 ISA IRQs
+    InvalidIrq(u8),
+    /// The IRQ is used by the kernel itself (the timer or the PIC cascade)
+    Reserved(u8),
+}
+
+/// Adds `handler` to the handlers of the given ISA IRQ and unmasks the IRQ
+///
+/// Handlers are called in the order they were registered. Needs the heap, and must not be called
+/// from an interrupt handler.
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<IrqHandlerId, IrqError> {
+    if irq >= apic::ISA_IRQS {
+        return Err(IrqError::InvalidIrq(irq));
+    }
+    if irq == TIMER_IRQ || irq == CASCADE_IRQ {
+        return Err(IrqError::Reserved(irq));
+    }
+    let id = NEXT_HANDLER_ID.fetch_add(1, Ordering::Relaxed);
+    let handlers = IRQ_HANDLERS.call_once(|| RcuCell::new(Default::default()));
+    let _registration = IRQ_REGISTRATION.lock();
+    handlers.update(|old| {
+        let mut new = old.clone();
+        new[usize::from(irq)].push((id, handler));
+        new
+    });
+    set_irq_masked(irq, false);
+    Ok(IrqHandlerId { irq, id })
+}
+
+/// Removes a handler added by `register_irq`, and masks the IRQ if it was the last one. Returns
+/// false if the handler was not registered
+pub fn unregister_irq(handler: IrqHandlerId) -> bool {
+    let handlers = match IRQ_HANDLERS.r#try() {
+        Some(handlers) => handlers,
+        None => return false,
+    };
+    let irq = usize::from(handler.irq);
+    let mut removed = false;
+    let mut last = false;
+    let _registration = IRQ_REGISTRATION.lock();
+    handlers.update(|old| {
+        let mut new = old.clone();
+        new[irq].retain(|&(id, _)| id != handler.id);
+        removed = new[irq].len() != old[irq].len();
+        last = new[irq].is_empty();
+        new
+    });
+    if last {
+        set_irq_masked(handler.irq, true);
     }
+    removed
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
+/// Returns whether any handler is registered for the given ISA IRQ
+pub fn has_irq_handler(irq: u8) -> bool {
+    IRQ_HANDLERS.r#try().is_some_and(|handlers| {
+        handlers.read().get(usize::from(irq)).is_some_and(|list| !list.is_empty())
+    })
+}
 
-    lazy_static!{
-        // By default, PS/2 keyboards emulate scancode set 1 (based on the IBM XT keyboard)
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(ScancodeSet1::new(),
-                layouts::Us104Key, HandleControl::Ignore) // Do not convert Ctrl+[a-z] to unicode
-                                                          // values, handle like normal keys
-            );
+/// Masks or unmasks an ISA IRQ at whichever interrupt controller is in use
+pub fn set_irq_masked(irq: u8, masked: bool) {
+    if apic::is_enabled() {
+        apic::set_irq_masked(irq, masked);
+    } else {
+        let mut pics = PICS.lock();
+        unsafe {
+            let mut masks = pics.read_masks();
+            let (pic, bit) = (usize::from(irq / 8), irq % 8);
+            if masked {
+                masks[pic] |= 1 << bit;
+            } else {
+                masks[pic] &= !(1 << bit);
+            }
+            // IRQs of the secondary PIC also need the cascade on the primary one
+            if pic == 1 && !masked {
+                masks[0] &= !(1 << CASCADE_IRQ);
+            }
+            pics.write_masks(masks[0], masks[1]);
+        }
     }
+}
 
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60); // Data port of the PS/2 controller
+// One entry point per ISA IRQ, since the handler can't tell from the stack frame which vector it
+// was called for
+extern "x86-interrupt" fn irq_stub<const IRQ: u8>(_stack_frame: InterruptStackFrame) {
+    handle_irq(IRQ);
+}
 
-    let scancode: u8 = unsafe {port.read()};
+const IRQ_STUBS: [HandlerFunc; apic::ISA_IRQS as usize] = [
+    irq_stub::<0>, irq_stub::<1>, irq_stub::<2>, irq_stub::<3>,
+    irq_stub::<4>, irq_stub::<5>, irq_stub::<6>, irq_stub::<7>,
+    irq_stub::<8>, irq_stub::<9>, irq_stub::<10>, irq_stub::<11>,
+    irq_stub::<12>, irq_stub::<13>, irq_stub::<14>, irq_stub::<15>,
+];
 
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) { // add_byte translates the scancode
-                                                               // into an Option<KeyEvent>
-        if let Some(key) = keyboard.process_keyevent(key_event) { // Translate Key event to a
-                                                                  // character if possible
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
+fn handle_irq(irq: u8) {
+    percpu::enter_interrupt();
+    if is_spurious(irq) {
+        SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+        // The primary PIC did see a real interrupt from the secondary one on the cascade
+        if irq == 15 {
+            end_of_vector(PIC_1_OFFSET + CASCADE_IRQ);
+        }
+    } else {
+        count_interrupt(PIC_1_OFFSET + irq);
+        if let Some(handlers) = IRQ_HANDLERS.r#try() {
+            for &(_, handler) in handlers.read()[usize::from(irq)].iter() {
+                handler();
             }
         }
+        end_of_vector(PIC_1_OFFSET + irq);
     }
+    percpu::leave_interrupt();
+}
+
+// When an interrupt goes away before the CPU acknowledges it, the PIC raises its lowest priority
+// IRQ instead (7 on the primary, 15 on the secondary PIC), but doesn't set it in its In-Service
+// Register. Such a spurious IRQ must not be handled or acknowledged. The I/O APIC doesn't do this
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+const PIC_READ_ISR: u8 = 0x0b;
 
+fn is_spurious(irq: u8) -> bool {
+    if apic::is_enabled() || (irq != 7 && irq != 15) {
+        return false;
+    }
+    // Hold the lock so nobody else talks to the PICs in between
+    let _pics = PICS.lock();
+    let mut command: Port<u8> = Port::new(if irq == 7 { PIC_1_COMMAND } else { PIC_2_COMMAND });
     unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        command.write(PIC_READ_ISR);
+        command.read() & (1 << 7) == 0
     }
 }
 
@@ -0,0 +1,364 @@
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::task::Poll;
+use pc_keyboard::layouts::{AnyLayout, Azerty, De105Key, Dvorak104Key, Uk105Key, Us104Key};
+use pc_keyboard::{DecodedKey, Error, EventDecoder, HandleControl, KeyCode, KeyEvent, KeyState,
+    ScancodeSet, ScancodeSet1, ScancodeSet2};
+use crate::driver::{Device, Driver, DriverError, Match};
+use crate::sync::{ArrayQueue, AtomicWaker};
+use crate::interrupts::{self, IrqError, IrqHandlerId};
+use crate::ps2::{self, DeviceType, Ps2Port};
+use crate::{power, tty, vga_buffer};
+
+// This file drives the PS/2 keyboard on the first port of the PS/2 controller and turns the
+// scancodes it sends into key presses. The IRQ handler only reads the scancode and pushes it into
+// a lock-free queue; decoding and acting on the keys (which takes locks on the consoles) happens
+// in `run`, outside of interrupt context.
+//
+//  keyboard IRQ ---> add_scancode ---> SCANCODES ---> run: decode, shortcuts ---> TTY
+//
+// Keys reach the TTY the way a terminal would send them: Ctrl+letter as control characters, and
+// keys without a character (arrows, Home, ...) as ANSI escape sequences.
+//
+// Once the IRQ handler is registered, the answers to commands sent to the keyboard (e.g. to set
+// the LEDs) arrive through it as well, so `run` sends the commands and handles their ACKs.
+
+const KEYBOARD_IRQ: u8 = 1;
+
+// Keyboard commands
+const SET_LEDS: u8 = 0xed;
+const SET_SCANCODE_SET: u8 = 0xf0;
+const SET_TYPEMATIC: u8 = 0xf3;
+
+// Bits of the SET_LEDS data byte
+const LED_SCROLL_LOCK: u8 = 1 << 0;
+const LED_NUM_LOCK: u8 = 1 << 1;
+const LED_CAPS_LOCK: u8 = 1 << 2;
+
+// Keys repeat every 1/30 s (bits 0-4 = 0) after being held for 500 ms (bits 5-6 = 1)
+const TYPEMATIC: u8 = 0x20;
+
+// Scancodes arriving while the queue is full are dropped
+const SCANCODE_QUEUE_SIZE: usize = 128;
+
+static SCANCODES: ArrayQueue<u8, SCANCODE_QUEUE_SIZE> = ArrayQueue::new();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+// The scancode set the keyboard sends, 1 unless `init` could switch it to set 2
+static SCANCODE_SET: AtomicU8 = AtomicU8::new(1);
+static LAYOUT: AtomicU8 = AtomicU8::new(Layout::Us as u8);
+
+// Number of rows a Shift+PageUp/PageDown scrolls the screen by
+const SCROLL_STEP: usize = vga_buffer::BUFFER_HEIGHT / 2;
+
+/// The keyboard layouts to choose from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Layout {
+    Us,
+    Uk,
+    De,
+    Dvorak,
+    Azerty,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 5] = [Layout::Us, Layout::Uk, Layout::De, Layout::Dvorak,
+                                  Layout::Azerty];
+
+    /// Returns the short name of the layout, e.g. "us"
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us => "us",
+            Layout::Uk => "uk",
+            Layout::De => "de",
+            Layout::Dvorak => "dvorak",
+            Layout::Azerty => "azerty",
+        }
+    }
+
+    /// Returns the layout with the given short name
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Layout::ALL.iter().copied().find(|layout| layout.name() == name)
+    }
+
+    fn from_u8(value: u8) -> Layout {
+        Layout::ALL.get(usize::from(value)).copied().unwrap_or(Layout::Us)
+    }
+
+    fn any_layout(self) -> AnyLayout {
+        match self {
+            Layout::Us => AnyLayout::Us104Key(Us104Key),
+            Layout::Uk => AnyLayout::Uk105Key(Uk105Key),
+            Layout::De => AnyLayout::De105Key(De105Key),
+            Layout::Dvorak => AnyLayout::Dvorak104Key(Dvorak104Key),
+            Layout::Azerty => AnyLayout::Azerty(Azerty),
+        }
+    }
+}
+
+/// Returns the keyboard layout in use
+pub fn layout() -> Layout {
+    Layout::from_u8(LAYOUT.load(Ordering::Relaxed))
+}
+
+/// Switches the keyboard layout, starting with the next key press
+pub fn set_layout(layout: Layout) {
+    LAYOUT.store(layout as u8, Ordering::Relaxed);
+}
+
+/// Returns the scancode set the keyboard sends (1 or 2)
+pub fn scancode_set() -> u8 {
+    SCANCODE_SET.load(Ordering::Relaxed)
+}
+
+// Decodes either scancode set, chosen at runtime
+enum Scancodes {
+    Set1(ScancodeSet1),
+    Set2(ScancodeSet2),
+}
+
+impl ScancodeSet for Scancodes {
+    fn advance_state(&mut self, code: u8) -> Result<Option<KeyEvent>, Error> {
+        match self {
+            Scancodes::Set1(set) => set.advance_state(code),
+            Scancodes::Set2(set) => set.advance_state(code),
+        }
+    }
+}
+
+/// Configures the keyboard and registers its IRQ handler. Needs the heap, and `ps2::init` to have
+/// found the keyboard; without it, the keyboard is used the way the firmware left it
+pub fn init() -> Result<IrqHandlerId, IrqError> {
+    if ps2::device(Ps2Port::First) == Some(DeviceType::Keyboard) {
+        configure();
+    }
+    let handler = interrupts::register_irq(KEYBOARD_IRQ, interrupt_handler)?;
+    // A scancode that arrived before there was a handler was never read, and the controller
+    // doesn't raise another interrupt until it is
+    ps2::flush_output();
+    Ok(handler)
+}
+
+// The IRQ handler registered by `KeyboardDriver`
+static HANDLER: spin::Mutex<Option<IrqHandlerId>> = spin::Mutex::new(None);
+
+/// The driver of the keyboard `ps2::Ps2Driver` finds
+pub struct KeyboardDriver;
+
+impl Driver for KeyboardDriver {
+    fn name(&self) -> &'static str {
+        "keyboard"
+    }
+
+    fn matches(&self) -> &'static [Match] {
+        &[Match::Isa("keyboard")]
+    }
+
+    fn attach(&self, _device: &Device) -> Result<(), DriverError> {
+        *HANDLER.lock() = Some(init().map_err(DriverError::Irq)?);
+        Ok(())
+    }
+
+    fn detach(&self, _device: &Device) -> Result<(), DriverError> {
+        if let Some(handler) = HANDLER.lock().take() {
+            interrupts::unregister_irq(handler);
+        }
+        Ok(())
+    }
+}
+
+/// Switches the keyboard to scancode set 2 and sets the repeat rate and the LEDs. Failures are
+/// not fatal, the keyboard just keeps its defaults
+fn configure() {
+    // Set 2 is the one every keyboard supports natively, set 1 only exists through the
+    // controller's translation
+    let set_2 = ps2::device_command(Ps2Port::First, &[SET_SCANCODE_SET, 2]).is_ok()
+        && ps2::set_translation(false).is_ok();
+    if set_2 {
+        SCANCODE_SET.store(2, Ordering::Relaxed);
+    } else {
+        let _ = ps2::set_translation(true);
+    }
+    let _ = ps2::device_command(Ps2Port::First, &[SET_TYPEMATIC, TYPEMATIC]);
+    let _ = ps2::device_command(Ps2Port::First, &[SET_LEDS, LED_NUM_LOCK]);
+}
+
+fn interrupt_handler() {
+    add_scancode(ps2::read_data());
+}
+
+/// Queues a scancode for `run` and wakes it. Called by the keyboard interrupt handler, so it
+/// neither blocks nor allocates. Returns false if the queue was full and the scancode dropped
+pub fn add_scancode(scancode: u8) -> bool {
+    let queued = SCANCODES.push(scancode).is_ok();
+    WAKER.wake();
+    queued
+}
+
+/// Waits for the next scancode from the keyboard
+///
+/// Only one task may wait for scancodes at a time.
+pub async fn next_scancode() -> u8 {
+    poll_fn(|context| {
+        if let Some(scancode) = SCANCODES.pop() {
+            return Poll::Ready(scancode);
+        }
+        // Check again after registering, the scancode may have come in between
+        WAKER.register(context.waker());
+        match SCANCODES.pop() {
+            Some(scancode) => Poll::Ready(scancode),
+            None => Poll::Pending,
+        }
+    }).await
+}
+
+/// Handles key presses forever: runs the keyboard shortcuts and feeds the keys typed to the TTY
+/// of the console that is currently shown
+pub async fn run() {
+    let mut scancodes = match scancode_set() {
+        2 => Scancodes::Set2(ScancodeSet2::new()),
+        _ => Scancodes::Set1(ScancodeSet1::new()),
+    };
+    let mut current_layout = layout();
+    // Ctrl+[a-z] become the control characters 0x01-0x1a, like on a terminal
+    let mut decoder = EventDecoder::new(current_layout.any_layout(),
+                                        HandleControl::MapLettersToUnicode);
+    // pc-keyboard does not expose the modifier state it keeps internally (and does not track Alt
+    // at all), so we track the modifiers used by our own keyboard shortcuts here. The left and
+    // right keys are tracked separately, releasing one while the other is held keeps it pressed
+    let (mut left_shift, mut right_shift) = (false, false);
+    let (mut left_ctrl, mut right_ctrl) = (false, false);
+    let (mut left_alt, mut right_alt) = (false, false);
+    // The LEDs follow the lock states of the decoder, which starts with Num Lock on
+    let mut leds = LED_NUM_LOCK;
+    let mut held_locks = 0;
+    let mut pending_leds = None;
+
+    loop {
+        let scancode = next_scancode().await;
+        match scancode {
+            // The keyboard acknowledged SET_LEDS and waits for the new state
+            ps2::ACK => {
+                if let Some(leds) = pending_leds.take() {
+                    let _ = ps2::write_device(Ps2Port::First, leds);
+                }
+                continue;
+            }
+            ps2::RESEND => {
+                if pending_leds.is_some() {
+                    let _ = ps2::write_device(Ps2Port::First, SET_LEDS);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        if layout() != current_layout {
+            current_layout = layout();
+            decoder.change_layout(current_layout.any_layout());
+        }
+        let Ok(Some(key_event)) = scancodes.advance_state(scancode) else {
+            continue;
+        };
+        let down = key_event.state == KeyState::Down;
+        match key_event.code {
+            KeyCode::LShift => left_shift = down,
+            KeyCode::RShift => right_shift = down,
+            KeyCode::LAlt => left_alt = down,
+            KeyCode::RAltGr => right_alt = down,
+            KeyCode::LControl => left_ctrl = down,
+            KeyCode::RControl => right_ctrl = down,
+            _ => {}
+        }
+        let (shift, ctrl, alt) = (left_shift || right_shift, left_ctrl || right_ctrl,
+                                  left_alt || right_alt);
+        match key_event.code {
+            // Ctrl+Alt+Del restarts the machine
+            KeyCode::Delete if down && ctrl && alt => power::reboot(),
+            // A held lock key repeats, but should only toggle its lock once
+            KeyCode::CapsLock | KeyCode::NumpadLock | KeyCode::ScrollLock => {
+                let bit = lock_led(key_event.code);
+                if down && held_locks & bit != 0 {
+                    continue;
+                }
+                held_locks = if down { held_locks | bit } else { held_locks & !bit };
+            }
+            _ => {}
+        }
+
+        // Translate the key event to a character if possible
+        if let Some(key) = decoder.process_keyevent(key_event) {
+            // Keys typed go to the console that is currently shown
+            let console = vga_buffer::active_console();
+            let tty = tty::tty(console);
+            match key {
+                // Alt+F1..F6 switch between the virtual consoles
+                DecodedKey::RawKey(KeyCode::F1) if alt => vga_buffer::switch_console(0),
+                DecodedKey::RawKey(KeyCode::F2) if alt => vga_buffer::switch_console(1),
+                DecodedKey::RawKey(KeyCode::F3) if alt => vga_buffer::switch_console(2),
+                DecodedKey::RawKey(KeyCode::F4) if alt => vga_buffer::switch_console(3),
+                DecodedKey::RawKey(KeyCode::F5) if alt => vga_buffer::switch_console(4),
+                DecodedKey::RawKey(KeyCode::F6) if alt => vga_buffer::switch_console(5),
+                // Shift+PageUp/PageDown scroll through the history instead of being printed
+                DecodedKey::RawKey(KeyCode::PageUp) if shift => {
+                    vga_buffer::CONSOLES[console].lock().scroll_up(SCROLL_STEP)
+                }
+                DecodedKey::RawKey(KeyCode::PageDown) if shift => {
+                    vga_buffer::CONSOLES[console].lock().scroll_down(SCROLL_STEP)
+                }
+                // The decoder toggled a lock, show it on the LEDs
+                DecodedKey::RawKey(code @ (KeyCode::CapsLock | KeyCode::NumpadLock
+                    | KeyCode::ScrollLock)) => {
+                    leds ^= lock_led(code);
+                    if ps2::device(Ps2Port::First) == Some(DeviceType::Keyboard)
+                        && ps2::write_device(Ps2Port::First, SET_LEDS).is_ok() {
+                        pending_leds = Some(leds);
+                    }
+                }
+                // The layouts map Delete to DEL, which terminals use for Backspace
+                DecodedKey::Unicode('\x7f') => tty.input_str("\x1b[3~"),
+                DecodedKey::Unicode(character) => tty.input(character),
+                DecodedKey::RawKey(key) => {
+                    if let Some(sequence) = escape_sequence(key) {
+                        tty.input_str(sequence);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the ANSI escape sequence a terminal sends for a key without a character
+fn escape_sequence(key: KeyCode) -> Option<&'static str> {
+    Some(match key {
+        KeyCode::ArrowUp => "\x1b[A",
+        KeyCode::ArrowDown => "\x1b[B",
+        KeyCode::ArrowRight => "\x1b[C",
+        KeyCode::ArrowLeft => "\x1b[D",
+        KeyCode::Home => "\x1b[H",
+        KeyCode::End => "\x1b[F",
+        KeyCode::Insert => "\x1b[2~",
+        KeyCode::PageUp => "\x1b[5~",
+        KeyCode::PageDown => "\x1b[6~",
+        _ => return None,
+    })
+}
+
+/// Returns the LED bit of a lock key
+fn lock_led(code: KeyCode) -> u8 {
+    match code {
+        KeyCode::CapsLock => LED_CAPS_LOCK,
+        KeyCode::NumpadLock => LED_NUM_LOCK,
+        KeyCode::ScrollLock => LED_SCROLL_LOCK,
+        _ => 0,
+    }
+}
+
+#[test_case]
+fn test_layout_names() {
+    for &layout in Layout::ALL.iter() {
+        assert_eq!(Layout::from_name(layout.name()), Some(layout));
+        assert_eq!(Layout::from_u8(layout as u8), layout);
+    }
+    assert_eq!(Layout::from_name("klingon"), None);
+}
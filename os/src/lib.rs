@@ -15,9 +15,27 @@ use bootloader::{entry_point, BootInfo};
 pub mod serial;
 pub mod vga_buffer;
 pub mod interrupts;
+pub mod keyboard;
+pub mod ps2;
+pub mod mouse;
+pub mod tty;
+pub mod shell;
 pub mod gdt;
 pub mod memory;
 pub mod allocator;
+pub mod framebuffer;
+pub mod time;
+pub mod rtc;
+pub mod apic;
+pub mod acpi;
+pub mod pci;
+pub mod driver;
+pub mod block;
+pub mod ata;
+pub mod power;
+pub mod smp;
+pub mod percpu;
+pub mod sync;
 
 /// Overwrite entry point for `cargo test`
 #[cfg(test)]
@@ -53,7 +71,7 @@ where
     fn run(&self) {
         serial_print!("{}...\t", core::any::type_name::<T>()); // For functions, their type is
                                                                // their name, so we print this to
-                                                               // SERIAL1
+                                                               // the serial log port
         self(); // invoke the test function (we require that self implements the Fn() trait
         serial_println!("[ok]"); // Indicate that the function did not panic
     }
@@ -92,10 +110,18 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 pub fn init() {
     // Initialize Global Descriptor Table
     gdt::init();
+    // Point the GS base register to the per-CPU data of the boot CPU
+    percpu::init_boot_cpu();
     // Initialize Interrupt Descriptor Table
     interrupts::init_idt();
     // Initialize Programmable Interrupt Controller (PIC8259)
     unsafe {interrupts::PICS.lock().initialize()};
+    // Program the Programmable Interval Timer (PIT8253) that drives the timer interrupt
+    time::init(time::DEFAULT_TICK_HZ);
+    // Read the date and time from the CMOS Real-Time Clock (RTC) to start the wall clock
+    rtc::init();
+    // Find the serial ports, before an interrupt handler could print to them
+    serial::init();
     // Tell the CPU to listen to the interrupt controller
     x86_64::instructions::interrupts::enable();
 }
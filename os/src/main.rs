@@ -24,6 +24,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     // Initialize Interrupt Descriptor Table
     NeekOS::init();
+    println!("Booted at {}", NeekOS::rtc::now());
 
     // Invoke a breakpoint exception
     // x86_64::instructions::interrupts::int3();
@@ -79,6 +80,56 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // Initialize heap and allocate memory on it
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
 
+    // Discover the hardware through the tables the firmware provides
+    if let Err(err) = NeekOS::acpi::init() {
+        println!("ACPI tables unavailable: {:?}", err);
+    }
+
+    // Find the devices on the PCI buses
+    match NeekOS::pci::init(&mut mapper, &mut frame_allocator) {
+        Ok(devices) => println!("{} PCI functions found", devices.len()),
+        Err(err) => println!("PCI configuration space unavailable: {:?}", err),
+    }
+
+    // Put the legacy devices and the PCI functions into the device tree and bind the drivers to
+    // them: the PS/2 controller, the keyboard and mouse behind it, the serial ports and the
+    // disks on the primary IDE channel
+    NeekOS::driver::init();
+    for device in NeekOS::driver::devices() {
+        if let Some(err) = device.error {
+            println!("{} unavailable: {:?}", device.name, err);
+        }
+    }
+    // Use the port the log goes to (COM1) as a terminal too, so the shell can be used over
+    // `-serial stdio`
+    let serial_console = NeekOS::serial::log_port();
+    if serial_console.is_receiving() {
+        serial_console.attach_console(NeekOS::vga_buffer::LOG_CONSOLE);
+    }
+
+    // Route interrupts through the local and I/O APIC instead of the legacy 8259 PICs
+    if let Err(err) = NeekOS::apic::init(&mut mapper, &mut frame_allocator) {
+        println!("APIC unavailable, using the 8259 PICs: {:?}", err);
+    }
+
+    // Start the other CPUs
+    match NeekOS::smp::init(&boot_info.memory_map, &mut mapper, &mut frame_allocator) {
+        Ok(cpus) => println!("{} CPUs online", cpus),
+        Err(err) => println!("Could not start the other CPUs: {:?}, {} CPUs online", err,
+                             NeekOS::smp::cpu_count()),
+    }
+
+    // Keep the rows that scroll off the screen so they can be read back with Shift+PageUp
+    NeekOS::vga_buffer::enable_scrollback();
+
+    // Built with `--features framebuffer`, the console is drawn to a pixel framebuffer, in mode
+    // 13h on cards without the Bochs VBE extensions
+    #[cfg(feature = "framebuffer")]
+    match NeekOS::framebuffer::init(640, 480, &mut mapper, &mut frame_allocator) {
+        Ok(framebuffer) => NeekOS::vga_buffer::use_framebuffer(framebuffer),
+        Err(err) => println!("framebuffer console unavailable: {:?}", err),
+    }
+
     // Allocate a number on the heap
     let heap_value = Box::new(42);
     println!("heap_value at {:p}", heap_value);
@@ -102,7 +153,13 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     println!("It did not crash!");
 
-    NeekOS::hlt_loop(); // wait for interrupts, sleep in the meantime
+    // Handle key presses, the mouse and serial input, and run the shell on the console the log
+    // goes to, sleeping while there is nothing to do
+    let mut shell = NeekOS::shell::Shell::new(NeekOS::vga_buffer::LOG_CONSOLE);
+    let devices = NeekOS::sync::join(NeekOS::keyboard::run(), NeekOS::mouse::run());
+    let input = NeekOS::sync::join(devices, serial_console.run());
+    NeekOS::sync::block_on(NeekOS::sync::join(input, shell.run()));
+    NeekOS::hlt_loop();
 }
 
 // This diverging function is called on panic.
@@ -3,6 +3,20 @@ use x86_64::PhysAddr;
 use x86_64::structures::paging::{OffsetPageTable, Page, PhysFrame, Mapper, Size4KiB, FrameAllocator};
 use bootloader::bootinfo::MemoryMap;
 use bootloader::bootinfo::MemoryRegionType;
+use x86_64::structures::paging::mapper::{MapToError, Translate};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use alloc::vec::Vec;
+use x86_64::structures::paging::PageTableFlags;
+
+/// End of the memory that is reachable in real mode, which the frame allocator doesn't hand out
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+
+// Virtual address at which the bootloader mapped the complete physical memory, saved by `init`
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// Frames the frame allocator can hand out, and how many of them it did
+static USABLE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_FRAMES: AtomicUsize = AtomicUsize::new(0);
 
 /// Initialize a new OffsetPageTable.
 ///
@@ -10,6 +24,7 @@ use bootloader::bootinfo::MemoryRegionType;
 /// mapped to virtual memory at the passed `physical_memory_offset`. Also, this function must be
 /// only called once to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
@@ -31,6 +46,126 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr // unsafe
 }
 
+/// One level of a page table walk
+#[derive(Debug, Clone, Copy)]
+pub struct PageWalkStep {
+    /// 4 for the level 4 table down to 1 for the level 1 table
+    pub level: u8,
+    /// Index of the entry in the table
+    pub index: u16,
+    /// Physical address the entry points to (the next table, or the frame)
+    pub address: PhysAddr,
+    pub flags: PageTableFlags,
+}
+
+/// The result of walking the page tables for a virtual address
+#[derive(Debug)]
+pub struct PageWalk {
+    /// The entries visited, from the level 4 table down. Stops at an entry that is not present
+    pub steps: Vec<PageWalkStep>,
+    /// The physical address the virtual address is mapped to, if it is mapped
+    pub physical: Option<PhysAddr>,
+}
+
+impl PageWalk {
+    /// Returns the flags that apply to the page, if the address is mapped
+    ///
+    /// The entries of every level restrict the access: the page is only writable or accessible
+    /// from user mode if all of them allow it, and not executable if any of them forbids it. The
+    /// other flags are those of the entry that maps the page.
+    pub fn flags(&self) -> Option<PageTableFlags> {
+        self.physical?;
+        Some(combined_flags(self.steps.iter().map(|step| step.flags)))
+    }
+}
+
+/// Combines the flags of the entries of a walk, from the level 4 table down, see `PageWalk::flags`
+fn combined_flags(levels: impl Iterator<Item = PageTableFlags>) -> PageTableFlags {
+    let all = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let (mut allowed, mut forbidden, mut leaf) = (all, PageTableFlags::empty(),
+                                                  PageTableFlags::empty());
+    for flags in levels {
+        allowed &= flags;
+        forbidden |= flags & PageTableFlags::NO_EXECUTE;
+        leaf = flags;
+    }
+    (leaf - all - PageTableFlags::NO_EXECUTE) | allowed | forbidden
+}
+
+/// Walks the active page tables for `address`, recording every entry on the way
+///
+/// Only valid once `init` was called.
+pub fn page_walk(address: VirtAddr) -> PageWalk {
+    let offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed));
+    // The tables are only read, so the mutable reference is given up right away
+    let mut table: &PageTable = unsafe { active_level_4_table(offset) };
+    let indexes = [address.p4_index(), address.p3_index(), address.p2_index(), address.p1_index()];
+    let mut steps = Vec::new();
+    for (depth, &index) in indexes.iter().enumerate() {
+        let level = 4 - depth as u8;
+        let entry = &table[index];
+        let step = PageWalkStep {
+            level,
+            index: u16::from(index),
+            address: entry.addr(),
+            flags: entry.flags(),
+        };
+        steps.push(step);
+        if !step.flags.contains(PageTableFlags::PRESENT) {
+            return PageWalk { steps, physical: None };
+        }
+        // A huge page at level 3 (1 GiB) or level 2 (2 MiB), or the frame at level 1
+        if level == 1 || step.flags.contains(PageTableFlags::HUGE_PAGE) {
+            let page_size = 1u64 << (12 + 9 * u64::from(level - 1));
+            let physical = step.address + (address.as_u64() & (page_size - 1));
+            return PageWalk { steps, physical: Some(physical) };
+        }
+        table = unsafe { &*phys_to_virt(step.address).as_ptr::<PageTable>() };
+    }
+    unreachable!("the level 1 entry ends the walk");
+}
+
+/// Returns how many frames the frame allocator handed out, and how many it can hand out in total
+pub fn frame_usage() -> (usize, usize) {
+    (ALLOCATED_FRAMES.load(Ordering::Relaxed), USABLE_FRAMES.load(Ordering::Relaxed))
+}
+
+/// Returns the virtual address through which the given physical address can be accessed
+///
+/// Only valid once `init` was called.
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + phys.as_u64())
+}
+
+/// Makes sure the `size` bytes of physical memory starting at `phys` are accessible through
+/// `phys_to_virt` and returns the virtual address of `phys`
+///
+/// The bootloader only maps physical memory up to the end of the highest region in the memory
+/// map, which does not necessarily cover memory mapped device registers or framebuffers. Pages
+/// that are not mapped yet are mapped uncached, as is required for device memory.
+pub fn map_physical_region(
+    phys: PhysAddr,
+    size: u64,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let start_frame: PhysFrame = PhysFrame::containing_address(phys);
+    let end_frame = PhysFrame::containing_address(phys + size.max(1) - 1u64);
+    for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
+        let page = Page::containing_address(phys_to_virt(frame.start_address()));
+        if mapper.translate_addr(page.start_address()).is_some() {
+            continue;
+        }
+        let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_CACHE;
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+        }
+    }
+    Ok(phys_to_virt(phys))
+}
+
 /// Creates an example mapping for the given page to frame `0xb8000`.
 pub fn create_example_mapping(
     page: Page,
@@ -54,6 +189,9 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
+        if frame.is_some() {
+            ALLOCATED_FRAMES.fetch_add(1, Ordering::Relaxed);
+        }
         frame
     }
 }
@@ -74,10 +212,13 @@ impl BootInfoFrameAllocator {
     /// valid. The main requirement is that all frames that are marked as `USABLE` in it are really
     /// unused.
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
+        let allocator = BootInfoFrameAllocator {
             memory_map,
             next: 0,
-        }
+        };
+        USABLE_FRAMES.store(allocator.usable_frames().count(), Ordering::Relaxed);
+        ALLOCATED_FRAMES.store(0, Ordering::Relaxed);
+        allocator
     }
 
     /// Returns an iterator over the usable frames specified in the memory map
@@ -87,9 +228,27 @@ impl BootInfoFrameAllocator {
         let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
         // map each region to its address range
         let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+        // transform to an iterator of frame start addresses. The first MiB is left alone, it is
+        // where the other CPUs start (see `smp`)
+        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096))
+            .filter(|&addr| addr >= LOW_MEMORY_END);
         // create `PhysFrame` types from the start addresses
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
 }
+
+#[test_case]
+fn test_combined_flags() {
+    use PageTableFlags as Flags;
+
+    let table = Flags::PRESENT | Flags::WRITABLE | Flags::USER_ACCESSIBLE;
+    let leaf = Flags::PRESENT | Flags::WRITABLE | Flags::USER_ACCESSIBLE | Flags::DIRTY;
+    // A read-only level 3 entry makes the page read-only
+    let levels = [table, Flags::PRESENT | Flags::USER_ACCESSIBLE, table, leaf];
+    let flags = combined_flags(levels.iter().copied());
+    assert_eq!(flags, Flags::PRESENT | Flags::USER_ACCESSIBLE | Flags::DIRTY);
+    // No-execute on a table entry applies to the page, user access needs every level
+    let levels = [table | Flags::NO_EXECUTE, Flags::PRESENT | Flags::WRITABLE, table, leaf];
+    let flags = combined_flags(levels.iter().copied());
+    assert_eq!(flags, Flags::PRESENT | Flags::WRITABLE | Flags::DIRTY | Flags::NO_EXECUTE);
+}
@@ -0,0 +1,333 @@
+use alloc::string::String;
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use core::task::Poll;
+use crate::driver::{Device, Driver, DriverError, Match};
+use crate::interrupts::{self, IrqError, IrqHandlerId};
+use crate::ps2::{self, DeviceType, Ps2Error, Ps2Port};
+use crate::sync::{ArrayQueue, AtomicWaker};
+use crate::{tty, vga_buffer};
+
+// This file drives the PS/2 mouse on the second port of the PS/2 controller. The mouse sends a
+// packet of 3 bytes whenever it moves or a button changes, or 4 bytes once its scroll wheel is
+// enabled:
+//
+//  byte 0: Y overflow | X overflow | Y sign | X sign | 1 | middle | right | left
+//  byte 1: X movement (the low 8 bits of a 9 bit two's complement number, sign in byte 0)
+//  byte 2: Y movement (same, positive is up)
+//  byte 3: wheel movement in bits 0-3 (4 bit two's complement), buttons 4 and 5 in bits 4 and 5
+//
+// The IRQ handler collects the bytes and pushes each complete packet as a `MouseEvent` into a
+// lock-free queue, which tasks read with `next_event`.
+//
+// `run` is the task that reads them: it moves a pointer over the console that is shown and
+// selects text with it. Dragging with the left button held selects the text from where it was
+// pressed to where it is released, which is then copied to the clipboard; the middle button types
+// the clipboard into the TTY, like pasting in a terminal.
+
+const MOUSE_IRQ: u8 = 12;
+
+// Mouse commands
+const SET_DEFAULTS: u8 = 0xf6;
+const ENABLE_DATA_REPORTING: u8 = 0xf4;
+const DISABLE_DATA_REPORTING: u8 = 0xf5;
+const SET_SAMPLE_RATE: u8 = 0xf3;
+const IDENTIFY: u8 = 0xf2;
+
+// Setting these sample rates one after another unlocks the scroll wheel (IntelliMouse), and then
+// the two extra buttons; the mouse then identifies as ID 3 or ID 4
+const WHEEL_SEQUENCE: [u8; 3] = [200, 100, 80];
+const FIVE_BUTTON_SEQUENCE: [u8; 3] = [200, 200, 80];
+const WHEEL_MOUSE_ID: u8 = 3;
+const FIVE_BUTTON_MOUSE_ID: u8 = 4;
+const SAMPLE_RATE: u8 = 100;
+
+// Bits of the first byte of a packet
+const BUTTON_LEFT: u8 = 1 << 0;
+const BUTTON_RIGHT: u8 = 1 << 1;
+const BUTTON_MIDDLE: u8 = 1 << 2;
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+// Events arriving while the queue is full are dropped
+const EVENT_QUEUE_SIZE: usize = 64;
+
+// How far the mouse moves (in its own counts) for the pointer to move by one cell
+const COUNTS_PER_COLUMN: i32 = 8;
+const COUNTS_PER_ROW: i32 = 16;
+
+static EVENTS: ArrayQueue<MouseEvent, EVENT_QUEUE_SIZE> = ArrayQueue::new();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+// The packet being received. Only the IRQ handler touches it, the atomics just make it a static
+static PACKET: [AtomicU8; 4] = [const { AtomicU8::new(0) }; 4];
+static PACKET_RECEIVED: AtomicUsize = AtomicUsize::new(0);
+static PACKET_SIZE: AtomicUsize = AtomicUsize::new(3);
+static MOUSE_ID: AtomicU8 = AtomicU8::new(0);
+
+// The text last selected with the mouse
+static CLIPBOARD: spin::Mutex<String> = spin::Mutex::new(String::new());
+
+#[derive(Debug)]
+pub enum MouseError {
+    /// `ps2::init` found no mouse on the second port
+    NotFound,
+    Ps2(Ps2Error),
+    Irq(IrqError),
+}
+
+/// The buttons held down, as a bit set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseButtons(u8);
+
+impl MouseButtons {
+    pub const LEFT: MouseButtons = MouseButtons(1 << 0);
+    pub const RIGHT: MouseButtons = MouseButtons(1 << 1);
+    pub const MIDDLE: MouseButtons = MouseButtons(1 << 2);
+    pub const FOURTH: MouseButtons = MouseButtons(1 << 3);
+    pub const FIFTH: MouseButtons = MouseButtons(1 << 4);
+
+    pub fn contains(self, buttons: MouseButtons) -> bool {
+        self.0 & buttons.0 == buttons.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+/// What one packet from the mouse reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    /// Movement to the right since the last packet
+    pub dx: i16,
+    /// Movement up since the last packet
+    pub dy: i16,
+    /// Scroll wheel movement, positive is towards the user
+    pub wheel: i8,
+    pub buttons: MouseButtons,
+}
+
+/// Enables the scroll wheel and the extra buttons if the mouse has them, turns on data reporting
+/// and registers the IRQ handler. Needs the heap and `ps2::init`
+pub fn init() -> Result<IrqHandlerId, MouseError> {
+    match ps2::device(Ps2Port::Second) {
+        Some(DeviceType::Mouse | DeviceType::WheelMouse | DeviceType::FiveButtonMouse) => {}
+        _ => return Err(MouseError::NotFound),
+    }
+    ps2::device_command(Ps2Port::Second, &[SET_DEFAULTS]).map_err(MouseError::Ps2)?;
+    let mut id = 0;
+    for sequence in [WHEEL_SEQUENCE, FIVE_BUTTON_SEQUENCE].iter() {
+        for &rate in sequence.iter() {
+            ps2::device_command(Ps2Port::Second, &[SET_SAMPLE_RATE, rate])
+                .map_err(MouseError::Ps2)?;
+        }
+        let mut reply = [0];
+        ps2::device_request(Ps2Port::Second, &[IDENTIFY], &mut reply).map_err(MouseError::Ps2)?;
+        id = reply[0];
+        if id != WHEEL_MOUSE_ID {
+            break;
+        }
+    }
+    MOUSE_ID.store(id, Ordering::Relaxed);
+    let has_wheel = id == WHEEL_MOUSE_ID || id == FIVE_BUTTON_MOUSE_ID;
+    PACKET_SIZE.store(if has_wheel { 4 } else { 3 }, Ordering::Relaxed);
+    PACKET_RECEIVED.store(0, Ordering::Relaxed);
+
+    // Stale bytes go before reporting starts: once the handler is registered, anything read here
+    // could be the start of a packet it is waiting for
+    ps2::flush_output();
+    ps2::device_command(Ps2Port::Second, &[SET_SAMPLE_RATE, SAMPLE_RATE, ENABLE_DATA_REPORTING])
+        .map_err(MouseError::Ps2)?;
+    interrupts::register_irq(MOUSE_IRQ, interrupt_handler).map_err(MouseError::Irq)
+}
+
+// The IRQ handler registered by `MouseDriver`
+static HANDLER: spin::Mutex<Option<IrqHandlerId>> = spin::Mutex::new(None);
+
+/// The driver of the mouse `ps2::Ps2Driver` finds
+pub struct MouseDriver;
+
+impl Driver for MouseDriver {
+    fn name(&self) -> &'static str {
+        "mouse"
+    }
+
+    fn matches(&self) -> &'static [Match] {
+        &[Match::Isa("mouse")]
+    }
+
+    fn attach(&self, _device: &Device) -> Result<(), DriverError> {
+        let handler = init().map_err(|error| match error {
+            MouseError::Irq(error) => DriverError::Irq(error),
+            MouseError::NotFound => DriverError::NotSupported,
+            error => DriverError::device(error),
+        })?;
+        *HANDLER.lock() = Some(handler);
+        Ok(())
+    }
+
+    fn detach(&self, _device: &Device) -> Result<(), DriverError> {
+        if let Some(handler) = HANDLER.lock().take() {
+            interrupts::unregister_irq(handler);
+        }
+        // Stop the mouse from sending packets nobody reads
+        ps2::device_command(Ps2Port::Second, &[DISABLE_DATA_REPORTING])
+            .map_err(DriverError::device)
+    }
+}
+
+/// Returns whether the mouse reports a scroll wheel
+pub fn has_wheel() -> bool {
+    PACKET_SIZE.load(Ordering::Relaxed) == 4
+}
+
+fn interrupt_handler() {
+    let byte = ps2::read_data();
+    let received = PACKET_RECEIVED.load(Ordering::Relaxed);
+    // Bit 3 is always set in the first byte; if it isn't, a byte got lost and we wait for the
+    // next packet to get back in step
+    if received == 0 && byte & ALWAYS_ONE == 0 {
+        return;
+    }
+    PACKET[received].store(byte, Ordering::Relaxed);
+    let size = PACKET_SIZE.load(Ordering::Relaxed);
+    if received + 1 < size {
+        PACKET_RECEIVED.store(received + 1, Ordering::Relaxed);
+        return;
+    }
+    PACKET_RECEIVED.store(0, Ordering::Relaxed);
+
+    let mut packet = [0; 4];
+    for (byte, received) in packet.iter_mut().zip(PACKET.iter()) {
+        *byte = received.load(Ordering::Relaxed);
+    }
+    if let Some(event) = decode_packet(&packet[..size], MOUSE_ID.load(Ordering::Relaxed)) {
+        let _ = EVENTS.push(event);
+        WAKER.wake();
+    }
+}
+
+/// Turns a packet into an event. Returns `None` for packets with overflowed movement, which are
+/// garbage
+fn decode_packet(packet: &[u8], mouse_id: u8) -> Option<MouseEvent> {
+    let flags = packet[0];
+    if flags & (X_OVERFLOW | Y_OVERFLOW) != 0 {
+        return None;
+    }
+    let movement = |value: u8, negative: bool| i16::from(value) - if negative { 0x100 } else { 0 };
+    let mut buttons = flags & (BUTTON_LEFT | BUTTON_RIGHT | BUTTON_MIDDLE);
+    let mut wheel = 0;
+    if let Some(&extra) = packet.get(3) {
+        // Sign extend the 4 bit value
+        wheel = ((extra << 4) as i8) >> 4;
+        if mouse_id == FIVE_BUTTON_MOUSE_ID {
+            buttons |= (extra >> 1) & (MouseButtons::FOURTH.0 | MouseButtons::FIFTH.0);
+        }
+    }
+    Some(MouseEvent {
+        dx: movement(packet[1], flags & X_SIGN != 0),
+        dy: movement(packet[2], flags & Y_SIGN != 0),
+        wheel,
+        buttons: MouseButtons(buttons),
+    })
+}
+
+/// Returns the next mouse event if there is one
+pub fn try_next_event() -> Option<MouseEvent> {
+    EVENTS.pop()
+}
+
+/// Waits for the next mouse event
+///
+/// Only one task may wait for mouse events at a time.
+pub async fn next_event() -> MouseEvent {
+    poll_fn(|context| {
+        if let Some(event) = EVENTS.pop() {
+            return Poll::Ready(event);
+        }
+        // Check again after registering, the event may have come in between
+        WAKER.register(context.waker());
+        match EVENTS.pop() {
+            Some(event) => Poll::Ready(event),
+            None => Poll::Pending,
+        }
+    }).await
+}
+
+/// Returns the text last selected with the mouse
+pub fn clipboard() -> String {
+    CLIPBOARD.lock().clone()
+}
+
+/// Moves the pointer over the console that is shown and selects and pastes text with it, forever
+pub async fn run() {
+    // The pointer position in mouse counts, starting in the middle of the screen
+    let width = vga_buffer::BUFFER_WIDTH as i32 * COUNTS_PER_COLUMN;
+    let height = vga_buffer::BUFFER_HEIGHT as i32 * COUNTS_PER_ROW;
+    let (mut x, mut y) = (width / 2, height / 2);
+    let mut buttons = MouseButtons::default();
+    // The console, selection generation and cell the left button was pressed on while it is
+    // held, and whether it moved since
+    let mut anchor = None;
+    let mut dragged = false;
+
+    loop {
+        let event = next_event().await;
+        x = (x + i32::from(event.dx)).clamp(0, width - 1);
+        y = (y - i32::from(event.dy)).clamp(0, height - 1);
+        let cell = ((y / COUNTS_PER_ROW) as usize, (x / COUNTS_PER_COLUMN) as usize);
+        let pressed = |button| event.buttons.contains(button) && !buttons.contains(button);
+        let released = |button| buttons.contains(button) && !event.buttons.contains(button);
+
+        let console = vga_buffer::active_console();
+        let mut writer = vga_buffer::CONSOLES[console].lock();
+        writer.set_pointer(Some(cell));
+        // Output, scrolling or switching consoles took the selection away under the drag
+        if anchor.is_some_and(|(anchor_console, generation, _)| {
+            anchor_console != console || generation != writer.selection_generation()
+        }) {
+            anchor = None;
+        }
+        if pressed(MouseButtons::LEFT) {
+            writer.set_selection(None);
+            anchor = Some((console, writer.selection_generation(), cell));
+            dragged = false;
+        } else if let Some((_, _, start)) = anchor {
+            // A click without moving selects nothing
+            dragged |= cell != start;
+            if dragged {
+                writer.set_selection(Some((start, cell)));
+            }
+            if released(MouseButtons::LEFT) {
+                anchor = None;
+                if dragged {
+                    *CLIPBOARD.lock() = writer.selected_text();
+                }
+            }
+        }
+        drop(writer);
+        // The TTY echoes to the console, so it must not be locked any more
+        if pressed(MouseButtons::MIDDLE) {
+            tty::tty(console).input_str(&clipboard());
+        }
+        buttons = event.buttons;
+    }
+}
+
+#[test_case]
+fn test_decode_packet() {
+    // Left button, moved 5 right and 3 down
+    let event = decode_packet(&[ALWAYS_ONE | BUTTON_LEFT | Y_SIGN, 5, 0xfd], 0).unwrap();
+    assert_eq!((event.dx, event.dy, event.wheel), (5, -3, 0));
+    assert!(event.buttons.contains(MouseButtons::LEFT));
+    assert!(!event.buttons.contains(MouseButtons::RIGHT));
+    // Scrolled one step up (-1), with the fifth button held
+    let event = decode_packet(&[ALWAYS_ONE, 0, 0, 0x2f], FIVE_BUTTON_MOUSE_ID).unwrap();
+    assert_eq!(event.wheel, -1);
+    assert!(event.buttons.contains(MouseButtons::FIFTH));
+    assert_eq!(decode_packet(&[ALWAYS_ONE | X_OVERFLOW, 0xff, 0], 0), None);
+}
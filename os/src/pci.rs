@@ -0,0 +1,392 @@
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Once;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, Size4KiB};
+use crate::acpi;
+
+mod capability;
+mod config;
+
+pub use capability::{Capability, Msi, MsiX, CAPABILITY_MSI, CAPABILITY_MSI_X,
+    CAPABILITY_PCI_EXPRESS, CAPABILITY_POWER_MANAGEMENT, CAPABILITY_VENDOR_SPECIFIC};
+pub use config::{ConfigAccess, EcamRegion};
+
+// This file finds the devices on the PCI buses. Every PCI function has a configuration space
+// (256 bytes, 4 KiB with PCI Express) that starts with a standard header:
+//
+//  0x00  vendor id | device id       0x08  revision | prog if | subclass | class
+//  0x04  command | status            0x0c  ... | header type | ...
+//  0x10  BARs (6 for a device, 2 for a bridge)
+//  0x34  capabilities pointer        0x3c  interrupt line | interrupt pin | ...
+//
+// `init` scans every bus for functions, through ECAM if the ACPI MCFG table describes it and
+// through the 0xcf8/0xcfc ports otherwise, and decodes their headers into `PciDevice`s.
+
+// Offsets in the configuration space header
+const VENDOR_ID: u16 = 0x00;
+const COMMAND: u16 = 0x04;
+const REVISION: u16 = 0x08;
+const HEADER_TYPE: u16 = 0x0e;
+const BAR0: u16 = 0x10;
+const INTERRUPT_LINE: u16 = 0x3c;
+
+// Bits of the command register
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+// The header type tells how many BARs there are, bit 7 whether the device has functions 1-7
+const HEADER_TYPE_MASK: u8 = 0x7f;
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+pub const HEADER_TYPE_DEVICE: u8 = 0x00;
+pub const HEADER_TYPE_PCI_BRIDGE: u8 = 0x01;
+
+// Read from the vendor id of a function that isn't there
+const NO_VENDOR: u16 = 0xffff;
+
+// Class 06, subclass 00 in the upper half of the class register
+const HOST_BRIDGE_CLASS: u32 = 0x0600;
+
+const DEVICES_PER_BUS: u8 = 32;
+const FUNCTIONS_PER_DEVICE: u8 = 8;
+
+static ACCESS: Once<ConfigAccess> = Once::new();
+static DEVICES: Once<Vec<PciDevice>> = Once::new();
+
+#[derive(Debug)]
+pub enum PciError {
+    /// Neither ECAM nor configuration mechanism #1 is available
+    NoConfigAccess,
+    /// The ECAM region could not be mapped
+    Mapping(MapToError<Size4KiB>),
+}
+
+/// The location of a PCI function
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> PciAddress {
+        PciAddress { segment, bus, device, function }
+    }
+}
+
+impl fmt::Display for PciAddress {
+    /// Formats the address the usual way, e.g. "0000:00:1f.2"
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}:{:02x}:{:02x}.{}", self.segment, self.bus, self.device, self.function)
+    }
+}
+
+/// A Base Address Register: where a function's registers or memory are mapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        /// Reads have no side effects, so the region may be mapped write-combining
+        prefetchable: bool,
+        /// The BAR takes two slots, and the address may be above 4 GiB
+        is_64bit: bool,
+    },
+    Io {
+        port: u32,
+        size: u32,
+    },
+}
+
+impl Bar {
+    /// Decodes a BAR from its value and what reading it back after writing all ones gave. For
+    /// a 64 bit BAR, `high` holds the same for the upper half
+    fn decode(value: u32, mask: u32, high: Option<(u32, u32)>) -> Option<Bar> {
+        if value & 1 != 0 {
+            let mut mask = mask & !0x3;
+            // The upper 16 bits of an I/O BAR may be hardwired to 0
+            if mask & 0xffff_0000 == 0 {
+                mask |= 0xffff_0000;
+            }
+            return Some(Bar::Io { port: value & !0x3, size: (!mask).wrapping_add(1) });
+        }
+        let (high_value, high_mask) = high.unwrap_or((0, u32::MAX));
+        let mask = u64::from(high_mask) << 32 | u64::from(mask & !0xf);
+        // Nothing implemented behind the BAR: a 32 bit BAR's upper half counts as all ones
+        if mask == 0 || (mask & 0xffff_ffff == 0 && high.is_none()) {
+            return None;
+        }
+        Some(Bar::Memory {
+            address: u64::from(high_value) << 32 | u64::from(value & !0xf),
+            size: (!mask).wrapping_add(1),
+            prefetchable: value & (1 << 3) != 0,
+            is_64bit: high.is_some(),
+        })
+    }
+
+    /// Returns the size of the region
+    pub fn size(&self) -> u64 {
+        match *self {
+            Bar::Memory { size, .. } => size,
+            Bar::Io { size, .. } => u64::from(size),
+        }
+    }
+}
+
+/// A PCI function found by `init`
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    /// `HEADER_TYPE_DEVICE`, `HEADER_TYPE_PCI_BRIDGE`, ...
+    pub header_type: u8,
+    /// The BARs by index. The second slot of a 64 bit BAR is None
+    pub bars: [Option<Bar>; 6],
+    /// The legacy IRQ the firmware routed the interrupt pin to, if any
+    pub interrupt_line: Option<u8>,
+    /// The interrupt pin the function uses, 1-4 for INTA#-INTD#, 0 for none
+    pub interrupt_pin: u8,
+    pub capabilities: Vec<Capability>,
+    pub msi: Option<Msi>,
+    pub msix: Option<MsiX>,
+}
+
+impl PciDevice {
+    /// Reads the configuration space header of the function at `address`
+    fn read(address: PciAddress) -> PciDevice {
+        let id = read_config(address, VENDOR_ID);
+        let class = read_config(address, REVISION);
+        let header_type = read_u8(address, HEADER_TYPE) & HEADER_TYPE_MASK;
+        let bar_count = match header_type {
+            HEADER_TYPE_DEVICE => 6,
+            HEADER_TYPE_PCI_BRIDGE => 2,
+            _ => 0,
+        };
+        let interrupt = read_config(address, INTERRUPT_LINE);
+        let capabilities = capability::read_capabilities(address);
+        let find = |id| capabilities.iter().find(|capability| capability.id == id);
+        let msi = find(CAPABILITY_MSI).map(|capability| Msi::read(address, capability.offset));
+        let msix = find(CAPABILITY_MSI_X).map(|capability| MsiX::read(address, capability.offset));
+        PciDevice {
+            address,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type,
+            bars: read_bars(address, bar_count, class >> 16 == HOST_BRIDGE_CLASS),
+            // 0xff means "not connected" on PCs
+            interrupt_line: Some(interrupt as u8).filter(|&line| line != 0xff),
+            interrupt_pin: (interrupt >> 8) as u8,
+            capabilities,
+            msi,
+            msix,
+        }
+    }
+
+    /// Returns a short description of the class of the function
+    pub fn class_name(&self) -> &'static str {
+        class_name(self.class, self.subclass)
+    }
+
+    pub fn command(&self) -> u16 {
+        read_u16(self.address, COMMAND)
+    }
+
+    /// Writes the command register, e.g. to enable memory space access and bus mastering
+    pub fn set_command(&self, command: u16) {
+        // The status register in the upper half clears the bits written as 1, so write zeros
+        write_config(self.address, COMMAND, u32::from(command));
+    }
+}
+
+/// Reads the BARs of a function, probing their sizes
+///
+/// `always_on` is for functions whose decoding must not be turned off while probing, like host
+/// bridges, through which the CPU reaches memory.
+fn read_bars(address: PciAddress, count: u16, always_on: bool) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    // The function must not decode addresses while its BARs hold all ones
+    let command = read_u16(address, COMMAND);
+    if !always_on {
+        write_config(address, COMMAND,
+                     u32::from(command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE)));
+    }
+
+    let probe = |offset: u16| {
+        let value = read_config(address, offset);
+        write_config(address, offset, u32::MAX);
+        let mask = read_config(address, offset);
+        write_config(address, offset, value);
+        (value, mask)
+    };
+    let mut index = 0;
+    while index < count {
+        let offset = BAR0 + index * 4;
+        let (value, mask) = probe(offset);
+        // Memory BAR with type 2: 64 bits wide, the upper half is in the next slot
+        let is_64bit = value & 0x1 == 0 && (value >> 1) & 0x3 == 0x2 && index + 1 < count;
+        let high = if is_64bit { Some(probe(offset + 4)) } else { None };
+        bars[usize::from(index)] = Bar::decode(value, mask, high);
+        index += if is_64bit { 2 } else { 1 };
+    }
+
+    if !always_on {
+        write_config(address, COMMAND, u32::from(command));
+    }
+    bars
+}
+
+/// Returns a short description of a class and subclass
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x00, _) => "unclassified",
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVMe controller",
+        (0x01, _) => "storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "network controller",
+        (0x03, 0x00) => "VGA controller",
+        (0x03, _) => "display controller",
+        (0x04, _) => "multimedia controller",
+        (0x05, _) => "memory controller",
+        (0x06, 0x00) => "host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "bridge",
+        (0x07, _) => "communication controller",
+        (0x08, _) => "system peripheral",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, 0x05) => "SMBus controller",
+        (0x0c, _) => "serial bus controller",
+        _ => "other",
+    }
+}
+
+/// Chooses how to access configuration space and scans all buses for PCI functions. Needs the
+/// heap, and `acpi::init` to use ECAM
+///
+/// Only the first call does any work, later calls return the same devices.
+pub fn init(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<&'static [PciDevice], PciError> {
+    if let Some(devices) = DEVICES.r#try() {
+        return Ok(devices);
+    }
+    let mcfg = acpi::tables().and_then(|tables| tables.mcfg.as_ref())
+        .filter(|mcfg| !mcfg.entries.is_empty());
+    let access = match mcfg {
+        Some(mcfg) => ConfigAccess::ecam(mcfg, mapper, frame_allocator)
+            .map_err(PciError::Mapping)?,
+        None if config::port_io_available() => ConfigAccess::PortIo,
+        None => return Err(PciError::NoConfigAccess),
+    };
+    let access = ACCESS.call_once(|| access);
+    Ok(DEVICES.call_once(|| scan(access)))
+}
+
+/// Returns how configuration space is accessed, once `init` has chosen
+pub fn config_access() -> Option<&'static ConfigAccess> {
+    ACCESS.r#try()
+}
+
+/// Returns the PCI functions `init` found, ordered by address
+pub fn devices() -> &'static [PciDevice] {
+    DEVICES.r#try().map_or(&[], Vec::as_slice)
+}
+
+/// Returns the first function with the given vendor and device id
+pub fn find_device(vendor_id: u16, device_id: u16) -> Option<&'static PciDevice> {
+    devices().iter().find(|device| device.vendor_id == vendor_id && device.device_id == device_id)
+}
+
+/// Returns the functions of the given class and subclass
+pub fn find_class(class: u8, subclass: u8) -> impl Iterator<Item = &'static PciDevice> {
+    devices().iter().filter(move |device| device.class == class && device.subclass == subclass)
+}
+
+/// Checks every device number of every bus for functions
+fn scan(access: &ConfigAccess) -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for (segment, buses) in access.buses() {
+        for bus in buses {
+            for device in 0..DEVICES_PER_BUS {
+                let address = PciAddress::new(segment, bus, device, 0);
+                if read_u16(address, VENDOR_ID) == NO_VENDOR {
+                    continue;
+                }
+                let functions = if read_u8(address, HEADER_TYPE) & HEADER_MULTIFUNCTION != 0 {
+                    FUNCTIONS_PER_DEVICE
+                } else {
+                    1
+                };
+                for function in 0..functions {
+                    let address = PciAddress::new(segment, bus, device, function);
+                    if read_u16(address, VENDOR_ID) != NO_VENDOR {
+                        devices.push(PciDevice::read(address));
+                    }
+                }
+            }
+        }
+    }
+    devices
+}
+
+/// Reads the dword at `offset` (rounded down to a multiple of 4) of a function's configuration
+/// space. Before `init`, only segment 0 can be reached, through the 0xcf8/0xcfc ports
+pub fn read_config(address: PciAddress, offset: u16) -> u32 {
+    config_access().unwrap_or(&ConfigAccess::PortIo).read(address, offset)
+}
+
+/// Writes the dword at `offset` (rounded down to a multiple of 4) of a function's configuration
+/// space
+pub fn write_config(address: PciAddress, offset: u16, value: u32) {
+    config_access().unwrap_or(&ConfigAccess::PortIo).write(address, offset, value)
+}
+
+/// Reads the word at `offset` (rounded down to a multiple of 2)
+pub fn read_u16(address: PciAddress, offset: u16) -> u16 {
+    (read_config(address, offset) >> ((offset & 0x2) * 8)) as u16
+}
+
+pub fn read_u8(address: PciAddress, offset: u16) -> u8 {
+    (read_config(address, offset) >> ((offset & 0x3) * 8)) as u8
+}
+
+#[test_case]
+fn test_address_display() {
+    use alloc::format;
+    assert_eq!(format!("{}", PciAddress::new(0, 0, 0x1f, 2)), "0000:00:1f.2");
+    assert_eq!(format!("{}", PciAddress::new(1, 0xa0, 3, 0)), "0001:a0:03.0");
+}
+
+#[test_case]
+fn test_bar_decode() {
+    // 32 bit, prefetchable, 16 MiB memory BAR
+    assert_eq!(Bar::decode(0xfd00_0008, 0xff00_0008, None), Some(Bar::Memory {
+        address: 0xfd00_0000, size: 0x100_0000, prefetchable: true, is_64bit: false,
+    }));
+    // 64 bit, 16 KiB memory BAR above 4 GiB
+    assert_eq!(Bar::decode(0x0000_000c, 0xffff_c00c, Some((0x1, 0xffff_ffff))),
+               Some(Bar::Memory {
+                   address: 0x1_0000_0000, size: 0x4000, prefetchable: true, is_64bit: true,
+               }));
+    // 32 byte I/O BAR whose upper half is hardwired to 0
+    assert_eq!(Bar::decode(0xc041, 0xffe1, None), Some(Bar::Io { port: 0xc040, size: 0x20 }));
+    // Not implemented
+    assert_eq!(Bar::decode(0, 0, None), None);
+    assert_eq!(Bar::decode(0x4, 0x4, Some((0, 0))), None);
+}
@@ -0,0 +1,131 @@
+use alloc::vec::Vec;
+use super::{read_config, read_u16, read_u8, PciAddress};
+
+// Capabilities are optional features of a function, described by a linked list of structures in
+// its configuration space. The list starts at CAPABILITIES_POINTER if bit 4 of the status
+// register is set; each entry starts with its id and the offset of the next one (0 ends it).
+//
+// The two capabilities decoded further are the ways a function can signal interrupts by
+// writing to memory instead of using an interrupt pin:
+//  - MSI: one message address/data pair, for up to 32 vectors
+//  - MSI-X: a table of up to 2048 address/data pairs in one of the function's BARs
+
+const STATUS: u16 = 0x06;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+const CAPABILITIES_POINTER: u16 = 0x34;
+
+// Longest list walked, so a corrupt list that loops doesn't hang the scan
+const MAX_CAPABILITIES: usize = 48;
+
+pub const CAPABILITY_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_VENDOR_SPECIFIC: u8 = 0x09;
+pub const CAPABILITY_PCI_EXPRESS: u8 = 0x10;
+pub const CAPABILITY_MSI_X: u8 = 0x11;
+
+/// An entry of the capabilities list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Offset of the capability structure in configuration space
+    pub offset: u8,
+}
+
+impl Capability {
+    /// Returns a short name for the capability
+    pub fn name(&self) -> &'static str {
+        match self.id {
+            CAPABILITY_POWER_MANAGEMENT => "power management",
+            0x03 => "VPD",
+            CAPABILITY_MSI => "MSI",
+            0x07 => "PCI-X",
+            CAPABILITY_VENDOR_SPECIFIC => "vendor specific",
+            0x0d => "bridge subsystem id",
+            CAPABILITY_PCI_EXPRESS => "PCI Express",
+            CAPABILITY_MSI_X => "MSI-X",
+            0x12 => "SATA",
+            0x13 => "advanced features",
+            _ => "unknown",
+        }
+    }
+}
+
+/// Reads the capabilities list of a function
+pub(super) fn read_capabilities(address: PciAddress) -> Vec<Capability> {
+    let mut capabilities = Vec::new();
+    if read_u16(address, STATUS) & STATUS_CAPABILITIES == 0 {
+        return capabilities;
+    }
+    // The bottom two bits of the pointers are reserved
+    let mut offset = read_u8(address, CAPABILITIES_POINTER) & 0xfc;
+    while offset != 0 && capabilities.len() < MAX_CAPABILITIES {
+        let header = read_u16(address, u16::from(offset));
+        capabilities.push(Capability { id: header as u8, offset });
+        offset = (header >> 8) as u8 & 0xfc;
+    }
+    capabilities
+}
+
+/// The MSI capability of a function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msi {
+    /// Offset of the capability in configuration space
+    pub offset: u8,
+    /// Whether the message address can be 64 bits wide
+    pub is_64bit: bool,
+    /// Number of vectors the function can use, a power of two up to 32
+    pub vectors: u8,
+    pub per_vector_masking: bool,
+    pub enabled: bool,
+}
+
+impl Msi {
+    pub(super) fn read(address: PciAddress, offset: u8) -> Msi {
+        let control = read_u16(address, u16::from(offset) + 2);
+        Msi {
+            offset,
+            is_64bit: control & (1 << 7) != 0,
+            // "Multiple message capable" is log2 of the number of vectors
+            vectors: 1 << ((control >> 1) & 0x7).min(5),
+            per_vector_masking: control & (1 << 8) != 0,
+            enabled: control & 1 != 0,
+        }
+    }
+}
+
+/// The MSI-X capability of a function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiX {
+    /// Offset of the capability in configuration space
+    pub offset: u8,
+    /// Number of entries in the MSI-X table
+    pub table_size: u16,
+    /// Index of the BAR the MSI-X table is in, and its offset into the BAR
+    pub table_bar: u8,
+    pub table_offset: u32,
+    /// Index of the BAR the pending bit array is in, and its offset into the BAR
+    pub pba_bar: u8,
+    pub pba_offset: u32,
+    pub enabled: bool,
+    /// All vectors are masked, whatever their own mask bits say
+    pub function_masked: bool,
+}
+
+impl MsiX {
+    pub(super) fn read(address: PciAddress, offset: u8) -> MsiX {
+        let control = read_u16(address, u16::from(offset) + 2);
+        let table = read_config(address, u16::from(offset) + 4);
+        let pba = read_config(address, u16::from(offset) + 8);
+        MsiX {
+            offset,
+            // Encoded as N - 1
+            table_size: (control & 0x7ff) + 1,
+            table_bar: (table & 0x7) as u8,
+            table_offset: table & !0x7,
+            pba_bar: (pba & 0x7) as u8,
+            pba_offset: pba & !0x7,
+            enabled: control & (1 << 15) != 0,
+            function_masked: control & (1 << 14) != 0,
+        }
+    }
+}
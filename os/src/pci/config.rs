@@ -0,0 +1,166 @@
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::acpi::Mcfg;
+use crate::memory;
+use crate::sync::IrqSpinLock;
+use super::PciAddress;
+
+// The two ways to get at the configuration space of a PCI function:
+//
+//  - Configuration mechanism #1: write the address of a dword to 0xcf8, then read or write it
+//    through 0xcfc. Reaches the first 256 bytes of each function of segment 0 only, and the two
+//    steps have to happen under a lock.
+//  - ECAM (PCI Express): each function's whole 4 KiB configuration space is mapped into memory.
+//    The MCFG ACPI table tells where, for each segment group and range of buses.
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+/// Size of the configuration space of a function through ECAM
+pub const ECAM_FUNCTION_SIZE: u64 = 4096;
+/// Size of the configuration space through port I/O
+pub const LEGACY_CONFIG_SIZE: u16 = 256;
+
+static LEGACY_LOCK: IrqSpinLock<()> = IrqSpinLock::new(());
+
+/// The part of the configuration space of one segment group mapped through ECAM
+#[derive(Debug, Clone)]
+pub struct EcamRegion {
+    pub segment: u16,
+    pub buses: RangeInclusive<u8>,
+    /// Virtual address of the configuration space of bus 0 (even if it's not in `buses`)
+    base: VirtAddr,
+}
+
+/// How configuration space is accessed
+#[derive(Debug, Clone)]
+pub enum ConfigAccess {
+    PortIo,
+    Ecam(Vec<EcamRegion>),
+}
+
+/// Returns whether configuration mechanism #1 is there: the address register has to keep the
+/// enable bit written to it
+pub fn port_io_available() -> bool {
+    let _guard = LEGACY_LOCK.lock();
+    let mut address_port: Port<u32> = Port::new(CONFIG_ADDRESS);
+    unsafe {
+        let previous = address_port.read();
+        address_port.write(CONFIG_ENABLE);
+        let available = address_port.read() == CONFIG_ENABLE;
+        address_port.write(previous);
+        available
+    }
+}
+
+impl ConfigAccess {
+    /// Maps the ECAM regions listed in the MCFG
+    pub fn ecam(
+        mcfg: &Mcfg,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<ConfigAccess, MapToError<Size4KiB>> {
+        let mut regions = Vec::new();
+        for entry in mcfg.entries.iter() {
+            // Only the buses in the entry are mapped, the rest of the window may be anything
+            let Some(span) = entry.end_bus.checked_sub(entry.start_bus) else {
+                continue;
+            };
+            let start = entry.base_address + (u64::from(entry.start_bus) << 20);
+            let size = (u64::from(span) + 1) << 20;
+            memory::map_physical_region(PhysAddr::new(start), size, mapper, frame_allocator)?;
+            regions.push(EcamRegion {
+                segment: entry.segment_group,
+                buses: entry.start_bus..=entry.end_bus,
+                base: memory::phys_to_virt(PhysAddr::new(entry.base_address)),
+            });
+        }
+        Ok(ConfigAccess::Ecam(regions))
+    }
+
+    /// Returns the segment groups and their buses that can be accessed
+    pub fn buses(&self) -> Vec<(u16, RangeInclusive<u8>)> {
+        match self {
+            ConfigAccess::PortIo => alloc::vec![(0, 0..=255)],
+            ConfigAccess::Ecam(regions) => {
+                regions.iter().map(|region| (region.segment, region.buses.clone())).collect()
+            }
+        }
+    }
+
+    /// Returns the address of a dword of configuration space through ECAM, if it's mapped
+    fn ecam_address(&self, address: PciAddress, offset: u16) -> Option<*mut u32> {
+        let ConfigAccess::Ecam(regions) = self else {
+            return None;
+        };
+        if u64::from(offset) >= ECAM_FUNCTION_SIZE {
+            return None;
+        }
+        let region = regions.iter().find(|region| {
+            region.segment == address.segment && region.buses.contains(&address.bus)
+        })?;
+        let function = u64::from(address.bus) << 20 | u64::from(address.device) << 15
+            | u64::from(address.function) << 12;
+        Some((region.base + function + u64::from(offset & !3)).as_mut_ptr())
+    }
+
+    /// Reads the dword at `offset` (rounded down to a multiple of 4). Returns all ones, like
+    /// the hardware for a function that isn't there, if it can't be reached
+    pub fn read(&self, address: PciAddress, offset: u16) -> u32 {
+        if let Some(pointer) = self.ecam_address(address, offset) {
+            return unsafe { pointer.read_volatile() };
+        }
+        match legacy_address(address, offset) {
+            Some(config_address) => {
+                let _guard = LEGACY_LOCK.lock();
+                let mut address_port: Port<u32> = Port::new(CONFIG_ADDRESS);
+                let mut data_port: Port<u32> = Port::new(CONFIG_DATA);
+                unsafe {
+                    address_port.write(config_address);
+                    data_port.read()
+                }
+            }
+            None => u32::MAX,
+        }
+    }
+
+    /// Writes the dword at `offset` (rounded down to a multiple of 4), if it can be reached
+    pub fn write(&self, address: PciAddress, offset: u16, value: u32) {
+        if let Some(pointer) = self.ecam_address(address, offset) {
+            unsafe { pointer.write_volatile(value) };
+            return;
+        }
+        if let Some(config_address) = legacy_address(address, offset) {
+            let _guard = LEGACY_LOCK.lock();
+            let mut address_port: Port<u32> = Port::new(CONFIG_ADDRESS);
+            let mut data_port: Port<u32> = Port::new(CONFIG_DATA);
+            unsafe {
+                address_port.write(config_address);
+                data_port.write(value);
+            }
+        }
+    }
+}
+
+/// Returns the value for the address register of configuration mechanism #1, if the dword can be
+/// reached through it
+fn legacy_address(address: PciAddress, offset: u16) -> Option<u32> {
+    if address.segment != 0 || offset >= LEGACY_CONFIG_SIZE {
+        return None;
+    }
+    Some(CONFIG_ENABLE | u32::from(address.bus) << 16 | u32::from(address.device) << 11
+        | u32::from(address.function) << 8 | u32::from(offset & 0xfc))
+}
+
+#[test_case]
+fn test_legacy_address() {
+    assert_eq!(legacy_address(PciAddress::new(0, 0, 2, 0), 0x10), Some(0x8000_1010));
+    assert_eq!(legacy_address(PciAddress::new(0, 1, 31, 7), 0x3e), Some(0x8001_ff3c));
+    assert_eq!(legacy_address(PciAddress::new(0, 0, 0, 0), 0x100), None);
+    assert_eq!(legacy_address(PciAddress::new(1, 0, 0, 0), 0), None);
+}
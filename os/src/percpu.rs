@@ -0,0 +1,189 @@
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use x86_64::registers::model_specific::GsBase;
+use x86_64::VirtAddr;
+
+// This file keeps data that every CPU has its own copy of. The GS base register of each CPU points
+// to its `CpuData`, so a CPU finds its own data with a single GS relative load and without taking
+// a lock, e.g. `mov rax, gs:[0]` reads the ID of the CPU that runs the instruction.
+//
+//  CPU 0: GS base ---> CPU_DATA[0] { id: 0, ... }       PerCpu<T> { values: [T (CPU 0),
+//  CPU 1: GS base ---> CPU_DATA[1] { id: 1, ... }                           T (CPU 1), ...] }
+//
+// Statics of type `PerCpu<T>` hold one value per CPU and hand out the one of the calling CPU.
+
+/// Maximum number of CPUs the kernel supports
+pub const MAX_CPUS: usize = 16;
+
+const SCRATCH_STACK_SIZE: usize = 4096 * 4;
+
+// Offset of `CpuData::id`, read through GS
+const ID_OFFSET: usize = 0;
+
+static CPU_DATA: [CpuData; MAX_CPUS] = [const { CpuData::new() }; MAX_CPUS];
+
+/// The data every CPU keeps about itself
+#[repr(C)]
+pub struct CpuData {
+    id: AtomicUsize, // must stay the first field, see ID_OFFSET
+    apic_id: AtomicU32,
+    interrupt_depth: AtomicUsize,
+    current_task: AtomicU64,
+    scratch_stack_top: AtomicU64,
+}
+
+impl CpuData {
+    const fn new() -> CpuData {
+        CpuData {
+            id: AtomicUsize::new(0),
+            apic_id: AtomicU32::new(0),
+            interrupt_depth: AtomicUsize::new(0),
+            current_task: AtomicU64::new(0),
+            scratch_stack_top: AtomicU64::new(0),
+        }
+    }
+
+    /// Index of the CPU, 0 for the boot CPU and counting up in the order the CPUs were started
+    pub fn id(&self) -> usize {
+        self.id.load(Ordering::Relaxed)
+    }
+
+    /// ID of the local APIC of the CPU
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    /// Number of interrupt handlers the CPU is currently running, nested into each other
+    pub fn interrupt_depth(&self) -> usize {
+        self.interrupt_depth.load(Ordering::Relaxed)
+    }
+
+    /// ID of the task the CPU is running, 0 if it runs the kernel's own code
+    pub fn current_task(&self) -> u64 {
+        self.current_task.load(Ordering::Relaxed)
+    }
+
+    pub fn set_current_task(&self, task: u64) {
+        self.current_task.store(task, Ordering::Relaxed);
+    }
+
+    /// Top of a small stack the CPU can switch to when its current stack can't be used
+    pub fn scratch_stack_top(&self) -> VirtAddr {
+        VirtAddr::new(self.scratch_stack_top.load(Ordering::Relaxed))
+    }
+}
+
+/// Sets up the `CpuData` of the calling CPU and points its GS base to it
+///
+/// `id` must be unique and below `MAX_CPUS`. Must be called on every CPU before anything else
+/// in this file is used on that CPU.
+pub fn init(id: usize, scratch_stack_top: VirtAddr) {
+    assert!(id < MAX_CPUS, "CPU index {} is above MAX_CPUS", id);
+    let data = &CPU_DATA[id];
+    data.id.store(id, Ordering::Relaxed);
+    // CPUID reports the initial local APIC ID, which works before the APIC is mapped
+    data.apic_id.store(__cpuid(1).ebx >> 24, Ordering::Relaxed);
+    data.interrupt_depth.store(0, Ordering::Relaxed);
+    data.current_task.store(0, Ordering::Relaxed);
+    data.scratch_stack_top.store(scratch_stack_top.as_u64(), Ordering::Relaxed);
+    GsBase::write(VirtAddr::from_ptr(data));
+}
+
+/// Sets up the `CpuData` of the boot CPU, whose scratch stack is static since there is no heap yet
+pub fn init_boot_cpu() {
+    static mut SCRATCH_STACK: [u8; SCRATCH_STACK_SIZE] = [0; SCRATCH_STACK_SIZE];
+    let stack_start = VirtAddr::from_ptr(&raw const SCRATCH_STACK);
+    init(0, stack_start + SCRATCH_STACK_SIZE);
+}
+
+/// Allocates a scratch stack on the heap and returns its top. For the CPUs started after boot
+pub fn allocate_scratch_stack() -> VirtAddr {
+    use alloc::{boxed::Box, vec};
+    let stack = Box::leak(vec![0u8; SCRATCH_STACK_SIZE].into_boxed_slice());
+    VirtAddr::from_ptr(stack.as_ptr()) + SCRATCH_STACK_SIZE
+}
+
+/// Returns the index of the calling CPU
+pub fn cpu_id() -> usize {
+    let id: usize;
+    unsafe {
+        asm!("mov {}, qword ptr gs:[{}]", out(reg) id, const ID_OFFSET,
+             options(nostack, readonly, preserves_flags));
+    }
+    id
+}
+
+/// Returns the index of the calling CPU, or `None` if `init` was not called on it yet
+pub fn try_cpu_id() -> Option<usize> {
+    if GsBase::read().is_null() {
+        None
+    } else {
+        Some(cpu_id())
+    }
+}
+
+/// Returns the data of the calling CPU
+pub fn current() -> &'static CpuData {
+    &CPU_DATA[cpu_id()]
+}
+
+/// Returns the data of the CPU with the given index
+pub fn cpu(id: usize) -> &'static CpuData {
+    &CPU_DATA[id]
+}
+
+/// Marks the start of an interrupt handler on the calling CPU
+pub fn enter_interrupt() {
+    current().interrupt_depth.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Marks the end of an interrupt handler on the calling CPU
+pub fn leave_interrupt() {
+    current().interrupt_depth.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Returns whether the calling CPU is running an interrupt handler
+pub fn in_interrupt() -> bool {
+    current().interrupt_depth() > 0
+}
+
+/// A static that holds a separate value for every CPU
+///
+/// A CPU only ever gets its own value, so values that are only changed from one CPU can use
+/// cheap interior mutability like `Cell`. Interrupt handlers on the same CPU can still access it
+/// though, so code that is interrupted while changing it must be prepared for that.
+pub struct PerCpu<T> {
+    values: [T; MAX_CPUS],
+}
+
+// Each CPU only accesses its own value, so values are never shared between CPUs
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    /// Creates a `PerCpu` from the initial value of every CPU
+    pub const fn new(values: [T; MAX_CPUS]) -> PerCpu<T> {
+        PerCpu { values }
+    }
+
+    /// Returns the value of the calling CPU
+    pub fn get(&self) -> &T {
+        &self.values[cpu_id()]
+    }
+}
+
+#[test_case]
+fn test_boot_cpu_data() {
+    assert_eq!(cpu_id(), 0);
+    assert_eq!(current().id(), 0);
+    assert!(!in_interrupt());
+    assert!(!current().scratch_stack_top().is_null());
+}
+
+#[test_case]
+fn test_per_cpu_value() {
+    use core::cell::Cell;
+    static COUNTER: PerCpu<Cell<u32>> = PerCpu::new([const { Cell::new(0) }; MAX_CPUS]);
+    COUNTER.get().set(COUNTER.get().get() + 1);
+    assert_eq!(COUNTER.get().get(), 1);
+}
@@ -0,0 +1,200 @@
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+use crate::acpi::{self, AddressSpace, Fadt};
+use crate::{hlt_loop, memory};
+
+// This file turns the machine off or restarts it. Both are tried in several ways, from the proper
+// ACPI mechanism to emulator specific ports and hardware tricks, since not every machine (or
+// emulator) supports all of them.
+
+// The sleep type of the S5 (soft off) state is stored in bits 10-12 of the PM1 control registers,
+// writing SLP_EN then enters that state. The other bits belong to the chipset and are kept
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+const SCI_EN: u16 = 1 << 0; // set in PM1a control while the machine is in ACPI mode
+
+// Ports that power off emulators, with the value to write to them
+const QEMU_SHUTDOWN: (u16, u16) = (0x604, 0x2000);
+const BOCHS_SHUTDOWN: (u16, u16) = (0xb004, 0x2000); // also older QEMU versions
+const VIRTUALBOX_SHUTDOWN: (u16, u16) = (0x4004, 0x3400);
+
+// 8042 PS/2 controller: status register bit 1 is set while the controller is busy with a command,
+// command 0xfe pulses the CPU reset line
+const PS2_STATUS_PORT: u16 = 0x64;
+const PS2_INPUT_BUFFER_FULL: u8 = 1 << 1;
+const PS2_RESET_CPU: u8 = 0xfe;
+const PS2_WAIT_LIMIT: u32 = 1_000_000; // status reads before giving up on a stuck controller
+
+// AML opcodes used in the DSDT around the \_S5 object
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+
+/// Turns the machine off
+///
+/// Uses ACPI to enter the S5 (soft off) sleep state, falling back to the ports QEMU, Bochs and
+/// VirtualBox power off through. Halts forever if none of them works.
+pub fn shutdown() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    if let Some(fadt) = acpi::tables().and_then(|tables| tables.fadt.as_ref()) {
+        if let Some((slp_typ_a, slp_typ_b)) = s5_sleep_types(fadt) {
+            enable_acpi_mode(fadt);
+            unsafe {
+                enter_sleep_state(fadt.pm1a_control_block as u16, slp_typ_a);
+                if fadt.pm1b_control_block != 0 {
+                    enter_sleep_state(fadt.pm1b_control_block as u16, slp_typ_b);
+                }
+            }
+        }
+    }
+
+    for &(port, value) in &[QEMU_SHUTDOWN, BOCHS_SHUTDOWN, VIRTUALBOX_SHUTDOWN] {
+        unsafe { Port::<u16>::new(port).write(value) };
+    }
+    hlt_loop();
+}
+
+/// Restarts the machine
+///
+/// Tries the ACPI reset register, then the reset line of the 8042 PS/2 controller, and finally
+/// a triple fault, which makes the CPU reset itself.
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    if let Some(fadt) = acpi::tables().and_then(|tables| tables.fadt.as_ref()) {
+        if let Some(register) = fadt.reset_register {
+            match register.address_space {
+                AddressSpace::SystemIo => unsafe {
+                    Port::<u8>::new(register.address as u16).write(fadt.reset_value);
+                },
+                AddressSpace::SystemMemory => unsafe {
+                    let virt = memory::phys_to_virt(PhysAddr::new(register.address));
+                    core::ptr::write_volatile(virt.as_mut_ptr::<u8>(), fadt.reset_value);
+                },
+                _ => {}
+            }
+        }
+    }
+
+    // A controller that stays busy can't take the command, go on to the triple fault then
+    let mut ps2_status: Port<u8> = Port::new(PS2_STATUS_PORT);
+    unsafe {
+        if (0..PS2_WAIT_LIMIT).any(|_| ps2_status.read() & PS2_INPUT_BUFFER_FULL == 0) {
+            ps2_status.write(PS2_RESET_CPU);
+        }
+    }
+
+    // With an empty IDT, the breakpoint exception can't be handled, neither can the resulting
+    // double fault. The CPU resets on this triple fault
+    unsafe {
+        use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+        lidt(&DescriptorTablePointer { limit: 0, base: x86_64::VirtAddr::new(0) });
+    }
+    x86_64::instructions::interrupts::int3();
+    hlt_loop();
+}
+
+/// Writes the sleep type and SLP_EN to a PM1 control register, keeping its other bits
+unsafe fn enter_sleep_state(control_block: u16, slp_typ: u16) {
+    let mut control: Port<u16> = Port::new(control_block);
+    let value = control.read();
+    control.write(sleep_control_value(value, slp_typ));
+}
+
+/// Returns the PM1 control register value `value` with the sleep type replaced and SLP_EN set
+fn sleep_control_value(value: u16, slp_typ: u16) -> u16 {
+    value & !(SLP_TYP_MASK | SLP_EN) | (slp_typ << SLP_TYP_SHIFT & SLP_TYP_MASK) | SLP_EN
+}
+
+/// Switches the machine from legacy mode into ACPI mode if the firmware did not do so already
+fn enable_acpi_mode(fadt: &Fadt) {
+    let mut pm1a_control: Port<u16> = Port::new(fadt.pm1a_control_block as u16);
+    unsafe {
+        if pm1a_control.read() & SCI_EN != 0 || fadt.smi_command_port == 0
+            || fadt.acpi_enable == 0 {
+            return;
+        }
+        Port::<u8>::new(fadt.smi_command_port as u16).write(fadt.acpi_enable);
+        // The switch can take a while, but don't wait forever
+        for _ in 0..1_000_000 {
+            if pm1a_control.read() & SCI_EN != 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Returns the SLP_TYPa and SLP_TYPb values of the S5 state from the DSDT
+fn s5_sleep_types(fadt: &Fadt) -> Option<(u16, u16)> {
+    if fadt.pm1a_control_block == 0 {
+        return None;
+    }
+    let dsdt = unsafe { acpi::sdt(fadt.dsdt) }?;
+    parse_s5(&dsdt[acpi::SDT_HEADER_LENGTH..])
+}
+
+/// Finds the `\_S5` package in the AML code and returns its first two elements
+///
+/// We don't have an AML interpreter, so this looks for the byte sequence the ASL compiler emits
+/// for `Name (_S5, Package () { a, b, ... })`, which is what all firmware we know of uses.
+fn parse_s5(aml: &[u8]) -> Option<(u16, u16)> {
+    let position = aml.windows(4).position(|window| window == b"_S5_")?;
+    // The name must be defined by a NameOp, possibly with a root prefix (\_S5_)
+    let defined = match position {
+        0 => false,
+        1 => aml[0] == AML_NAME_OP,
+        _ => aml[position - 1] == AML_NAME_OP
+            || (aml[position - 1] == b'\\' && aml[position - 2] == AML_NAME_OP),
+    };
+    if !defined || *aml.get(position + 4)? != AML_PACKAGE_OP {
+        return None;
+    }
+
+    // Skip the package length, whose first byte tells how many bytes follow in bits 6-7, and the
+    // number of elements
+    let mut offset = position + 5;
+    offset += usize::from(*aml.get(offset)? >> 6) + 1;
+    offset += 1;
+
+    let mut read_integer = || -> Option<u16> {
+        let value = match *aml.get(offset)? {
+            AML_BYTE_PREFIX => {
+                offset += 1;
+                *aml.get(offset)?
+            }
+            AML_ZERO_OP => 0,
+            AML_ONE_OP => 1,
+            _ => return None,
+        };
+        offset += 1;
+        Some(u16::from(value))
+    };
+    let slp_typ_a = read_integer()?;
+    let slp_typ_b = read_integer()?;
+    Some((slp_typ_a, slp_typ_b))
+}
+
+#[test_case]
+fn test_parse_s5() {
+    // Name (\_S5, Package (0x04) { 0x05, Zero, Zero, Zero }), as compiled by iasl
+    let aml = [0x10, 0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0a, 0x05, 0x00, 0x00,
+               0x00];
+    assert_eq!(parse_s5(&aml), Some((5, 0)));
+    // Name (_S5, Package (0x02) { Zero, One })
+    let aml = [0x08, b'_', b'S', b'5', b'_', 0x12, 0x04, 0x02, 0x00, 0x01];
+    assert_eq!(parse_s5(&aml), Some((0, 1)));
+    // A method named _S5_ is not the sleep type package
+    let aml = [0x14, 0x06, b'_', b'S', b'5', b'_', 0x00];
+    assert_eq!(parse_s5(&aml), None);
+}
+
+#[test_case]
+fn test_sleep_control_value() {
+    assert_eq!(sleep_control_value(SCI_EN, 5), SCI_EN | 5 << SLP_TYP_SHIFT | SLP_EN);
+    assert_eq!(sleep_control_value(SLP_TYP_MASK | 1 << 9, 0), 1 << 9 | SLP_EN);
+}
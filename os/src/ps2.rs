@@ -0,0 +1,450 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use spin::Once;
+use x86_64::instructions::port::Port;
+use crate::driver::{self, Device, DeviceKind, Driver, DriverError, IsaDevice, Match};
+use crate::time;
+
+// This file drives the 8042 PS/2 controller, which connects the keyboard and the mouse. The
+// controller has two ports; the CPU talks to the controller itself through the command port and
+// to the devices behind it through the data port (writes to the second port need a prefix).
+//
+//         command/status (0x64) |      |---- first port (IRQ 1) ----- keyboard
+//  CPU <----------------------> | 8042 |
+//               data (0x60)     |      |---- second port (IRQ 12) --- mouse
+//
+// `init` resets and tests the controller and the devices and tells which devices are connected.
+// After it, the keyboard and mouse drivers configure their device and receive its data through
+// their IRQ handlers. `Ps2Driver` runs `init` when the controller is bound, and adds the keyboard
+// and mouse it found to the device tree for those drivers.
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64; // read
+const COMMAND_PORT: u16 = 0x64; // write
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+// Controller commands
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND_PORT: u8 = 0xa7;
+const ENABLE_SECOND_PORT: u8 = 0xa8;
+const TEST_SECOND_PORT: u8 = 0xa9;
+const TEST_CONTROLLER: u8 = 0xaa;
+const TEST_FIRST_PORT: u8 = 0xab;
+const DISABLE_FIRST_PORT: u8 = 0xad;
+const ENABLE_FIRST_PORT: u8 = 0xae;
+const WRITE_SECOND_PORT: u8 = 0xd4;
+
+const CONTROLLER_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// Bits of the configuration byte
+const CONFIG_FIRST_IRQ: u8 = 1 << 0;
+const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+// Device commands and responses
+const DEVICE_IDENTIFY: u8 = 0xf2;
+const DEVICE_ENABLE_SCANNING: u8 = 0xf4;
+const DEVICE_DISABLE_SCANNING: u8 = 0xf5;
+const DEVICE_RESET: u8 = 0xff;
+/// Sent by a device to acknowledge a command byte
+pub const ACK: u8 = 0xfa;
+/// Sent by a device that wants the last command byte again
+pub const RESEND: u8 = 0xfe;
+const SELF_TEST_PASSED: u8 = 0xaa;
+
+const TIMEOUT: Duration = Duration::from_millis(50);
+// The controller buffers at most 16 bytes; the slack covers bytes arriving meanwhile. A missing
+// controller reads as 0xff, which looks like a full output buffer forever
+const FLUSH_LIMIT: usize = 32;
+// Devices may take much longer to answer a reset, since they test themselves first
+const RESET_TIMEOUT: Duration = Duration::from_millis(1000);
+const COMMAND_RETRIES: usize = 3;
+
+static CONTROLLER: Once<Ps2Controller> = Once::new();
+
+// Serializes writes, since writing to the second port takes two steps
+static WRITE_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+
+// Whether each port is enabled, i.e. its device can send data
+static PORT_ENABLED: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Port {
+    First,
+    Second,
+}
+
+impl Ps2Port {
+    fn other(self) -> Ps2Port {
+        match self {
+            Ps2Port::First => Ps2Port::Second,
+            Ps2Port::Second => Ps2Port::First,
+        }
+    }
+
+    /// The controller command that enables or disables the port
+    fn enable_command(self, enabled: bool) -> u8 {
+        match (self, enabled) {
+            (Ps2Port::First, true) => ENABLE_FIRST_PORT,
+            (Ps2Port::First, false) => DISABLE_FIRST_PORT,
+            (Ps2Port::Second, true) => ENABLE_SECOND_PORT,
+            (Ps2Port::Second, false) => DISABLE_SECOND_PORT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    /// A keyboard, including translated AT keyboards that don't answer the identify command
+    Keyboard,
+    /// A mouse with three buttons
+    Mouse,
+    /// A mouse with a scroll wheel (IntelliMouse)
+    WheelMouse,
+    /// A mouse with a scroll wheel and five buttons
+    FiveButtonMouse,
+    /// A device that answered the identify command with the given first byte
+    Unknown(u8),
+}
+
+#[derive(Debug)]
+pub enum Ps2Error {
+    /// The controller or a device did not answer in time. Also the error if there is no controller
+    Timeout,
+    /// The controller failed its self-test with the given result
+    ControllerTestFailed(u8),
+    /// The device answered a command with something else than ACK
+    UnexpectedResponse(u8),
+}
+
+/// What `init` found out about the controller
+#[derive(Debug, Clone, Copy)]
+pub struct Ps2Controller {
+    /// Whether the controller has a second (mouse) port
+    pub dual_channel: bool,
+    /// The device on each port, `None` if the port failed its test or has no working device
+    pub first: Option<DeviceType>,
+    pub second: Option<DeviceType>,
+}
+
+impl Ps2Controller {
+    pub fn device(&self, port: Ps2Port) -> Option<DeviceType> {
+        match port {
+            Ps2Port::First => self.first,
+            Ps2Port::Second => self.second,
+        }
+    }
+}
+
+/// Resets and tests the controller and both ports, and identifies the devices on them
+///
+/// Leaves the ports with a device enabled, including their interrupts, with translation to
+/// scancode set 1 turned on. A keyboard sends key presses right away, a mouse only once its
+/// driver enables data reporting. Must be called with interrupts enabled, it waits on the timer.
+pub fn init() -> Result<Ps2Controller, Ps2Error> {
+    // Keep the devices quiet while the controller is reconfigured
+    set_port_enabled(Ps2Port::First, false)?;
+    set_port_enabled(Ps2Port::Second, false)?;
+    flush_output();
+
+    let mut config = read_config()?;
+    config &= !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ | CONFIG_TRANSLATION);
+    write_config(config)?;
+
+    write_command(TEST_CONTROLLER)?;
+    match read_data_timeout(TIMEOUT)? {
+        CONTROLLER_TEST_PASSED => {}
+        result => return Err(Ps2Error::ControllerTestFailed(result)),
+    }
+    // The self-test resets the controller on some machines
+    write_config(config)?;
+
+    // A single channel controller ignores the command to enable the second port
+    let dual_channel = config & CONFIG_SECOND_CLOCK_DISABLED != 0 && {
+        set_port_enabled(Ps2Port::Second, true)?;
+        let enabled = read_config()? & CONFIG_SECOND_CLOCK_DISABLED == 0;
+        set_port_enabled(Ps2Port::Second, false)?;
+        enabled
+    };
+
+    let first = test_port(TEST_FIRST_PORT)? && {
+        set_port_enabled(Ps2Port::First, true)?;
+        true
+    };
+    let second = dual_channel && test_port(TEST_SECOND_PORT)? && {
+        set_port_enabled(Ps2Port::Second, true)?;
+        true
+    };
+
+    let controller = Ps2Controller {
+        dual_channel,
+        first: if first { identify(Ps2Port::First) } else { None },
+        second: if second { identify(Ps2Port::Second) } else { None },
+    };
+
+    config = read_config()? | CONFIG_TRANSLATION;
+    if controller.first.is_some() {
+        config |= CONFIG_FIRST_IRQ;
+    }
+    if controller.second.is_some() {
+        config |= CONFIG_SECOND_IRQ;
+    }
+    write_config(config)?;
+    Ok(*CONTROLLER.call_once(|| controller))
+}
+
+/// Returns what `init` found, `None` before it succeeded
+pub fn controller() -> Option<&'static Ps2Controller> {
+    CONTROLLER.r#try()
+}
+
+/// Returns the device on the given port, `None` if there is none or `init` did not succeed
+pub fn device(port: Ps2Port) -> Option<DeviceType> {
+    controller().and_then(|controller| controller.device(port))
+}
+
+/// The driver of the controller itself
+pub struct Ps2Driver;
+
+impl Driver for Ps2Driver {
+    fn name(&self) -> &'static str {
+        "ps2"
+    }
+
+    fn matches(&self) -> &'static [Match] {
+        &[Match::Isa("ps2")]
+    }
+
+    fn attach(&self, device: &Device) -> Result<(), DriverError> {
+        let controller = init().map_err(DriverError::device)?;
+        if controller.first == Some(DeviceType::Keyboard) {
+            let keyboard = IsaDevice { name: "keyboard", port: DATA_PORT, irq: Some(1) };
+            driver::add_device(device.id, keyboard.name, DeviceKind::Isa(keyboard));
+        }
+        if let Some(DeviceType::Mouse | DeviceType::WheelMouse | DeviceType::FiveButtonMouse) =
+            controller.second {
+            let mouse = IsaDevice { name: "mouse", port: DATA_PORT, irq: Some(12) };
+            driver::add_device(device.id, mouse.name, DeviceKind::Isa(mouse));
+        }
+        Ok(())
+    }
+
+    fn detach(&self, _device: &Device) -> Result<(), DriverError> {
+        // The keyboard and mouse were detached already, the controller itself holds nothing
+        Ok(())
+    }
+}
+
+/// Turns the controller's translation of scancode set 2 into set 1 on or off
+pub fn set_translation(enabled: bool) -> Result<(), Ps2Error> {
+    let config = read_config()?;
+    write_config(if enabled { config | CONFIG_TRANSLATION } else { config & !CONFIG_TRANSLATION })
+}
+
+/// Sends command bytes to the device on `port`, waiting for each to be acknowledged
+///
+/// Reads the answers from the data port, so it must only be used while nobody else does, i.e.
+/// before the device's IRQ handler is registered.
+pub fn device_command(port: Ps2Port, bytes: &[u8]) -> Result<(), Ps2Error> {
+    device_request(port, bytes, &mut [])
+}
+
+/// Sends command bytes to the device on `port` like `device_command`, then reads the
+/// `reply.len()` bytes it answers with
+pub fn device_request(port: Ps2Port, bytes: &[u8], reply: &mut [u8]) -> Result<(), Ps2Error> {
+    if request(port, bytes, reply, TIMEOUT)? < reply.len() {
+        return Err(Ps2Error::Timeout);
+    }
+    Ok(())
+}
+
+/// Sends a command and reads up to `reply.len()` bytes of its answer, returning how many came.
+/// The first byte may take up to `timeout`
+///
+/// The device on the other port is disabled meanwhile, so that the bytes it sends (e.g. keys
+/// typed at the same time) are not taken for the answer.
+fn request(
+    port: Ps2Port,
+    bytes: &[u8],
+    reply: &mut [u8],
+    timeout: Duration,
+) -> Result<usize, Ps2Error> {
+    let other = port.other();
+    let pause = PORT_ENABLED[other as usize].load(Ordering::Relaxed);
+    if pause {
+        write_command(other.enable_command(false))?;
+    }
+    let result = send_and_receive(port, bytes, reply, timeout);
+    if pause {
+        write_command(other.enable_command(true))?;
+    }
+    result
+}
+
+fn send_and_receive(
+    port: Ps2Port,
+    bytes: &[u8],
+    reply: &mut [u8],
+    mut timeout: Duration,
+) -> Result<usize, Ps2Error> {
+    for &byte in bytes {
+        let mut retries = 0;
+        loop {
+            write_device(port, byte)?;
+            match read_acknowledgement()? {
+                ACK => break,
+                RESEND if retries < COMMAND_RETRIES => retries += 1,
+                response => return Err(Ps2Error::UnexpectedResponse(response)),
+            }
+        }
+    }
+    for (count, reply_byte) in reply.iter_mut().enumerate() {
+        match read_data_timeout(timeout) {
+            Ok(byte) => *reply_byte = byte,
+            Err(Ps2Error::Timeout) => return Ok(count),
+            Err(error) => return Err(error),
+        }
+        timeout = TIMEOUT;
+    }
+    Ok(reply.len())
+}
+
+/// Waits for an ACK or RESEND, skipping the data the device sent before it saw the command
+fn read_acknowledgement() -> Result<u8, Ps2Error> {
+    let start = time::uptime();
+    loop {
+        let remaining = TIMEOUT.checked_sub(time::uptime() - start).ok_or(Ps2Error::Timeout)?;
+        if let response @ (ACK | RESEND) = read_data_timeout(remaining)? {
+            return Ok(response);
+        }
+    }
+}
+
+/// Writes a byte to the device on `port` without waiting for its answer
+///
+/// Used once the device's IRQ handler is registered: the answer arrives through it.
+pub fn write_device(port: Ps2Port, byte: u8) -> Result<(), Ps2Error> {
+    let _lock = WRITE_LOCK.lock();
+    if port == Ps2Port::Second {
+        write_command_unlocked(WRITE_SECOND_PORT)?;
+    }
+    wait_until(TIMEOUT, || status() & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::new(DATA_PORT).write(byte) };
+    Ok(())
+}
+
+/// Reads the byte a device sent. For IRQ handlers, which only run when there is one
+pub fn read_data() -> u8 {
+    unsafe { Port::new(DATA_PORT).read() }
+}
+
+/// Throws away the bytes waiting in the output buffer
+pub fn flush_output() {
+    for _ in 0..FLUSH_LIMIT {
+        if status() & STATUS_OUTPUT_FULL == 0 {
+            break;
+        }
+        read_data();
+    }
+}
+
+/// Resets the device on `port` and finds out what kind of device it is
+fn identify(port: Ps2Port) -> Option<DeviceType> {
+    // Mice send their ID after the self-test result
+    let mut result = [0; 2];
+    if request(port, &[DEVICE_RESET], &mut result, RESET_TIMEOUT).ok()? == 0
+        || result[0] != SELF_TEST_PASSED {
+        return None;
+    }
+
+    device_command(port, &[DEVICE_DISABLE_SCANNING]).ok()?;
+    // Keyboards send a second ID byte
+    let mut id = [0; 2];
+    let count = request(port, &[DEVICE_IDENTIFY], &mut id, TIMEOUT).ok()?;
+    let device = device_type(id[..count].first().copied());
+    // A mouse would start sending packets right away, leave that to the mouse driver
+    if device == DeviceType::Keyboard {
+        device_command(port, &[DEVICE_ENABLE_SCANNING]).ok()?;
+    }
+    Some(device)
+}
+
+/// Interprets the first byte of the answer to the identify command
+fn device_type(id: Option<u8>) -> DeviceType {
+    match id {
+        // Ancient AT keyboards don't answer at all
+        None | Some(0xab) => DeviceType::Keyboard,
+        Some(0x00) => DeviceType::Mouse,
+        Some(0x03) => DeviceType::WheelMouse,
+        Some(0x04) => DeviceType::FiveButtonMouse,
+        Some(id) => DeviceType::Unknown(id),
+    }
+}
+
+fn test_port(command: u8) -> Result<bool, Ps2Error> {
+    write_command(command)?;
+    Ok(read_data_timeout(TIMEOUT)? == PORT_TEST_PASSED)
+}
+
+fn read_config() -> Result<u8, Ps2Error> {
+    write_command(READ_CONFIG)?;
+    read_data_timeout(TIMEOUT)
+}
+
+fn write_config(config: u8) -> Result<(), Ps2Error> {
+    let _lock = WRITE_LOCK.lock();
+    write_command_unlocked(WRITE_CONFIG)?;
+    wait_until(TIMEOUT, || status() & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::new(DATA_PORT).write(config) };
+    Ok(())
+}
+
+fn set_port_enabled(port: Ps2Port, enabled: bool) -> Result<(), Ps2Error> {
+    write_command(port.enable_command(enabled))?;
+    PORT_ENABLED[port as usize].store(enabled, Ordering::Relaxed);
+    Ok(())
+}
+
+fn write_command(command: u8) -> Result<(), Ps2Error> {
+    let _lock = WRITE_LOCK.lock();
+    write_command_unlocked(command)
+}
+
+fn write_command_unlocked(command: u8) -> Result<(), Ps2Error> {
+    wait_until(TIMEOUT, || status() & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::new(COMMAND_PORT).write(command) };
+    Ok(())
+}
+
+fn read_data_timeout(timeout: Duration) -> Result<u8, Ps2Error> {
+    wait_until(timeout, || status() & STATUS_OUTPUT_FULL != 0)?;
+    Ok(read_data())
+}
+
+fn status() -> u8 {
+    unsafe { Port::new(STATUS_PORT).read() }
+}
+
+fn wait_until(timeout: Duration, mut condition: impl FnMut() -> bool) -> Result<(), Ps2Error> {
+    let start = time::uptime();
+    while !condition() {
+        if time::uptime() - start > timeout {
+            return Err(Ps2Error::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_device_type() {
+    assert_eq!(device_type(None), DeviceType::Keyboard);
+    assert_eq!(device_type(Some(0xab)), DeviceType::Keyboard);
+    assert_eq!(device_type(Some(0x03)), DeviceType::WheelMouse);
+    assert_eq!(device_type(Some(0x42)), DeviceType::Unknown(0x42));
+}
@@ -0,0 +1,229 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+use crate::time;
+
+// This file reads the date and time from the battery backed real-time clock (RTC) in the CMOS
+// chip. The RTC only has a resolution of one second and is slow to read, so it is read once at
+// boot and the timer tick (see `time`) is used to advance the wall clock from there.
+
+const CMOS_ADDRESS_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+
+// Setting bit 7 of the address disables Non-Maskable Interrupts, which we don't want to touch
+const NMI_DISABLE: u8 = 0x80;
+
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_CENTURY: u8 = 0x32; // not standardized, but where QEMU and most PCs keep it
+const RTC_STATUS_A: u8 = 0x0a;
+const RTC_STATUS_B: u8 = 0x0b;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7; // set in the hours register for PM times in 12 hour mode
+
+static BOOT_TIMESTAMP: AtomicU64 = AtomicU64::new(0); // Unix timestamp read from the RTC by `init`
+static BOOT_TICKS: AtomicU64 = AtomicU64::new(0); // timer ticks at the moment of that read
+
+/// A calendar date and time of day (UTC, or whatever the firmware keeps the RTC in)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8, // 1-12
+    pub day: u8, // 1-31
+    pub hour: u8, // 0-23
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Returns the number of seconds since 1970-01-01 00:00:00
+    pub fn unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(i64::from(self.year), u32::from(self.month),
+                                   u32::from(self.day));
+        let seconds = u64::from(self.hour) * 3600 + u64::from(self.minute) * 60
+            + u64::from(self.second);
+        days as u64 * 86400 + seconds
+    }
+
+    /// Converts a number of seconds since 1970-01-01 00:00:00 back into a date and time
+    pub fn from_unix_timestamp(timestamp: u64) -> DateTime {
+        let (year, month, day) = civil_from_days((timestamp / 86400) as i64);
+        let seconds = timestamp % 86400;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+               self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+/// Returns the number of days between 1970-01-01 and the given date
+///
+/// Uses Howard Hinnant's `days_from_civil` algorithm, which works with years starting in March
+/// so the leap day is the last day of the year.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400; // [0, 399]
+    let month_from_march = (month + 9) % 12; // [0, 11]
+    let day_of_year = i64::from((153 * month_from_march + 2) / 5 + day - 1); // [0, 365]
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Returns the (year, month, day) that is the given number of days after 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097; // [0, 146096]
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153; // [0, 11]
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u32;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as u32, day)
+}
+
+fn read_register(register: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS_PORT);
+    let mut data: Port<u8> = Port::new(CMOS_DATA_PORT);
+    unsafe {
+        address.write(register & !NMI_DISABLE);
+        data.read()
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_register(RTC_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// The raw register values, in whatever format the RTC is configured for
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_raw() -> RawTime {
+    while update_in_progress() {}
+    RawTime {
+        second: read_register(RTC_SECONDS),
+        minute: read_register(RTC_MINUTES),
+        hour: read_register(RTC_HOURS),
+        day: read_register(RTC_DAY),
+        month: read_register(RTC_MONTH),
+        year: read_register(RTC_YEAR),
+        century: read_register(RTC_CENTURY),
+    }
+}
+
+/// Converts raw register values to a `DateTime` according to the RTC status register B
+fn decode(raw: RawTime, status_b: u8) -> DateTime {
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let convert = |value: u8| if binary { value } else { bcd_to_binary(value) };
+
+    // The PM flag is stored in the top bit of the hours, outside of the BCD digits
+    let pm = raw.hour & HOUR_PM != 0;
+    let mut hour = convert(raw.hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 hour mode goes 12, 1, ..., 11 for both AM and PM
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let century = convert(raw.century);
+    let century = if (19..=21).contains(&century) { century } else { 20 };
+
+    DateTime {
+        year: u16::from(century) * 100 + u16::from(convert(raw.year)),
+        month: convert(raw.month),
+        day: convert(raw.day),
+        hour,
+        minute: convert(raw.minute),
+        second: convert(raw.second),
+    }
+}
+
+/// Reads the current date and time from the RTC
+///
+/// The RTC updates its registers once per second, and reading them during the update can return
+/// a mix of the old and new time. So we wait for the update to finish and read until we get the
+/// same values twice in a row.
+pub fn read() -> DateTime {
+    let mut raw = read_raw();
+    loop {
+        let again = read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+    decode(raw, read_register(RTC_STATUS_B))
+}
+
+/// Reads the RTC once to set the wall clock. Must be called after `time::init`
+pub fn init() {
+    let timestamp = read().unix_timestamp();
+    BOOT_TICKS.store(time::ticks(), Ordering::Relaxed);
+    BOOT_TIMESTAMP.store(timestamp, Ordering::Relaxed);
+}
+
+/// Returns the current number of seconds since 1970-01-01 00:00:00
+pub fn unix_timestamp() -> u64 {
+    let ticks = time::ticks() - BOOT_TICKS.load(Ordering::Relaxed);
+    BOOT_TIMESTAMP.load(Ordering::Relaxed) + time::ticks_to_duration(ticks).as_secs()
+}
+
+/// Returns the current date and time
+pub fn now() -> DateTime {
+    DateTime::from_unix_timestamp(unix_timestamp())
+}
+
+#[test_case]
+fn test_unix_timestamp() {
+    let date_time = DateTime { year: 2000, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+    assert_eq!(date_time.unix_timestamp(), 946_684_800);
+    let date_time = DateTime { year: 2024, month: 2, day: 29, hour: 13, minute: 37, second: 42 };
+    assert_eq!(date_time.unix_timestamp(), 1_709_213_862);
+    assert_eq!(DateTime::from_unix_timestamp(1_709_213_862), date_time);
+}
+
+#[test_case]
+fn test_decode_bcd_12_hour() { // 11:59:30 PM on 2023-12-31, as stored in BCD 12 hour mode
+    let raw = RawTime {
+        second: 0x30, minute: 0x59, hour: 0x11 | HOUR_PM, day: 0x31, month: 0x12, year: 0x23,
+        century: 0x20,
+    };
+    let expected = DateTime { year: 2023, month: 12, day: 31, hour: 23, minute: 59, second: 30 };
+    assert_eq!(decode(raw, 0), expected);
+}
@@ -1,30 +1,127 @@
-use uart_16550::SerialPort;
-use spin::Mutex;
-use lazy_static::lazy_static;
+use core::fmt;
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::Poll;
+use spin::Once;
+use crate::driver::{Device, Driver, DriverError, Match};
+use crate::interrupts::{self, IrqError, IrqHandlerId};
+use crate::sync::{ArrayQueue, AtomicWaker, IrqSpinLock};
+use crate::{tty, vga_buffer};
+
+mod uart;
+
+pub use uart::{DataBits, FifoTrigger, FlowControl, Parity, SerialConfig, SerialError, StopBits};
 
 // Defines communication over Serial Port (i.e. printing back to host from Qemu VM)
+//
+// A PC has up to four serial ports, COM1-COM4, each driven by a 16550 UART. They are probed on
+// first use (or by `init`), and the ones found can be looked up by name ("com1", ...) and used
+// for different things at the same time: `serial_print!` writes to the log port (COM1 unless
+// changed with `set_log_port`), a console can be attached to a port so its output goes out and
+// what comes in goes to its TTY, and other ports can be read and written directly.
+//
+// Bytes coming in on a port raise its IRQ (4 for COM1/COM3, 3 for COM2/COM4). Like the keyboard,
+// the IRQ handler only moves them into the port's lock-free queue; `SerialPort::run` decodes them
+// and feeds them to the TTY of the attached console. That way the shell can be used over
+// `-serial stdio` without a display:
+//
+//  COM IRQ ---> SerialPort::received ---> run: UTF-8 decoding ---> TTY of the attached console
+//  console output ---> Writer ---> VGA buffer
+//                         +------> serial port, with ANSI escape sequences for cursor movement
 
-lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8)}; // 0x3F8 is the standard port
-                                                                // number for the first serial
-                                                                // interface
-        serial_port.init();
-        Mutex::new(serial_port)
-    };
+// Bytes arriving while the queue is full are dropped
+const RECEIVE_QUEUE_SIZE: usize = 256;
+
+// With RTS/CTS flow control, the other side is asked to stop sending once the queue is this full
+// and may go on once it has drained to RESUME_LEVEL
+const PAUSE_LEVEL: usize = RECEIVE_QUEUE_SIZE * 3 / 4;
+const RESUME_LEVEL: usize = RECEIVE_QUEUE_SIZE / 4;
+
+// Ports no console is attached to
+const NO_CONSOLE: usize = usize::MAX;
+
+/// The serial ports a PC can have, probed by `init` or on first use
+static PORTS: [SerialPort; 4] = [
+    SerialPort::new("com1", 0x3f8, 4),
+    SerialPort::new("com2", 0x2f8, 3),
+    SerialPort::new("com3", 0x3e8, 4),
+    SerialPort::new("com4", 0x2e8, 3),
+];
+
+static PROBED: Once<()> = Once::new();
+
+// The handlers registered for IRQ 3 and 4, each shared by the ports using that IRQ
+static IRQ_HANDLERS: spin::Mutex<[Option<IrqHandlerId>; 2]> = spin::Mutex::new([None, None]);
+
+// Index into PORTS of the port `serial_print!` writes to
+static LOG_PORT: AtomicUsize = AtomicUsize::new(0);
+
+/// A serial port, i.e. a UART and what it has received
+pub struct SerialPort {
+    name: &'static str,
+    base: u16,
+    irq: u8,
+    present: AtomicBool,
+    uart: IrqSpinLock<(uart::Uart, SerialConfig)>,
+    received: ArrayQueue<u8, RECEIVE_QUEUE_SIZE>,
+    waker: AtomicWaker,
+    flow_control: AtomicBool, // RTS/CTS flow control is used, kept outside of the lock for the
+                              // IRQ handler
+    paused: AtomicBool, // RTS was cleared to stop the other side from sending
+    receiving: AtomicBool, // the IRQ handler reads the bytes coming in on the port
+    console: AtomicUsize, // the console attached to the port, NO_CONSOLE for none
+}
+
+/// Looks for UARTs behind the standard serial ports and sets the ones found up with the default
+/// configuration. Only the first call does anything
+///
+/// The ports are probed on first use anyway, so that output printed before this is not lost.
+pub fn init() {
+    // An interrupt handler printing while the ports are probed would spin on `PROBED` forever
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        PROBED.call_once(|| {
+            for port in PORTS.iter() {
+                let mut uart = port.uart.lock();
+                let (uart, config) = &mut *uart;
+                port.present.store(uart.probe(config), Ordering::Relaxed);
+            }
+        });
+    });
+}
+
+/// Returns the serial ports that were found
+pub fn ports() -> impl Iterator<Item = &'static SerialPort> {
+    init();
+    PORTS.iter().filter(|port| port.is_present())
+}
+
+/// Returns the serial port with the given name, e.g. "com2", if it was found
+pub fn port(name: &str) -> Option<&'static SerialPort> {
+    ports().find(|port| port.name == name)
+}
+
+/// Returns the port `serial_print!` writes to
+pub fn log_port() -> &'static SerialPort {
+    init();
+    &PORTS[LOG_PORT.load(Ordering::Relaxed)]
+}
+
+/// Makes `serial_print!` write to the port with the given name
+pub fn set_log_port(name: &str) -> Result<(), SerialError> {
+    init();
+    let index = PORTS.iter()
+        .position(|port| port.name == name && port.is_present())
+        .ok_or(SerialError::NotPresent)?;
+    LOG_PORT.store(index, Ordering::Relaxed);
+    Ok(())
 }
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
-    // Prevent deadlocks with interrupts calling print
-    interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial1 failed.");
-    });
+    // The lock disables interrupts, preventing deadlocks with interrupts calling print. A port
+    // that doesn't take the output just loses it; there is nowhere else to report that to
+    let _ = log_port().writer().write_fmt(args);
 }
 
 /// Prints to the host through the serial interface
@@ -43,3 +140,304 @@ macro_rules! serial_println {
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
             concat!($fmt, "\n"), $($arg)*));
 }
+
+/// Registers the IRQ handlers that receive bytes from the serial ports found. Needs the heap
+pub fn start_receiving() -> Result<(), IrqError> {
+    for port in ports() {
+        port.start_receiving()?;
+    }
+    Ok(())
+}
+
+/// Handles the IRQ shared by the ports using `IRQ`
+fn interrupt_handler<const IRQ: u8>() {
+    for port in PORTS.iter().filter(|port| port.irq == IRQ && port.is_receiving()) {
+        port.receive_pending();
+    }
+}
+
+/// The driver of the serial ports, binding `SerialPort`s to the COM devices of the tree
+pub struct SerialDriver;
+
+impl Driver for SerialDriver {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn matches(&self) -> &'static [Match] {
+        &[Match::Isa("com1"), Match::Isa("com2"), Match::Isa("com3"), Match::Isa("com4")]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), DriverError> {
+        port(&device.name).map(|_| ()).ok_or(DriverError::NotSupported)
+    }
+
+    fn attach(&self, device: &Device) -> Result<(), DriverError> {
+        let port = port(&device.name).ok_or(DriverError::NotSupported)?;
+        port.start_receiving().map_err(DriverError::Irq)
+    }
+
+    fn detach(&self, device: &Device) -> Result<(), DriverError> {
+        let port = port(&device.name).ok_or(DriverError::NotSupported)?;
+        port.stop_receiving();
+        Ok(())
+    }
+}
+
+impl SerialPort {
+    const fn new(name: &'static str, base: u16, irq: u8) -> SerialPort {
+        SerialPort {
+            name,
+            base,
+            irq,
+            present: AtomicBool::new(false),
+            uart: IrqSpinLock::new((uart::Uart::new(base), SerialConfig::DEFAULT)),
+            received: ArrayQueue::new(),
+            waker: AtomicWaker::new(),
+            flow_control: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            receiving: AtomicBool::new(false),
+            console: AtomicUsize::new(NO_CONSOLE),
+        }
+    }
+
+    /// The name of the port, e.g. "com1"
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The first of the I/O ports of the UART
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    pub fn irq(&self) -> u8 {
+        self.irq
+    }
+
+    /// Returns whether a UART was found behind the port
+    pub fn is_present(&self) -> bool {
+        self.present.load(Ordering::Relaxed)
+    }
+
+    pub fn config(&self) -> SerialConfig {
+        self.uart.lock().1
+    }
+
+    /// Changes the baud rate, line parameters, FIFO trigger and flow control. Bytes not read from
+    /// the UART yet are dropped
+    pub fn configure(&self, config: SerialConfig) -> Result<(), SerialError> {
+        if !self.is_present() {
+            return Err(SerialError::NotPresent);
+        }
+        let mut uart = self.uart.lock();
+        uart.0.configure(&config)?;
+        uart.1 = config;
+        self.flow_control.store(config.flow_control == FlowControl::RtsCts, Ordering::Relaxed);
+        self.paused.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Sends bytes as they are, without any translation
+    pub fn write(&self, bytes: &[u8]) -> Result<(), SerialError> {
+        if !self.is_present() {
+            return Err(SerialError::NotPresent);
+        }
+        let mut uart = self.uart.lock();
+        for &byte in bytes {
+            uart.0.send(byte)?;
+        }
+        Ok(())
+    }
+
+    /// Returns something to `write!` to the port
+    pub fn writer(&self) -> SerialWriter<'_> {
+        SerialWriter { port: self }
+    }
+
+    /// Returns whether the bytes coming in on the port are received, see `start_receiving`
+    pub fn is_receiving(&self) -> bool {
+        self.receiving.load(Ordering::Relaxed)
+    }
+
+    /// Registers the port's IRQ handler, unless another port on the same IRQ did already, so
+    /// the bytes coming in are queued for `receive`. Needs the heap
+    pub fn start_receiving(&self) -> Result<(), IrqError> {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let handler = &mut handlers[usize::from(self.irq == 4)];
+        if handler.is_none() {
+            let function = match self.irq {
+                3 => interrupt_handler::<3> as fn(),
+                _ => interrupt_handler::<4> as fn(),
+            };
+            *handler = Some(interrupts::register_irq(self.irq, function)?);
+        }
+        self.receiving.store(true, Ordering::Relaxed);
+        // Bytes that arrived before there was a handler were never read, and the UART doesn't
+        // raise another interrupt until they are
+        x86_64::instructions::interrupts::without_interrupts(|| self.receive_pending());
+        Ok(())
+    }
+
+    /// Stops receiving, unregistering the IRQ handler if no other port uses it. Bytes already
+    /// queued can still be read
+    pub fn stop_receiving(&self) {
+        let mut handlers = IRQ_HANDLERS.lock();
+        self.receiving.store(false, Ordering::Relaxed);
+        let shared = PORTS.iter().any(|port| port.irq == self.irq && port.is_receiving());
+        if !shared {
+            if let Some(handler) = handlers[usize::from(self.irq == 4)].take() {
+                interrupts::unregister_irq(handler);
+            }
+        }
+    }
+
+    /// Moves the bytes waiting in the UART into the queue. Reading them also acknowledges the
+    /// interrupt
+    fn receive_pending(&self) {
+        while let Some(byte) = uart::try_read(self.base) {
+            self.add_received(byte);
+        }
+    }
+
+    /// Queues a byte received on the port and wakes `receive`. Called by the IRQ handler, so it
+    /// neither blocks nor allocates. Returns false if the queue was full and the byte dropped
+    pub fn add_received(&self, byte: u8) -> bool {
+        let queued = self.received.push(byte).is_ok();
+        if self.received.len() >= PAUSE_LEVEL && self.flow_control.load(Ordering::Relaxed)
+            && !self.paused.swap(true, Ordering::Relaxed) {
+            self.uart.lock().0.set_rts(false);
+        }
+        self.waker.wake();
+        queued
+    }
+
+    /// Returns the next byte received on the port, if there is one
+    pub fn try_receive(&self) -> Option<u8> {
+        let byte = self.received.pop();
+        if self.received.len() <= RESUME_LEVEL && self.paused.swap(false, Ordering::Relaxed) {
+            self.uart.lock().0.set_rts(true);
+        }
+        byte
+    }
+
+    /// Waits for the next byte received on the port
+    ///
+    /// Only one task may wait for bytes at a time.
+    pub async fn receive(&self) -> u8 {
+        poll_fn(|context| {
+            if let Some(byte) = self.try_receive() {
+                return Poll::Ready(byte);
+            }
+            // Check again after registering, the byte may have come in between
+            self.waker.register(context.waker());
+            match self.try_receive() {
+                Some(byte) => Poll::Ready(byte),
+                None => Poll::Pending,
+            }
+        }).await
+    }
+
+    /// Makes this port the terminal of `console`: the console's output is mirrored to the port,
+    /// and what comes in on the port goes to the console's TTY
+    pub fn attach_console(&'static self, console: usize) {
+        if let Some(previous) = self.console() {
+            vga_buffer::CONSOLES[previous].lock().set_serial_mirror(None);
+        }
+        vga_buffer::CONSOLES[console].lock().set_serial_mirror(Some(self));
+        self.console.store(console, Ordering::Relaxed);
+    }
+
+    /// Returns the console attached to the port
+    pub fn console(&self) -> Option<usize> {
+        match self.console.load(Ordering::Relaxed) {
+            NO_CONSOLE => None,
+            console => Some(console),
+        }
+    }
+
+    /// Feeds the characters received on the port to the TTY of the attached console forever.
+    /// Without a console, they are dropped
+    pub async fn run(&self) {
+        let mut decoder = Utf8Decoder::new();
+        loop {
+            let byte = self.receive().await;
+            if let (Some(character), Some(console)) = (decoder.push(byte), self.console()) {
+                tty::tty(console).input(character);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for SerialPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SerialPort")
+            .field("name", &self.name)
+            .field("base", &self.base)
+            .field("irq", &self.irq)
+            .finish()
+    }
+}
+
+/// Writes formatted text to a serial port
+pub struct SerialWriter<'a> {
+    port: &'a SerialPort,
+}
+
+impl fmt::Write for SerialWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.port.write(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+// Puts characters back together from the UTF-8 bytes they are sent as
+struct Utf8Decoder {
+    bytes: [u8; 4],
+    len: usize,
+}
+
+impl Utf8Decoder {
+    fn new() -> Utf8Decoder {
+        Utf8Decoder { bytes: [0; 4], len: 0 }
+    }
+
+    /// Adds a byte. Returns the character once all of its bytes are in, U+FFFD for bytes that
+    /// are not valid UTF-8
+    fn push(&mut self, byte: u8) -> Option<char> {
+        // Anything but a continuation byte starts a new character
+        if byte & 0xc0 != 0x80 {
+            self.len = 0;
+        }
+        if self.len == self.bytes.len() {
+            self.len = 0;
+            return Some(char::REPLACEMENT_CHARACTER);
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        match core::str::from_utf8(&self.bytes[..self.len]) {
+            Ok(text) => {
+                self.len = 0;
+                text.chars().next()
+            }
+            // The rest of the character is still to come
+            Err(err) if err.error_len().is_none() => None,
+            Err(_) => {
+                self.len = 0;
+                Some(char::REPLACEMENT_CHARACTER)
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_utf8_decoder() {
+    let mut decoder = Utf8Decoder::new();
+    assert_eq!(decoder.push(b'a'), Some('a'));
+    // 'ä' is 0xc3 0xa4
+    assert_eq!(decoder.push(0xc3), None);
+    assert_eq!(decoder.push(0xa4), Some('ä'));
+    // A lone continuation byte, then a character cut short by the next one
+    assert_eq!(decoder.push(0xa4), Some(char::REPLACEMENT_CHARACTER));
+    assert_eq!(decoder.push(0xc3), None);
+    assert_eq!(decoder.push(b'\r'), Some('\r'));
+}
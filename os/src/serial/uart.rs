@@ -0,0 +1,298 @@
+use core::convert::TryFrom;
+use x86_64::instructions::port::Port;
+
+// Register level access to a 16550 UART, the chip behind the PC's serial ports. Its eight
+// registers are consecutive I/O ports starting at the base port:
+//
+//  +0  data (receive buffer / transmit holding), or the divisor low byte while DLAB is set
+//  +1  interrupt enable, or the divisor high byte while DLAB is set
+//  +2  FIFO control (write only)
+//  +3  line control: data bits, stop bits, parity and DLAB
+//  +4  modem control: DTR, RTS, OUT2 (gates the IRQ line) and loopback
+//  +5  line status
+//  +6  modem status
+//  +7  scratch, a spare byte that tells whether a UART is there at all
+
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const MODEM_STATUS: u16 = 6;
+const SCRATCH: u16 = 7;
+
+// Interrupt enable: "data received"
+const RECEIVED_DATA_INTERRUPT: u8 = 1 << 0;
+
+// FIFO control: enable the FIFOs and clear both of them
+const FIFO_ENABLE_AND_CLEAR: u8 = 0x07;
+
+// Line control: divisor latch access
+const DLAB: u8 = 1 << 7;
+
+// Modem control
+const DTR: u8 = 1 << 0;
+const RTS: u8 = 1 << 1;
+const OUT1: u8 = 1 << 2;
+const OUT2: u8 = 1 << 3;
+const LOOPBACK: u8 = 1 << 4;
+
+// Line status
+const DATA_READY: u8 = 1 << 0;
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+
+// Modem status: the other side is clear to receive
+const CTS: u8 = 1 << 4;
+
+/// The UART's clock divided by 16: the fastest baud rate, and what the divisor divides
+const MAX_BAUD: u32 = 115_200;
+
+// How often to check the line status before giving up on sending a byte
+const SEND_SPINS: usize = 1_000_000;
+
+// The byte sent to itself in loopback mode when probing
+const PROBE_BYTE: u8 = 0xae;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    /// The parity bit is always 1
+    Mark,
+    /// The parity bit is always 0
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    /// Two stop bits, or 1.5 with five data bits
+    Two,
+}
+
+/// Number of bytes in the receive FIFO that raise the "data received" interrupt. Fewer bytes
+/// raise it once no more have come in for a few characters' time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FifoTrigger {
+    Bytes1,
+    Bytes4,
+    Bytes8,
+    Bytes14,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowControl {
+    None,
+    /// Bytes are only sent while CTS is set, and RTS is cleared while the receive queue is
+    /// nearly full
+    RtsCts,
+}
+
+/// How a serial port is set up. The default is 38400 baud, 8 data bits, no parity, 1 stop bit
+/// (38400/8-N-1), a 14 byte FIFO trigger and no flow control
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialConfig {
+    pub baud: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub fifo_trigger: FifoTrigger,
+    pub flow_control: FlowControl,
+}
+
+impl Default for SerialConfig {
+    fn default() -> SerialConfig {
+        SerialConfig::DEFAULT
+    }
+}
+
+impl SerialConfig {
+    /// What the ports are set up with when they are probed
+    pub const DEFAULT: SerialConfig = SerialConfig {
+        baud: 38_400,
+        data_bits: DataBits::Eight,
+        parity: Parity::None,
+        stop_bits: StopBits::One,
+        fifo_trigger: FifoTrigger::Bytes14,
+        flow_control: FlowControl::None,
+    };
+
+    /// Returns the value of the divisor latch for the baud rate, if the UART can run at it
+    fn divisor(&self) -> Option<u16> {
+        if !MAX_BAUD.is_multiple_of(self.baud) {
+            return None;
+        }
+        u16::try_from(MAX_BAUD / self.baud).ok()
+    }
+
+    /// Returns the value of the line control register
+    fn line_control(&self) -> u8 {
+        let data_bits = match self.data_bits {
+            DataBits::Five => 0,
+            DataBits::Six => 1,
+            DataBits::Seven => 2,
+            DataBits::Eight => 3,
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => 1 << 2,
+        };
+        let parity = match self.parity {
+            Parity::None => 0,
+            Parity::Odd => 0b001 << 3,
+            Parity::Even => 0b011 << 3,
+            Parity::Mark => 0b101 << 3,
+            Parity::Space => 0b111 << 3,
+        };
+        data_bits | stop_bits | parity
+    }
+
+    /// Returns the value of the FIFO control register
+    fn fifo_control(&self) -> u8 {
+        let trigger = match self.fifo_trigger {
+            FifoTrigger::Bytes1 => 0,
+            FifoTrigger::Bytes4 => 1,
+            FifoTrigger::Bytes8 => 2,
+            FifoTrigger::Bytes14 => 3,
+        };
+        FIFO_ENABLE_AND_CLEAR | trigger << 6
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    /// There is no serial port with that name, or no UART behind it
+    NotPresent,
+    /// The baud rate is not 115200 divided by a whole number
+    InvalidBaud(u32),
+    /// The line never became ready (e.g. CTS stayed cleared) and the byte was dropped
+    Timeout,
+}
+
+/// A 16550 UART at a base I/O port
+pub(super) struct Uart {
+    base: u16,
+    flow_control: FlowControl,
+}
+
+impl Uart {
+    /// Creates an interface to the UART at `base`, without touching the hardware
+    ///
+    /// The registers are only accessed once the port is probed, so a wrong base is harmless
+    /// until then.
+    pub(super) const fn new(base: u16) -> Uart {
+        Uart { base, flow_control: FlowControl::None }
+    }
+
+    fn port(&self, register: u16) -> Port<u8> {
+        Port::new(self.base + register)
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { self.port(register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { self.port(register).write(value) }
+    }
+
+    /// Returns whether a working UART is at the base port: the scratch register has to hold a
+    /// value, and a byte sent in loopback mode has to come back. Leaves the UART configured
+    /// with `config`
+    pub(super) fn probe(&mut self, config: &SerialConfig) -> bool {
+        self.write(SCRATCH, 0x55);
+        if self.read(SCRATCH) != 0x55 || self.configure(config).is_err() {
+            return false;
+        }
+        self.write(MODEM_CONTROL, LOOPBACK | OUT2 | OUT1 | RTS);
+        self.write(DATA, PROBE_BYTE);
+        let mut came_back = false;
+        for _ in 0..SEND_SPINS {
+            if self.read(LINE_STATUS) & DATA_READY != 0 {
+                came_back = self.read(DATA) == PROBE_BYTE;
+                break;
+            }
+        }
+        self.write(MODEM_CONTROL, DTR | RTS | OUT2);
+        came_back
+    }
+
+    /// Sets the line parameters, FIFO and flow control up, and enables the "data received"
+    /// interrupt. Bytes in the FIFOs are dropped
+    pub(super) fn configure(&mut self, config: &SerialConfig) -> Result<(), SerialError> {
+        let divisor = config.divisor().ok_or(SerialError::InvalidBaud(config.baud))?;
+        self.write(INTERRUPT_ENABLE, 0);
+        self.write(LINE_CONTROL, DLAB);
+        self.write(DATA, divisor as u8);
+        self.write(INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        self.write(LINE_CONTROL, config.line_control());
+        self.write(FIFO_CONTROL, config.fifo_control());
+        // DTR and RTS tell the other side we're ready, OUT2 connects the UART to its IRQ line
+        self.write(MODEM_CONTROL, DTR | RTS | OUT2);
+        self.write(INTERRUPT_ENABLE, RECEIVED_DATA_INTERRUPT);
+        self.flow_control = config.flow_control;
+        Ok(())
+    }
+
+    /// Sets or clears RTS, telling the other side of a port with RTS/CTS flow control whether it
+    /// may send
+    pub(super) fn set_rts(&mut self, ready: bool) {
+        self.write(MODEM_CONTROL, if ready { DTR | RTS | OUT2 } else { DTR | OUT2 });
+    }
+
+    /// Sends a byte as it is, waiting for room in the transmitter and, with RTS/CTS flow
+    /// control, for the other side to be ready
+    pub(super) fn send(&mut self, byte: u8) -> Result<(), SerialError> {
+        let wait_for_cts = self.flow_control == FlowControl::RtsCts;
+        for _ in 0..SEND_SPINS {
+            let clear_to_send = !wait_for_cts || self.read(MODEM_STATUS) & CTS != 0;
+            if clear_to_send && self.read(LINE_STATUS) & TRANSMIT_EMPTY != 0 {
+                self.write(DATA, byte);
+                return Ok(());
+            }
+        }
+        Err(SerialError::Timeout)
+    }
+}
+
+/// Reads a byte the UART at `base` received, if there is one
+///
+/// Only touches the line status and receive registers, so it can be used from the IRQ handler
+/// without taking the lock that serializes sending.
+pub(super) fn try_read(base: u16) -> Option<u8> {
+    let uart = Uart::new(base);
+    if uart.read(LINE_STATUS) & DATA_READY != 0 {
+        Some(uart.read(DATA))
+    } else {
+        None
+    }
+}
+
+#[test_case]
+fn test_config_registers() {
+    let config = SerialConfig::default();
+    assert_eq!(config.divisor(), Some(3));
+    assert_eq!(config.line_control(), 0x03);
+    assert_eq!(config.fifo_control(), 0xc7);
+
+    let config = SerialConfig {
+        baud: 9600,
+        data_bits: DataBits::Seven,
+        parity: Parity::Even,
+        stop_bits: StopBits::Two,
+        ..SerialConfig::default()
+    };
+    assert_eq!(config.divisor(), Some(12));
+    assert_eq!(config.line_control(), 0x1e);
+    assert_eq!(SerialConfig { baud: 7000, ..config }.divisor(), None);
+}
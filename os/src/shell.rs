@@ -0,0 +1,158 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use crate::tty::TtyError;
+use crate::console_println;
+
+mod commands;
+mod line_editor;
+
+pub use line_editor::{LineEditor, HISTORY_SIZE};
+
+// This file is a small interactive shell for poking at the running kernel. It reads lines from
+// the TTY of its console with a `LineEditor`, splits them into words and runs the built-in
+// command named by the first word. Commands print to the shell's console; a command that fails
+// returns a `ShellError`, which the shell prints.
+
+const PROMPT: &str = "neek> ";
+
+/// A built-in command
+pub struct Command {
+    pub name: &'static str,
+    /// The arguments the command takes, e.g. "<addr> [count]"
+    pub usage: &'static str,
+    /// One line describing what the command does
+    pub help: &'static str,
+    run: fn(&Shell, &[&str]) -> Result<(), ShellError>,
+}
+
+/// Returns all built-in commands, sorted by name
+pub fn commands() -> &'static [Command] {
+    &commands::COMMANDS
+}
+
+/// Returns the built-in command with the given name
+pub fn find_command(name: &str) -> Option<&'static Command> {
+    commands().iter().find(|command| command.name == name)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ShellError {
+    UnknownCommand(String),
+    /// The arguments don't match the usage of the command
+    Usage(&'static Command),
+    InvalidNumber(String),
+    UnknownColor(String),
+    /// The address is not mapped to physical memory
+    NotMapped(u64),
+    /// The address is mapped read-only
+    NotWritable(u64),
+}
+
+impl PartialEq for Command {
+    fn eq(&self, other: &Command) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for Command {}
+
+impl fmt::Debug for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Command").field("name", &self.name).finish()
+    }
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShellError::UnknownCommand(name) => {
+                write!(f, "{}: command not found, try `help`", name)
+            }
+            ShellError::Usage(command) => write!(f, "usage: {} {}", command.name, command.usage),
+            ShellError::InvalidNumber(text) => write!(f, "{}: not a number", text),
+            ShellError::UnknownColor(name) => write!(f, "{}: unknown color", name),
+            ShellError::NotMapped(address) => write!(f, "{:#x}: not mapped", address),
+            ShellError::NotWritable(address) => write!(f, "{:#x}: not writable", address),
+        }
+    }
+}
+
+/// A shell running on one of the virtual consoles
+pub struct Shell {
+    console: usize,
+    editor: LineEditor,
+}
+
+impl Shell {
+    pub fn new(console: usize) -> Shell {
+        Shell {
+            console,
+            editor: LineEditor::new(console),
+        }
+    }
+
+    /// Index of the console the shell reads from and prints to
+    pub fn console(&self) -> usize {
+        self.console
+    }
+
+    /// Returns the command lines entered so far, oldest first
+    pub fn history(&self) -> impl Iterator<Item = &str> {
+        self.editor.history()
+    }
+
+    /// Reads and runs commands forever
+    pub async fn run(&mut self) {
+        loop {
+            match self.editor.read_line(PROMPT, &complete).await {
+                Ok(line) => {
+                    if let Err(err) = self.execute(&line) {
+                        console_println!(self.console, "{}", err);
+                    }
+                }
+                // The editor already moved to a new line
+                Err(TtyError::Interrupted) => {}
+            }
+        }
+    }
+
+    /// Runs one command line
+    pub fn execute(&self, line: &str) -> Result<(), ShellError> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&name, arguments)) = words.split_first() else {
+            return Ok(());
+        };
+        let command = find_command(name)
+            .ok_or_else(|| ShellError::UnknownCommand(name.to_string()))?;
+        (command.run)(self, arguments)
+    }
+}
+
+/// Parses a decimal number or a hexadecimal one starting with 0x
+pub fn parse_number(text: &str) -> Result<u64, ShellError> {
+    let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => text.replace('_', "").parse(),
+    };
+    parsed.map_err(|_| ShellError::InvalidNumber(text.to_string()))
+}
+
+/// Returns the words that can complete the last word of `line`: command names for the first
+/// word and for `help`, color names for `color`
+pub fn complete(line: &str) -> Vec<String> {
+    let (word, before) = match line.rfind(' ') {
+        Some(space) => (&line[space + 1..], &line[..space]),
+        None => (line, ""),
+    };
+    let previous: Vec<&str> = before.split_whitespace().collect();
+    let candidates: Vec<&str> = match previous.as_slice() {
+        [] | ["help"] => commands().iter().map(|command| command.name).collect(),
+        ["color"] | ["color", _] => commands::COLORS.iter().map(|&(name, _)| name).collect(),
+        _ => Vec::new(),
+    };
+    candidates.into_iter()
+        .filter(|candidate| candidate.starts_with(word))
+        .map(ToString::to_string)
+        .collect()
+}
@@ -0,0 +1,275 @@
+use alloc::string::ToString;
+use core::convert::TryFrom;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+use crate::block::{self, BlockDevice};
+use crate::vga_buffer::{Color, ColorCode, CONSOLES};
+use crate::{allocator, apic, ata, console_print, console_println, driver, interrupts, memory, pci,
+    power, time};
+use super::{find_command, parse_number, Command, Shell, ShellError};
+
+// The built-in commands of the shell. Each one gets the shell it runs in and the words after the
+// command name.
+
+pub(super) const COMMANDS: [Command; 14] = [
+    Command { name: "clear", usage: "", help: "clear the screen", run: clear },
+    Command { name: "color", usage: "<fg> <bg>", help: "change the text colors", run: color },
+    Command { name: "devices", usage: "", help: "show the device tree and drivers",
+              run: devices },
+    Command { name: "disks", usage: "", help: "list the ATA drives", run: disks },
+    Command { name: "help", usage: "[command]", help: "list the commands", run: help },
+    Command { name: "history", usage: "", help: "list the previous command lines",
+              run: history },
+    Command { name: "irq", usage: "", help: "show the interrupt counters", run: irq },
+    Command { name: "mem", usage: "", help: "show frame and heap usage", run: mem },
+    Command { name: "pagemap", usage: "<addr>", help: "walk the page tables for an address",
+              run: pagemap },
+    Command { name: "pci", usage: "", help: "list the PCI functions", run: pci },
+    Command { name: "peek", usage: "<addr> [count]", help: "dump memory", run: peek },
+    Command { name: "poke", usage: "<addr> <byte>", help: "write a byte to memory", run: poke },
+    Command { name: "reboot", usage: "", help: "restart the machine", run: reboot },
+    Command { name: "uptime", usage: "", help: "show the time since boot", run: uptime },
+];
+
+// `peek` dumps at most this many bytes
+const PEEK_MAX: u64 = 256;
+const PEEK_DEFAULT: u64 = 64;
+const BYTES_PER_LINE: u64 = 16;
+
+pub(super) const COLORS: [(&str, Color); 16] = [
+    ("black", Color::Black), ("blue", Color::Blue), ("green", Color::Green),
+    ("cyan", Color::Cyan), ("red", Color::Red), ("magenta", Color::Magent),
+    ("brown", Color::Brown), ("lightgray", Color::LightGray), ("darkgray", Color::DarkGray),
+    ("lightblue", Color::LightBlue), ("lightgreen", Color::LightGreen),
+    ("lightcyan", Color::LightCyan), ("lightred", Color::LightRed), ("pink", Color::Pink),
+    ("yellow", Color::Yellow), ("white", Color::White),
+];
+
+fn help(shell: &Shell, arguments: &[&str]) -> Result<(), ShellError> {
+    let console = shell.console();
+    match arguments {
+        [] => {
+            for command in COMMANDS.iter() {
+                let synopsis = alloc::format!("{} {}", command.name, command.usage);
+                console_println!(console, "  {:<24}{}", synopsis, command.help);
+            }
+        }
+        [name] => {
+            let command = find_command(name)
+                .ok_or_else(|| ShellError::UnknownCommand(name.to_string()))?;
+            console_println!(console, "{} {}: {}", command.name, command.usage, command.help);
+        }
+        _ => return Err(usage("help")),
+    }
+    Ok(())
+}
+
+fn history(shell: &Shell, _arguments: &[&str]) -> Result<(), ShellError> {
+    for (number, line) in shell.history().enumerate() {
+        console_println!(shell.console(), "{:>4}  {}", number + 1, line);
+    }
+    Ok(())
+}
+
+fn mem(shell: &Shell, _arguments: &[&str]) -> Result<(), ShellError> {
+    let (frames_used, frames_total) = memory::frame_usage();
+    let (heap_used, heap_size) = allocator::heap_usage();
+    console_println!(shell.console(), "frames: {} of {} used ({} KiB of {} KiB)", frames_used,
+                     frames_total, frames_used * 4, frames_total * 4);
+    console_println!(shell.console(), "heap:   {} of {} bytes used ({}%)", heap_used, heap_size,
+                     heap_used * 100 / heap_size.max(1));
+    Ok(())
+}
+
+fn pagemap(shell: &Shell, arguments: &[&str]) -> Result<(), ShellError> {
+    let console = shell.console();
+    let [address] = arguments else {
+        return Err(usage("pagemap"));
+    };
+    let address = virt_addr(parse_number(address)?)?;
+    let walk = memory::page_walk(address);
+    for step in walk.steps.iter() {
+        console_println!(console, "  P{}[{:>3}] -> {:#x} {:?}", step.level, step.index,
+                         step.address.as_u64(), step.flags);
+    }
+    match walk.physical {
+        Some(physical) => {
+            console_println!(console, "{:#x} -> {:#x}", address.as_u64(), physical.as_u64());
+            if let Some(flags) = walk.flags() {
+                console_println!(console, "access: {:?}", flags);
+            }
+        }
+        None => console_println!(console, "{:#x} is not mapped", address.as_u64()),
+    }
+    Ok(())
+}
+
+fn irq(shell: &Shell, _arguments: &[&str]) -> Result<(), ShellError> {
+    let console = shell.console();
+    console_println!(console, "IRQ  vector  count");
+    for irq in 0..apic::ISA_IRQS {
+        let count = interrupts::irq_count(irq);
+        if count > 0 {
+            console_println!(console, "{:>3}  {:>6}  {}", irq, interrupts::PIC_1_OFFSET + irq,
+                             count);
+        }
+    }
+    console_println!(console, "spurious: {} from the PICs, {} from the APIC",
+                     interrupts::spurious_irq_count(),
+                     interrupts::interrupt_count(apic::SPURIOUS_VECTOR));
+    Ok(())
+}
+
+fn devices(shell: &Shell, _arguments: &[&str]) -> Result<(), ShellError> {
+    let console = shell.console();
+    let devices = driver::devices();
+    // Depth first from the root (if `driver::init` ran); the tree is small enough to look the
+    // children up every time
+    let mut stack: alloc::vec::Vec<(driver::DeviceId, usize)> =
+        devices.first().map(|root| (root.id, 0)).into_iter().collect();
+    while let Some((id, depth)) = stack.pop() {
+        let device = &devices[id];
+        console_print!(console, "{:indent$}{}", "", device.name, indent = depth * 2);
+        let description = device.description();
+        if !description.is_empty() {
+            console_print!(console, "  {}", description);
+        }
+        match (device.driver(), &device.error) {
+            (Some(name), _) => console_print!(console, "  [{}]", name),
+            (None, Some(error)) => console_print!(console, "  (failed: {:?})", error),
+            (None, None) => {}
+        }
+        console_println!(console);
+        let children = devices.iter().filter(|child| child.parent == Some(id)).rev();
+        stack.extend(children.map(|child| (child.id, depth + 1)));
+    }
+    Ok(())
+}
+
+fn disks(shell: &Shell, _arguments: &[&str]) -> Result<(), ShellError> {
+    let console = shell.console();
+    for drive in ata::drives() {
+        if let Some(info) = drive.info() {
+            console_println!(console, "{}  {} MiB{}  {} ({})", drive.name(),
+                             info.sectors * block::SECTOR_SIZE as u64 / (1024 * 1024),
+                             if info.lba48 { ", LBA48" } else { "" }, info.model, info.serial);
+        }
+    }
+    Ok(())
+}
+
+fn pci(shell: &Shell, _arguments: &[&str]) -> Result<(), ShellError> {
+    let console = shell.console();
+    for device in pci::devices() {
+        console_print!(console, "{}  {:04x}:{:04x}  {}", device.address, device.vendor_id,
+                       device.device_id, device.class_name());
+        if let Some(line) = device.interrupt_line {
+            console_print!(console, ", IRQ {}", line);
+        }
+        if device.msix.is_some() {
+            console_print!(console, ", MSI-X");
+        } else if device.msi.is_some() {
+            console_print!(console, ", MSI");
+        }
+        console_println!(console);
+        for (index, bar) in device.bars.iter().enumerate() {
+            match bar {
+                Some(pci::Bar::Memory { address, size, .. }) => {
+                    console_println!(console, "    BAR{}: memory {:#x}, size {:#x}", index,
+                                     address, size);
+                }
+                Some(pci::Bar::Io { port, size }) => {
+                    console_println!(console, "    BAR{}: I/O {:#x}, size {:#x}", index, port,
+                                     size);
+                }
+                None => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+fn uptime(shell: &Shell, _arguments: &[&str]) -> Result<(), ShellError> {
+    let uptime = time::uptime();
+    let seconds = uptime.as_secs();
+    console_println!(shell.console(), "up {}:{:02}:{:02}.{:02}", seconds / 3600, seconds / 60 % 60,
+                     seconds % 60, uptime.subsec_millis() / 10);
+    Ok(())
+}
+
+fn reboot(_shell: &Shell, _arguments: &[&str]) -> Result<(), ShellError> {
+    power::reboot();
+}
+
+fn clear(shell: &Shell, _arguments: &[&str]) -> Result<(), ShellError> {
+    CONSOLES[shell.console()].lock().clear_screen();
+    Ok(())
+}
+
+fn color(shell: &Shell, arguments: &[&str]) -> Result<(), ShellError> {
+    let [foreground, background] = arguments else {
+        return Err(usage("color"));
+    };
+    let color_code = ColorCode::new(parse_color(foreground)?, parse_color(background)?);
+    CONSOLES[shell.console()].lock().set_color_code(color_code);
+    Ok(())
+}
+
+fn peek(shell: &Shell, arguments: &[&str]) -> Result<(), ShellError> {
+    let console = shell.console();
+    let (address, count) = match arguments {
+        [address] => (parse_number(address)?, PEEK_DEFAULT),
+        [address, count] => (parse_number(address)?, parse_number(count)?.min(PEEK_MAX)),
+        _ => return Err(usage("peek")),
+    };
+    let end = address.saturating_add(count);
+    // Check every page up front, reading an unmapped one would page fault
+    for page in (address & !0xfff..end).step_by(4096) {
+        if memory::page_walk(virt_addr(page)?).physical.is_none() {
+            return Err(ShellError::NotMapped(page.max(address)));
+        }
+    }
+    for line in (address..end).step_by(BYTES_PER_LINE as usize) {
+        console_print!(console, "{:016x}:", line);
+        for byte_address in line..line.saturating_add(BYTES_PER_LINE).min(end) {
+            let byte = unsafe { core::ptr::read_volatile(byte_address as *const u8) };
+            console_print!(console, " {:02x}", byte);
+        }
+        console_println!(console);
+    }
+    Ok(())
+}
+
+fn poke(_shell: &Shell, arguments: &[&str]) -> Result<(), ShellError> {
+    let [address, value] = arguments else {
+        return Err(usage("poke"));
+    };
+    let address = parse_number(address)?;
+    let value = u8::try_from(parse_number(value)?)
+        .map_err(|_| ShellError::InvalidNumber(value.to_string()))?;
+    // Any level of the tables may make the page read-only
+    let flags = memory::page_walk(virt_addr(address)?).flags()
+        .ok_or(ShellError::NotMapped(address))?;
+    if !flags.contains(PageTableFlags::WRITABLE) {
+        return Err(ShellError::NotWritable(address));
+    }
+    unsafe { core::ptr::write_volatile(address as *mut u8, value) };
+    Ok(())
+}
+
+/// Returns the usage error of the command with the given name
+fn usage(name: &str) -> ShellError {
+    ShellError::Usage(find_command(name).expect("usage of a command that doesn't exist"))
+}
+
+/// Turns a number into a virtual address, rejecting non-canonical ones
+fn virt_addr(address: u64) -> Result<VirtAddr, ShellError> {
+    VirtAddr::try_new(address).map_err(|_| ShellError::NotMapped(address))
+}
+
+fn parse_color(name: &str) -> Result<Color, ShellError> {
+    COLORS.iter()
+        .find(|(color_name, _)| color_name.eq_ignore_ascii_case(name))
+        .map(|&(_, color)| color)
+        .ok_or_else(|| ShellError::UnknownColor(name.to_string()))
+}
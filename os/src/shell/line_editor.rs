@@ -0,0 +1,448 @@
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::tty::{self, Tty, TtyError, TtyMode};
+use crate::vga_buffer::{BUFFER_WIDTH, CONSOLES};
+use crate::console_print;
+
+// A Readline-like line editor for the shell. It switches the TTY to raw mode while a line is
+// read, handles the keys itself and redraws the line in place on the last row of the console:
+//  - Left/Right, Home/End (or Ctrl+A/Ctrl+E) move the cursor, Backspace and Delete erase
+//  - Ctrl+U erases everything before the cursor, Ctrl+W the word before it
+//  - Up/Down go through the previous lines, which are kept in a ring of `HISTORY_SIZE` lines
+//  - Ctrl+R searches the history backwards for the text typed, again for an older match.
+//    Enter runs the match, Ctrl+G goes back to the line from before the search and any other
+//    key keeps the match for editing
+//  - Tab completes the word before the cursor; if several words fit, pressing it when there is
+//    nothing left in common lists them
+//  - Ctrl+C throws the line away (`TtyError::Interrupted`)
+//
+// A line longer than the row scrolls sideways, so the cursor always stays in view.
+
+/// Number of lines kept in the history
+pub const HISTORY_SIZE: usize = 32;
+
+const SEARCH_PROMPT: &str = "(reverse-i-search)";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    Tab,
+    Interrupt,
+    KillLine,
+    KillWord,
+    Search,
+    Cancel,
+    Unknown,
+}
+
+/// Reads the next key, decoding the escape sequences the keyboard sends for special keys
+async fn read_key(tty: &Tty) -> Key {
+    match tty.read_char().await {
+        '\n' | '\r' => Key::Enter,
+        '\x08' | '\x7f' => Key::Backspace,
+        '\t' => Key::Tab,
+        '\x01' => Key::Home,
+        '\x03' => Key::Interrupt,
+        '\x05' => Key::End,
+        '\x07' => Key::Cancel,
+        '\x12' => Key::Search,
+        '\x15' => Key::KillLine,
+        '\x17' => Key::KillWord,
+        '\x1b' => read_escape_sequence(tty).await,
+        character if character.is_control() => Key::Unknown,
+        character => Key::Char(character),
+    }
+}
+
+/// Reads the rest of an escape sequence: ESC [ or ESC O, parameters and a final byte
+async fn read_escape_sequence(tty: &Tty) -> Key {
+    let introducer = tty.read_char().await;
+    if introducer != '[' && introducer != 'O' {
+        return Key::Unknown;
+    }
+    let mut parameters = String::new();
+    let last = loop {
+        match tty.read_char().await {
+            character @ ('0'..='9' | ';') => parameters.push(character),
+            character => break character,
+        }
+    };
+    match (last, parameters.as_str()) {
+        ('A', _) => Key::Up,
+        ('B', _) => Key::Down,
+        ('C', _) => Key::Right,
+        ('D', _) => Key::Left,
+        ('H', _) | ('~', "1") | ('~', "7") => Key::Home,
+        ('F', _) | ('~', "4") | ('~', "8") => Key::End,
+        ('~', "3") => Key::Delete,
+        _ => Key::Unknown,
+    }
+}
+
+/// Reads lines from the TTY of a console, with editing, history and completion
+pub struct LineEditor {
+    console: usize,
+    history: VecDeque<String>, // oldest first
+}
+
+impl LineEditor {
+    pub fn new(console: usize) -> LineEditor {
+        LineEditor {
+            console,
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Returns the previous lines, oldest first
+    pub fn history(&self) -> impl Iterator<Item = &str> {
+        self.history.iter().map(String::as_str)
+    }
+
+    /// Adds a line to the history, dropping the oldest one if the history is full
+    ///
+    /// Blank lines and repeats of the last line are not added.
+    pub fn add_history(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.back().map(String::as_str) == Some(line) {
+            return;
+        }
+        if self.history.len() == HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back(String::from(line));
+    }
+
+    /// Shows `prompt`, lets a line be edited and returns it once Enter is pressed
+    ///
+    /// `complete` gets the line up to the cursor and returns the words that can replace the
+    /// last word of it. The line is added to the history.
+    pub async fn read_line(&mut self, prompt: &str, complete: &dyn Fn(&str) -> Vec<String>)
+                           -> Result<String, TtyError> {
+        let tty = tty::tty(self.console);
+        // Also restores the TTY if the future is dropped before the line is complete
+        let raw = RawMode::enter(tty);
+
+        let mut edit = Edit::new(self.console, prompt);
+        let result = loop {
+            let key = read_key(tty).await;
+            if let Some(result) = edit.handle(key, &self.history, complete) {
+                break result;
+            }
+        };
+
+        drop(raw);
+        if let Ok(line) = result.as_ref() {
+            self.add_history(line);
+        }
+        result
+    }
+}
+
+/// Keeps a TTY in raw mode without echo, and puts its previous mode and echo back when dropped
+struct RawMode {
+    tty: &'static Tty,
+    mode: TtyMode,
+    echo: bool,
+}
+
+impl RawMode {
+    fn enter(tty: &'static Tty) -> RawMode {
+        let raw = RawMode { tty, mode: tty.mode(), echo: tty.echo() };
+        tty.set_mode(TtyMode::Raw);
+        tty.set_echo(false);
+        raw
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        self.tty.set_mode(self.mode);
+        self.tty.set_echo(self.echo);
+    }
+}
+
+/// A reverse search through the history
+struct Search {
+    query: String,
+    found: Option<usize>, // index of the matching history line
+    matched: Vec<char>, // the matching history line
+    saved: Vec<char>, // the line from before the search, for Ctrl+G
+}
+
+/// The state of the line being edited
+struct Edit<'a> {
+    console: usize,
+    prompt: &'a str,
+    start: usize, // column the prompt starts at
+    view: usize, // index of the first character shown, for lines longer than the row
+    line: Vec<char>,
+    cursor: usize,
+    history_index: Option<usize>, // the history line shown, None for the line being typed
+    draft: Vec<char>, // the line being typed, while the history is shown
+    search: Option<Search>,
+}
+
+impl<'a> Edit<'a> {
+    fn new(console: usize, prompt: &'a str) -> Edit<'a> {
+        // Start on a new row if there's not much room left on the current one
+        let column = CONSOLES[console].lock().column();
+        if column > BUFFER_WIDTH / 2 {
+            console_print!(console, "\n");
+        }
+        let mut edit = Edit {
+            console,
+            prompt,
+            start: CONSOLES[console].lock().column(),
+            view: 0,
+            line: Vec::new(),
+            cursor: 0,
+            history_index: None,
+            draft: Vec::new(),
+            search: None,
+        };
+        edit.render();
+        edit
+    }
+
+    /// Handles a key. Returns the result of `read_line` once the line is done
+    fn handle(&mut self, key: Key, history: &VecDeque<String>,
+              complete: &dyn Fn(&str) -> Vec<String>) -> Option<Result<String, TtyError>> {
+        if self.search.is_some() && !self.handle_search(key, history) {
+            self.render();
+            return None;
+        }
+        match key {
+            Key::Enter => return Some(Ok(self.finish("\n"))),
+            Key::Interrupt => {
+                self.finish("^C\n");
+                return Some(Err(TtyError::Interrupted));
+            }
+            Key::Char(character) => {
+                self.line.insert(self.cursor, character);
+                self.cursor += 1;
+            }
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            }
+            Key::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            }
+            Key::Left => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right => self.cursor = (self.cursor + 1).min(self.line.len()),
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = self.line.len(),
+            Key::KillLine => {
+                self.line.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            Key::KillWord => {
+                let mut start = self.cursor;
+                while start > 0 && self.line[start - 1] == ' ' {
+                    start -= 1;
+                }
+                while start > 0 && self.line[start - 1] != ' ' {
+                    start -= 1;
+                }
+                self.line.drain(start..self.cursor);
+                self.cursor = start;
+            }
+            Key::Up => self.history_previous(history),
+            Key::Down => self.history_next(history),
+            Key::Tab => self.complete(complete),
+            Key::Search => {
+                self.search = Some(Search {
+                    query: String::new(),
+                    found: None,
+                    matched: Vec::new(),
+                    saved: self.line.clone(),
+                });
+                self.view = 0;
+            }
+            _ => {}
+        }
+        self.render();
+        None
+    }
+
+    /// Handles a key during a reverse search. Returns false if the key was used up by the
+    /// search, true if it ended the search and still needs to be handled on the line
+    fn handle_search(&mut self, key: Key, history: &VecDeque<String>) -> bool {
+        let Some(search) = self.search.as_mut() else {
+            return true;
+        };
+        match key {
+            Key::Char(character) => {
+                search.query.push(character);
+                // The current match is kept as long as it still matches
+                let from = search.found.unwrap_or(history.len());
+                search.found = find_older(history, &search.query, from + 1);
+            }
+            Key::Backspace => {
+                search.query.pop();
+                search.found = find_older(history, &search.query, history.len());
+            }
+            Key::Search => {
+                let from = search.found.unwrap_or(history.len());
+                if let Some(older) = find_older(history, &search.query, from) {
+                    search.found = Some(older);
+                }
+            }
+            Key::Cancel => {
+                self.line = core::mem::take(&mut search.saved);
+                self.cursor = self.line.len();
+                self.search = None;
+                self.view = 0;
+                return false;
+            }
+            _ => {
+                if search.found.is_some() {
+                    self.line = core::mem::take(&mut search.matched);
+                    self.cursor = self.line.len();
+                    self.history_index = None;
+                }
+                self.search = None;
+                self.view = 0;
+                return true;
+            }
+        }
+        search.matched = search.found
+            .map_or_else(Vec::new, |found| history[found].chars().collect());
+        false
+    }
+
+    fn history_previous(&mut self, history: &VecDeque<String>) {
+        let index = match self.history_index {
+            None if history.is_empty() => return,
+            None => {
+                self.draft = core::mem::take(&mut self.line);
+                history.len() - 1
+            }
+            Some(0) => return,
+            Some(index) => index - 1,
+        };
+        self.history_index = Some(index);
+        self.line = history[index].chars().collect();
+        self.cursor = self.line.len();
+    }
+
+    fn history_next(&mut self, history: &VecDeque<String>) {
+        let Some(index) = self.history_index else {
+            return;
+        };
+        if index + 1 < history.len() {
+            self.history_index = Some(index + 1);
+            self.line = history[index + 1].chars().collect();
+        } else {
+            self.history_index = None;
+            self.line = core::mem::take(&mut self.draft);
+        }
+        self.cursor = self.line.len();
+    }
+
+    /// Completes the word before the cursor
+    fn complete(&mut self, complete: &dyn Fn(&str) -> Vec<String>) {
+        let before: String = self.line[..self.cursor].iter().collect();
+        let word_start = self.line[..self.cursor].iter().rposition(|&character| character == ' ')
+            .map_or(0, |space| space + 1);
+        let word: String = self.line[word_start..self.cursor].iter().collect();
+        let candidates: Vec<String> = complete(&before).into_iter()
+            .filter(|candidate| candidate.starts_with(word.as_str()))
+            .collect();
+        let Some(first) = candidates.first() else {
+            return;
+        };
+
+        let common = candidates.iter().fold(first.as_str(), |common, candidate| {
+            let length = common.char_indices().zip(candidate.chars())
+                .find(|((_, a), b)| a != b)
+                .map_or(common.len().min(candidate.len()), |((index, _), _)| index);
+            &common[..length]
+        });
+        let mut insert: Vec<char> = common[word.len()..].chars().collect();
+        if candidates.len() == 1 && self.line.get(self.cursor) != Some(&' ') {
+            insert.push(' ');
+        }
+        if insert.is_empty() && candidates.len() > 1 {
+            // Nothing left in common, show what fits and start over on a new row
+            self.render_end();
+            let mut list = String::from("\n");
+            for candidate in candidates.iter() {
+                list.push_str(candidate);
+                list.push_str("  ");
+            }
+            console_print!(self.console, "{}\n", list.trim_end());
+            self.start = 0;
+            return;
+        }
+        let length = insert.len();
+        self.line.splice(self.cursor..self.cursor, insert);
+        self.cursor += length;
+    }
+
+    /// Shows the whole line with the cursor at its end and moves to a new line, for when the
+    /// line is done
+    fn finish(&mut self, end: &str) -> String {
+        self.render_end();
+        console_print!(self.console, "{}", end);
+        self.line.iter().collect()
+    }
+
+    fn render_end(&mut self) {
+        self.cursor = self.line.len();
+        self.render();
+    }
+
+    /// Redraws the prompt and the visible part of the line, and puts the cursor in place
+    fn render(&mut self) {
+        let (label, text, cursor) = match self.search.as_ref() {
+            Some(search) => {
+                let label = format!("{}`{}': ", SEARCH_PROMPT, search.query);
+                (label, search.matched.clone(), search.matched.len())
+            }
+            None => (String::from(self.prompt), self.line.clone(), self.cursor),
+        };
+
+        // The last column stays free for the cursor at the end of the line
+        let room = BUFFER_WIDTH - 1 - self.start;
+        let label: Vec<char> = label.chars().take(room).collect();
+        let width = room - label.len();
+        if cursor < self.view {
+            self.view = cursor;
+        } else if cursor > self.view + width {
+            self.view = cursor - width;
+        }
+        let shown = &text[self.view.min(text.len())..(self.view + width).min(text.len())];
+
+        let mut writer = CONSOLES[self.console].lock();
+        writer.set_column(self.start);
+        for &character in label.iter().chain(shown.iter()) {
+            writer.write_byte(glyph(character));
+        }
+        writer.clear_to_end_of_row();
+        writer.set_column(self.start + label.len() + cursor - self.view);
+    }
+}
+
+/// Returns the index of the newest history line before `before` that contains `query`
+fn find_older(history: &VecDeque<String>, query: &str, before: usize) -> Option<usize> {
+    history.iter().take(before).rposition(|line| line.contains(query))
+}
+
+/// Returns the code page 437 byte a character is shown as
+fn glyph(character: char) -> u8 {
+    match character {
+        ' '..='~' => character as u8,
+        _ => 0xfe,
+    }
+}
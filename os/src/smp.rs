@@ -0,0 +1,310 @@
+use alloc::vec;
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags,
+    PhysFrame, Size4KiB};
+use x86_64::structures::paging::mapper::{MapToError, Translate};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::{acpi, apic, gdt, hlt_loop, interrupts, memory, percpu, time};
+
+// This file starts the other CPUs (Application Processors, APs) next to the one the firmware
+// booted us on (the Bootstrap Processor, BSP). An AP is started with an INIT IPI followed by two
+// Startup IPIs, after which it runs in 16 bit real mode at the start of a 4KiB page in the first
+// MiB of memory. The trampoline below is copied there: it switches straight to long mode using
+// the kernel page tables (in which the page is identity mapped) and calls `ap_main` on a stack
+// allocated for the AP.
+//
+// The trampoline can't know where it is copied to at compile time, so the 16 bit code only uses
+// offsets from the start of the page (CS points to it), the 64 bit code is RIP relative, and the
+// two absolute addresses are filled in when copying. The parameters at the end are set for each
+// AP before it is started, which is why the APs are started one after another.
+//
+// An AP that is late may still be running the trampoline after the BSP gave up on it and set the
+// parameters for the next AP. So the index of the AP to start doubles as a claim: the AP swaps
+// it for `SLOT_TAKEN` before it touches the stack, and the BSP does the same when it times out.
+// Whoever loses parks the CPU without using any memory of the slot.
+
+core::arch::global_asm!(r#"
+.code16
+.global smp_trampoline_start
+smp_trampoline_start:
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+    lgdt [gdtr_offset]
+
+    // Enable Physical Address Extension, which long mode requires
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+    mov eax, dword ptr [cr3_offset]
+    mov cr3, eax
+
+    // Enable long mode and the no-execute bit in the EFER MSR
+    mov ecx, 0xc0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+
+    // Enable paging and protected mode at once, which activates long mode
+    mov eax, cr0
+    or eax, 0x80000001
+    mov cr0, eax
+
+    // Far jump to the 64 bit code segment: jmp 0x08:smp_trampoline_long_mode
+    .byte 0x66, 0xea
+.global smp_trampoline_long_mode_address
+smp_trampoline_long_mode_address:
+    .long 0
+    .word 0x08
+
+.code64
+smp_trampoline_long_mode:
+    xor eax, eax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov fs, ax
+    mov gs, ax
+    // Claim the slot: swap the index for SLOT_TAKEN unless someone else did
+    mov rax, qword ptr [rip + smp_trampoline_cpu]
+    cmp rax, -1
+    je smp_trampoline_park
+    mov rcx, -1
+    lock cmpxchg qword ptr [rip + smp_trampoline_cpu], rcx
+    jne smp_trampoline_park
+    mov rdi, rax
+    mov rsp, qword ptr [rip + smp_trampoline_stack_top]
+    mov rax, qword ptr [rip + smp_trampoline_entry]
+    call rax
+    ud2
+smp_trampoline_park:
+    cli
+    hlt
+    jmp smp_trampoline_park
+
+.align 8
+smp_trampoline_gdt:
+    .quad 0
+    .quad 0x00af9a000000ffff // 64 bit kernel code segment
+.global smp_trampoline_gdtr
+smp_trampoline_gdtr:
+    .word 15
+    .long 0
+.align 8
+.global smp_trampoline_cr3
+smp_trampoline_cr3:
+    .quad 0
+.global smp_trampoline_stack_top
+smp_trampoline_stack_top:
+    .quad 0
+.global smp_trampoline_entry
+smp_trampoline_entry:
+    .quad 0
+.global smp_trampoline_cpu
+smp_trampoline_cpu:
+    .quad 0
+.global smp_trampoline_end
+smp_trampoline_end:
+
+.set gdtr_offset, smp_trampoline_gdtr - smp_trampoline_start
+.set cr3_offset, smp_trampoline_cr3 - smp_trampoline_start
+"#);
+
+extern "C" {
+    static smp_trampoline_start: u8;
+    static smp_trampoline_long_mode_address: u8;
+    static smp_trampoline_gdtr: u8;
+    static smp_trampoline_cr3: u8;
+    static smp_trampoline_stack_top: u8;
+    static smp_trampoline_entry: u8;
+    static smp_trampoline_cpu: u8;
+    static smp_trampoline_end: u8;
+}
+
+const AP_STACK_SIZE: usize = 4096 * 16;
+
+// How long to wait for an AP to start before giving up on it
+const AP_START_TIMEOUT_MS: u64 = 100;
+
+// Replaces the index in the trampoline once an AP or the BSP has claimed the slot (-1 in the
+// trampoline code)
+const SLOT_TAKEN: u64 = u64::MAX;
+
+static CPUS_ONLINE: AtomicUsize = AtomicUsize::new(1); // the BSP is always online
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub enum SmpError {
+    /// The APIC is needed to start the other CPUs, but is not enabled
+    NoApic,
+    /// The ACPI MADT, which lists the CPUs, was not found
+    NoMadt,
+    /// There is no free page in the first MiB to start the CPUs in
+    NoLowMemory,
+    /// The trampoline could not be identity mapped
+    Mapping(MapToError<Size4KiB>),
+    /// The AP with this APIC ID took its slot but never reported in. The APs after it are not
+    /// started, since it may still come up using the trampoline
+    ApStuck(u32),
+}
+
+/// Returns the number of CPUs that are running
+pub fn cpu_count() -> usize {
+    CPUS_ONLINE.load(Ordering::Relaxed)
+}
+
+/// Starts all enabled CPUs the ACPI MADT lists and returns the number of CPUs online
+///
+/// Must be called after `apic::init` and `acpi::init`, with interrupts enabled. The APs load their
+/// own GDT and TSS and the shared IDT, and then wait for interrupts.
+pub fn init(
+    memory_map: &MemoryMap,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<usize, SmpError> {
+    if !apic::is_enabled() {
+        return Err(SmpError::NoApic);
+    }
+    let madt = acpi::tables().and_then(|tables| tables.madt.as_ref()).ok_or(SmpError::NoMadt)?;
+    let frame = trampoline_frame(memory_map).ok_or(SmpError::NoLowMemory)?;
+    identity_map(frame, mapper, frame_allocator)?;
+    let trampoline = unsafe { copy_trampoline(frame) };
+
+    let bsp = apic::local_apic_id();
+    for processor in madt.processors.iter().filter(|processor| processor.enabled) {
+        // APIC IDs above 255 need x2APIC mode, which we don't support
+        if processor.apic_id == bsp || processor.apic_id > 0xff {
+            continue;
+        }
+        if cpu_count() >= percpu::MAX_CPUS {
+            break;
+        }
+        let stack = Box::leak(vec![0u8; AP_STACK_SIZE].into_boxed_slice());
+        let stack_top = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xf;
+        let entry: extern "C" fn(u64) -> ! = ap_main;
+        let slot = unsafe {
+            write_parameter(trampoline, &smp_trampoline_stack_top, stack_top);
+            write_parameter(trampoline, &smp_trampoline_entry, entry as usize as u64);
+            &*(trampoline + offset(&smp_trampoline_cpu)).as_ptr::<AtomicU64>()
+        };
+        AP_STARTED.store(false, Ordering::SeqCst);
+        // Opening the slot last publishes the other parameters with it
+        slot.store(cpu_count() as u64, Ordering::SeqCst);
+        start_ap(processor.apic_id, frame, slot)?;
+    }
+    Ok(cpu_count())
+}
+
+/// Sends the INIT-SIPI-SIPI sequence and waits for the AP to report in
+///
+/// An AP that has not claimed `slot` in time is given up on, one that has is waited for a while
+/// longer.
+fn start_ap(apic_id: u32, frame: PhysFrame, slot: &AtomicU64) -> Result<(), SmpError> {
+    let page = (frame.start_address().as_u64() >> 12) as u8;
+    apic::send_init(apic_id);
+    time::sleep(10);
+    for _ in 0..2 {
+        apic::send_startup(apic_id, page);
+        let start = time::uptime();
+        while time::uptime() - start < core::time::Duration::from_millis(AP_START_TIMEOUT_MS) {
+            if AP_STARTED.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            x86_64::instructions::hlt();
+        }
+    }
+    let index = slot.load(Ordering::SeqCst);
+    if index != SLOT_TAKEN && slot.compare_exchange(index, SLOT_TAKEN, Ordering::SeqCst,
+                                                   Ordering::SeqCst).is_ok() {
+        return Ok(());
+    }
+    // The AP claimed the slot and is on its way, its stack and index are no longer ours
+    let start = time::uptime();
+    while time::uptime() - start < core::time::Duration::from_millis(AP_START_TIMEOUT_MS) {
+        if AP_STARTED.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        x86_64::instructions::hlt();
+    }
+    Err(SmpError::ApStuck(apic_id))
+}
+
+/// Entry point of the APs, called by the trampoline with the index of the slot the AP claimed
+extern "C" fn ap_main(cpu: u64) -> ! {
+    gdt::init_cpu();
+    percpu::init(cpu as usize, percpu::allocate_scratch_stack());
+    interrupts::init_idt();
+    apic::init_cpu();
+    CPUS_ONLINE.fetch_add(1, Ordering::SeqCst);
+    AP_STARTED.store(true, Ordering::SeqCst);
+    x86_64::instructions::interrupts::enable();
+    hlt_loop();
+}
+
+/// Returns a usable frame in the first MiB that the frame allocator does not hand out
+fn trampoline_frame(memory_map: &MemoryMap) -> Option<PhysFrame> {
+    memory_map.iter()
+        .filter(|region| region.region_type == MemoryRegionType::Usable)
+        .flat_map(|region| (region.range.start_addr()..region.range.end_addr()).step_by(4096))
+        .find(|&address| address != 0 && address < memory::LOW_MEMORY_END)
+        .map(|address| PhysFrame::containing_address(PhysAddr::new(address)))
+}
+
+/// Maps the page at the same virtual address as the physical address of `frame`, where the
+/// trampoline runs right after it enables paging
+fn identity_map(
+    frame: PhysFrame,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), SmpError> {
+    let page: Page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    match mapper.translate_addr(page.start_address()) {
+        Some(address) if address == frame.start_address() => return Ok(()),
+        Some(_) => return Err(SmpError::Mapping(MapToError::PageAlreadyMapped(frame))),
+        None => {}
+    }
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe {
+        mapper.map_to(page, frame, flags, frame_allocator).map_err(SmpError::Mapping)?.flush();
+    }
+    Ok(())
+}
+
+/// Copies the trampoline to `frame`, fills in its absolute addresses and the page tables to use,
+/// and returns the virtual address it was copied to
+unsafe fn copy_trampoline(frame: PhysFrame) -> VirtAddr {
+    let start = &smp_trampoline_start as *const u8;
+    let length = &smp_trampoline_end as *const u8 as usize - start as usize;
+    assert!(length <= 4096, "SMP trampoline does not fit into a page");
+
+    let trampoline = memory::phys_to_virt(frame.start_address());
+    core::ptr::copy_nonoverlapping(start, trampoline.as_mut_ptr::<u8>(), length);
+
+    let base = frame.start_address().as_u64();
+    let gdt_offset = offset(&smp_trampoline_gdtr) - 16; // the GDT is right before its pointer
+    let long_mode_offset = offset(&smp_trampoline_long_mode_address) + 6; // after the far jump
+    let gdtr_base = trampoline + offset(&smp_trampoline_gdtr) + 2u64;
+    core::ptr::write_unaligned(gdtr_base.as_mut_ptr::<u32>(), (base + gdt_offset) as u32);
+    let long_mode_address = trampoline + offset(&smp_trampoline_long_mode_address);
+    core::ptr::write_unaligned(long_mode_address.as_mut_ptr::<u32>(),
+                               (base + long_mode_offset) as u32);
+    write_parameter(trampoline, &smp_trampoline_cr3,
+                    Cr3::read().0.start_address().as_u64());
+    trampoline
+}
+
+/// Sets the trampoline parameter at `symbol` in the trampoline copied to `trampoline`
+unsafe fn write_parameter(trampoline: VirtAddr, symbol: &u8, value: u64) {
+    let address = trampoline + offset(symbol);
+    core::ptr::write_volatile(address.as_mut_ptr::<u64>(), value);
+}
+
+/// Returns the offset of a trampoline symbol from the start of the trampoline
+fn offset(symbol: &u8) -> u64 {
+    let start = unsafe { &smp_trampoline_start } as *const u8;
+    symbol as *const u8 as u64 - start as u64
+}
@@ -0,0 +1,32 @@
+// This file collects the synchronization primitives of the kernel. `spin::Mutex` is fine for data
+// that is never touched by interrupt handlers, everything else should use the primitives here.
+//
+// `IrqSpinLock` is for short critical sections that interrupt handlers share. The others put
+// waiting tasks to sleep in a `WaitQueue` rather than spinning, and are used with `.await`
+// (from a task, or from plain code through `block_on`).
+//
+// Interrupt handlers that only hand data to a task don't need a lock at all: they push it into an
+// `ArrayQueue` and wake the task through an `AtomicWaker`. Tables that interrupt handlers read
+// and that rarely change go into an `RcuCell`.
+
+pub mod irq_spin_lock;
+pub mod wait_queue;
+pub mod mutex;
+pub mod semaphore;
+pub mod condvar;
+pub mod block_on;
+pub mod join;
+pub mod array_queue;
+pub mod atomic_waker;
+pub mod rcu;
+
+pub use irq_spin_lock::{IrqSpinLock, IrqSpinLockGuard};
+pub use wait_queue::WaitQueue;
+pub use mutex::{Mutex, MutexGuard};
+pub use semaphore::{Semaphore, SemaphorePermit};
+pub use condvar::CondVar;
+pub use block_on::block_on;
+pub use join::join;
+pub use array_queue::ArrayQueue;
+pub use atomic_waker::AtomicWaker;
+pub use rcu::{RcuCell, RcuReadGuard};
@@ -0,0 +1,166 @@
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// A bounded queue that any number of producers and consumers can use at the same time without
+// locks (Dmitry Vyukov's bounded MPMC queue). Its storage is part of the queue, so it can be a
+// static and never allocates, which makes it the way to pass data out of interrupt handlers.
+//
+// Every slot has a sequence number that tells whose turn it is. For the n-th push or pop overall
+// (its position), slot position % N is used:
+//  - sequence == position       the slot is empty and waits for the push at `position`
+//  - sequence == position + 1   the slot holds the value pushed at `position`, waiting for its pop
+// A pop at `position` sets the sequence to position + N, the next push to use this slot.
+
+struct Slot<T> {
+    // Stored relative to the index of the slot, so all slots start out at 0 and `new` can be const
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+pub struct ArrayQueue<T, const N: usize> {
+    slots: [Slot<T>; N],
+    head: AtomicUsize, // position of the next pop
+    tail: AtomicUsize, // position of the next push
+}
+
+unsafe impl<T: Send, const N: usize> Sync for ArrayQueue<T, N> {}
+unsafe impl<T: Send, const N: usize> Send for ArrayQueue<T, N> {}
+
+impl<T, const N: usize> ArrayQueue<T, N> {
+    /// Creates an empty queue with room for `N` values
+    pub const fn new() -> ArrayQueue<T, N> {
+        assert!(N > 0, "ArrayQueue needs room for at least one value");
+        ArrayQueue {
+            slots: [const { Slot {
+                sequence: AtomicUsize::new(0),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+            } }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    fn sequence(&self, position: usize) -> usize {
+        let index = position % N;
+        self.slots[index].sequence.load(Ordering::Acquire).wrapping_add(index)
+    }
+
+    fn set_sequence(&self, position: usize, sequence: usize) {
+        let index = position % N;
+        self.slots[index].sequence.store(sequence.wrapping_sub(index), Ordering::Release);
+    }
+
+    /// Adds `value` to the back of the queue, or gives it back if the queue is full
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut position = self.tail.load(Ordering::Relaxed);
+        loop {
+            let difference = self.sequence(position).wrapping_sub(position) as isize;
+            if difference == 0 {
+                // The slot is free, try to claim it
+                match self.tail.compare_exchange_weak(position, position.wrapping_add(1),
+                                                      Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        unsafe { (*self.slots[position % N].value.get()).write(value) };
+                        self.set_sequence(position, position.wrapping_add(1));
+                        return Ok(());
+                    }
+                    Err(current) => position = current,
+                }
+            } else if difference < 0 {
+                // The slot still holds the value pushed N positions ago
+                return Err(value);
+            } else {
+                // Another producer claimed the slot first
+                position = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Removes the value at the front of the queue
+    pub fn pop(&self) -> Option<T> {
+        let mut position = self.head.load(Ordering::Relaxed);
+        loop {
+            let difference =
+                self.sequence(position).wrapping_sub(position.wrapping_add(1)) as isize;
+            if difference == 0 {
+                // The slot holds a value, try to claim it
+                match self.head.compare_exchange_weak(position, position.wrapping_add(1),
+                                                      Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        let value = unsafe {
+                            (*self.slots[position % N].value.get()).assume_init_read()
+                        };
+                        self.set_sequence(position, position.wrapping_add(N));
+                        return Some(value);
+                    }
+                    Err(current) => position = current,
+                }
+            } else if difference < 0 {
+                // Nothing was pushed to the slot yet
+                return None;
+            } else {
+                // Another consumer claimed the slot first
+                position = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Returns the number of values in the queue, which may already be outdated when it returns
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Relaxed);
+        tail.wrapping_sub(head).min(N)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == N
+    }
+}
+
+impl<T, const N: usize> Default for ArrayQueue<T, N> {
+    fn default() -> Self {
+        ArrayQueue::new()
+    }
+}
+
+impl<T, const N: usize> Drop for ArrayQueue<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[test_case]
+fn test_push_pop_in_order() {
+    let queue: ArrayQueue<u8, 4> = ArrayQueue::new();
+    assert_eq!(queue.pop(), None);
+    for value in 0..4 {
+        assert_eq!(queue.push(value), Ok(()));
+    }
+    assert!(queue.is_full());
+    assert_eq!(queue.push(4), Err(4));
+    assert_eq!(queue.pop(), Some(0));
+    assert_eq!(queue.push(4), Ok(()));
+    for value in 1..5 {
+        assert_eq!(queue.pop(), Some(value));
+    }
+    assert!(queue.is_empty());
+}
+
+#[test_case]
+fn test_wraps_around() {
+    let queue: ArrayQueue<usize, 3> = ArrayQueue::new();
+    for value in 0..100 {
+        queue.push(value).unwrap();
+        assert_eq!(queue.pop(), Some(value));
+    }
+    assert_eq!(queue.len(), 0);
+}
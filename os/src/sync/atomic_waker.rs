@@ -0,0 +1,86 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::Waker;
+
+// Holds the waker of a single task, which interrupt handlers can wake without taking a lock. The
+// task registers its waker before it checks for new data (e.g. in an `ArrayQueue`), the producer
+// wakes it after adding data. A small state machine resolves the race between the two:
+//
+//  WAITING ------register------> REGISTERING ----done----> WAITING
+//     |                               | wake (meanwhile)
+//     |                               v
+//     +---------wake------------> WAKING: register wakes the new waker itself
+
+const WAITING: usize = 0;
+const REGISTERING: usize = 1;
+const WAKING: usize = 2;
+
+pub struct AtomicWaker {
+    state: AtomicUsize,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+// The state machine makes sure only one side accesses `waker` at a time
+unsafe impl Sync for AtomicWaker {}
+unsafe impl Send for AtomicWaker {}
+
+impl AtomicWaker {
+    pub const fn new() -> AtomicWaker {
+        AtomicWaker { state: AtomicUsize::new(WAITING), waker: UnsafeCell::new(None) }
+    }
+
+    /// Makes `waker` the one that `wake` wakes
+    ///
+    /// Only one task may register at a time, a concurrent registration is ignored.
+    pub fn register(&self, waker: &Waker) {
+        match self.state.compare_exchange(WAITING, REGISTERING, Ordering::Acquire,
+                                          Ordering::Acquire) {
+            Ok(_) => {
+                unsafe {
+                    let slot = &mut *self.waker.get();
+                    if !slot.as_ref().is_some_and(|old| old.will_wake(waker)) {
+                        *slot = Some(waker.clone());
+                    }
+                }
+                if self.state.compare_exchange(REGISTERING, WAITING, Ordering::AcqRel,
+                                               Ordering::Acquire).is_err() {
+                    // `wake` was called while we registered, and left the waking to us
+                    let waker = unsafe { (*self.waker.get()).take() };
+                    self.state.store(WAITING, Ordering::Release);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+            }
+            Err(state) if state & WAKING != 0 => waker.wake_by_ref(),
+            Err(_) => {}
+        }
+    }
+
+    /// Wakes the registered waker, if any
+    pub fn wake(&self) {
+        if let Some(waker) = self.take() {
+            waker.wake();
+        }
+    }
+
+    /// Removes and returns the registered waker
+    pub fn take(&self) -> Option<Waker> {
+        match self.state.fetch_or(WAKING, Ordering::AcqRel) {
+            WAITING => {
+                let waker = unsafe { (*self.waker.get()).take() };
+                self.state.fetch_and(!WAKING, Ordering::Release);
+                waker
+            }
+            // Registration in progress, which will see WAKING and wake the new waker. Or another
+            // `wake` is in progress
+            _ => None,
+        }
+    }
+}
+
+impl Default for AtomicWaker {
+    fn default() -> Self {
+        AtomicWaker::new()
+    }
+}
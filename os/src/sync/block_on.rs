@@ -0,0 +1,48 @@
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use x86_64::instructions::interrupts;
+
+// Runs a future to completion on the calling CPU. While the future can't make progress, the CPU
+// is halted until the next interrupt, which is what usually makes the awaited thing happen (a key
+// press, a timer tick, a disk completion). A waker called from another CPU only takes effect on
+// the next interrupt of this CPU, e.g. the next timer tick.
+
+struct BlockOnWaker {
+    woken: AtomicBool,
+}
+
+impl Wake for BlockOnWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+    }
+}
+
+/// Polls `future` until it completes, halting the CPU while it waits. Interrupts must be enabled
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let state = Arc::new(BlockOnWaker { woken: AtomicBool::new(false) });
+    let waker = Waker::from(state.clone());
+    let mut context = Context::from_waker(&waker);
+    loop {
+        state.woken.store(false, Ordering::Release);
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        // Check for a wakeup with interrupts disabled, so one that comes in between can't be
+        // slept through. `enable_and_hlt` enables them right before halting
+        interrupts::disable();
+        if state.woken.load(Ordering::Acquire) {
+            interrupts::enable();
+        } else {
+            interrupts::enable_and_hlt();
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use super::{MutexGuard, WaitQueue};
+
+// A condition variable: `wait` unlocks a `Mutex` and sleeps until another task calls `notify_one`
+// or `notify_all`, then locks the mutex again. As usual, waiters have to check their condition
+// again after waking up, since another task may have changed it in the meantime.
+
+pub struct CondVar {
+    // Incremented by every notification, so a waiter can tell whether it was notified since it
+    // started waiting
+    generation: AtomicU64,
+    waiters: WaitQueue,
+}
+
+impl CondVar {
+    pub const fn new() -> CondVar {
+        CondVar { generation: AtomicU64::new(0), waiters: WaitQueue::new() }
+    }
+
+    /// Unlocks the mutex of `guard`, waits for a notification and locks the mutex again
+    pub async fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex();
+        // Notifications sent after this point wake us, even those sent before we start waiting
+        let generation = self.generation.load(Ordering::Acquire);
+        drop(guard);
+        self.waiters
+            .wait_until(|| (self.generation.load(Ordering::Acquire) != generation).then_some(()))
+            .await;
+        mutex.lock().await
+    }
+
+    /// Wakes one waiting task
+    pub fn notify_one(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    /// Wakes all waiting tasks
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.wake_all();
+    }
+}
+
+impl Default for CondVar {
+    fn default() -> Self {
+        CondVar::new()
+    }
+}
@@ -0,0 +1,198 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+#[cfg(feature = "lock_debug")]
+use core::panic::Location;
+#[cfg(feature = "lock_debug")]
+use core::sync::atomic::{AtomicPtr, AtomicUsize};
+
+// A spinlock that disables interrupts while it is held. With a plain spinlock, an interrupt
+// handler that takes a lock the interrupted code holds spins forever, since the interrupted code
+// can't continue until the handler returns. Disabling interrupts on the CPU that holds the lock
+// rules this out; other CPUs can still take the lock once it is released.
+//
+// Built with `--features lock_debug`, every lock remembers where it was taken, and taking it
+// panics with that location if the lock is already held by the same CPU (which would never be
+// released) or if it stays held for too long.
+
+// Number of attempts after which waiting for a lock counts as a deadlock, in `lock_debug` builds.
+// Interrupts are disabled while spinning, so the timer can't be used to measure the time
+#[cfg(feature = "lock_debug")]
+const SPIN_LIMIT: u64 = 100_000_000;
+
+#[cfg(feature = "lock_debug")]
+const NO_CPU: usize = usize::MAX;
+
+pub struct IrqSpinLock<T: ?Sized> {
+    locked: AtomicBool,
+    #[cfg(feature = "lock_debug")]
+    owner: AtomicPtr<Location<'static>>, // where the lock was taken, null while it is free
+    #[cfg(feature = "lock_debug")]
+    owner_cpu: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+// The lock makes sure only one CPU accesses the data at a time
+unsafe impl<T: ?Sized + Send> Sync for IrqSpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for IrqSpinLock<T> {}
+
+/// Gives access to the data of a locked `IrqSpinLock`. Dropping it unlocks the lock and enables
+/// interrupts again if they were enabled before locking
+pub struct IrqSpinLockGuard<'a, T: ?Sized> {
+    lock: &'a IrqSpinLock<T>,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(data: T) -> IrqSpinLock<T> {
+        IrqSpinLock {
+            locked: AtomicBool::new(false),
+            #[cfg(feature = "lock_debug")]
+            owner: AtomicPtr::new(core::ptr::null_mut()),
+            #[cfg(feature = "lock_debug")]
+            owner_cpu: AtomicUsize::new(NO_CPU),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqSpinLock<T> {
+    /// Disables interrupts and spins until the lock is free
+    #[track_caller]
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        #[cfg(feature = "lock_debug")]
+        let mut spins = 0u64;
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err() {
+            // Wait for the lock to look free before trying again, without writing to it
+            while self.locked.load(Ordering::Relaxed) {
+                #[cfg(feature = "lock_debug")]
+                {
+                    self.check_deadlock(spins);
+                    spins += 1;
+                }
+                core::hint::spin_loop();
+            }
+        }
+        self.set_owner();
+        IrqSpinLockGuard { lock: self, interrupts_were_enabled }
+    }
+
+    /// Takes the lock if it is free, without spinning
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<'_, T>> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            self.set_owner();
+            Some(IrqSpinLockGuard { lock: self, interrupts_were_enabled })
+        } else {
+            if interrupts_were_enabled {
+                interrupts::enable();
+            }
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Gives access to the data without locking, which the `&mut` makes safe
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    #[cfg(feature = "lock_debug")]
+    #[track_caller]
+    fn set_owner(&self) {
+        let location: &'static Location<'static> = Location::caller();
+        self.owner.store(location as *const _ as *mut _, Ordering::Relaxed);
+        self.owner_cpu.store(crate::percpu::try_cpu_id().unwrap_or(0), Ordering::Relaxed);
+    }
+
+    #[cfg(not(feature = "lock_debug"))]
+    fn set_owner(&self) {}
+
+    /// Panics if the lock is held by the calling CPU or was waited for too long
+    #[cfg(feature = "lock_debug")]
+    #[track_caller]
+    fn check_deadlock(&self, spins: u64) {
+        let same_cpu = crate::percpu::try_cpu_id().unwrap_or(0)
+            == self.owner_cpu.load(Ordering::Relaxed);
+        if !same_cpu && spins < SPIN_LIMIT {
+            return;
+        }
+        let owner = self.owner.load(Ordering::Relaxed);
+        let owner = unsafe { owner.as_ref() };
+        let reason = if same_cpu { "already held by this CPU" } else { "held for too long" };
+        match owner {
+            Some(owner) => panic!("deadlock: lock taken at {} is {} (locking at {})",
+                                  owner, reason, Location::caller()),
+            None => panic!("deadlock: lock is {} (locking at {})", reason, Location::caller()),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for IrqSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "lock_debug")]
+        {
+            self.lock.owner.store(core::ptr::null_mut(), Ordering::Relaxed);
+            self.lock.owner_cpu.store(NO_CPU, Ordering::Relaxed);
+        }
+        self.lock.locked.store(false, Ordering::Release);
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+#[test_case]
+fn test_lock_restores_interrupts() {
+    let lock = IrqSpinLock::new(0);
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = lock.lock();
+        assert!(!interrupts::are_enabled());
+        *guard += 1;
+        // Nested locks must not enable interrupts when the inner one is released
+        let other = IrqSpinLock::new(());
+        drop(other.lock());
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(*lock.lock(), 1);
+}
+
+#[test_case]
+fn test_try_lock() {
+    let lock = IrqSpinLock::new(());
+    let guard = lock.try_lock().expect("lock is free");
+    assert!(lock.try_lock().is_none());
+    drop(guard);
+    assert!(lock.try_lock().is_some());
+    assert!(interrupts::are_enabled());
+}
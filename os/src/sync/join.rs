@@ -0,0 +1,34 @@
+use core::future::{poll_fn, Future};
+use core::pin::pin;
+use core::task::Poll;
+
+// Runs two futures at the same time on the calling task, e.g. the keyboard driver and the shell
+// reading what it decodes. Both are polled whenever the task is woken.
+
+/// Waits for both futures and returns both results
+pub async fn join<A: Future, B: Future>(a: A, b: B) -> (A::Output, B::Output) {
+    let mut a = pin!(a);
+    let mut b = pin!(b);
+    let mut a_output = None;
+    let mut b_output = None;
+    poll_fn(|context| {
+        if a_output.is_none() {
+            if let Poll::Ready(output) = a.as_mut().poll(context) {
+                a_output = Some(output);
+            }
+        }
+        if b_output.is_none() {
+            if let Poll::Ready(output) = b.as_mut().poll(context) {
+                b_output = Some(output);
+            }
+        }
+        match (a_output.take(), b_output.take()) {
+            (Some(a), Some(b)) => Poll::Ready((a, b)),
+            (a, b) => {
+                a_output = a;
+                b_output = b;
+                Poll::Pending
+            }
+        }
+    }).await
+}
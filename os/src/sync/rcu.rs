@@ -0,0 +1,139 @@
+use alloc::boxed::Box;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use crate::percpu::{self, MAX_CPUS};
+
+// Read-copy-update: readers of an `RcuCell` get a reference to its current value without taking a
+// lock or even writing to shared memory, which makes it cheap and safe to read from interrupt
+// handlers. An update swaps in a new value and frees the old one once no reader can still see it.
+//
+// Readers announce themselves per CPU: the outermost read on a CPU records the global epoch it
+// started in. An update swaps the pointer, advances the epoch and then waits for a grace period,
+// until every CPU is either outside of a read or started its read after the swap:
+//
+//  CPU 0: ---[read, epoch 4]------------------]
+//  CPU 1:                    [read, epoch 5]---]
+//  update:   swap, epoch 4 -> 5 ... wait for CPU 0 ... free old value
+//
+// Reads nest (an interrupt handler may read while the code it interrupted does), but an update
+// must not be started from inside a read on the same CPU, it would wait for itself forever.
+
+static EPOCH: AtomicU64 = AtomicU64::new(1);
+
+struct ReaderState {
+    depth: AtomicUsize,
+    epoch: AtomicU64, // epoch the outermost read started in
+}
+
+static READERS: [ReaderState; MAX_CPUS] = [const { ReaderState {
+    depth: AtomicUsize::new(0),
+    epoch: AtomicU64::new(0),
+} }; MAX_CPUS];
+
+fn reader_state() -> &'static ReaderState {
+    // Before per-CPU data is set up, only the boot CPU is running
+    &READERS[percpu::try_cpu_id().unwrap_or(0)]
+}
+
+fn read_lock() {
+    let state = reader_state();
+    if state.depth.fetch_add(1, Ordering::SeqCst) == 0 {
+        state.epoch.store(EPOCH.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+}
+
+fn read_unlock() {
+    reader_state().depth.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// Waits until every read that started before the call has ended
+///
+/// Must not be called from inside a read or from an interrupt handler.
+pub fn synchronize() {
+    let epoch = EPOCH.fetch_add(1, Ordering::SeqCst) + 1;
+    for state in READERS.iter() {
+        while state.depth.load(Ordering::SeqCst) != 0
+            && state.epoch.load(Ordering::SeqCst) < epoch {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// A value that is read often and rarely replaced
+///
+/// Reads never block, updates copy the value, change the copy and wait for all reads of the old
+/// value to end before freeing it. Concurrent updates are serialized.
+pub struct RcuCell<T> {
+    value: AtomicPtr<T>,
+    writer: spin::Mutex<()>,
+    _owned: PhantomData<Box<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for RcuCell<T> {}
+unsafe impl<T: Send> Send for RcuCell<T> {}
+
+impl<T> RcuCell<T> {
+    pub fn new(value: T) -> RcuCell<T> {
+        RcuCell {
+            value: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            writer: spin::Mutex::new(()),
+            _owned: PhantomData,
+        }
+    }
+
+    /// Returns a guard to the current value. Doesn't block or allocate
+    ///
+    /// The value stays alive while the guard exists, so guards should be short-lived: updates
+    /// wait for them.
+    pub fn read(&self) -> RcuReadGuard<'_, T> {
+        read_lock();
+        let value = self.value.load(Ordering::SeqCst);
+        RcuReadGuard { value: unsafe { &*value } }
+    }
+
+    /// Replaces the value and waits until no reader uses the old one anymore
+    pub fn replace(&self, value: T) {
+        let _writer = self.writer.lock();
+        self.swap(Box::new(value));
+    }
+
+    /// Replaces the value with the one `update` makes from (a reference to) the current one
+    pub fn update<F: FnOnce(&T) -> T>(&self, update: F) {
+        let _writer = self.writer.lock();
+        // Other writers are locked out, so the value can't be freed while we look at it
+        let new = update(unsafe { &*self.value.load(Ordering::SeqCst) });
+        self.swap(Box::new(new));
+    }
+
+    fn swap(&self, new: Box<T>) {
+        let old = self.value.swap(Box::into_raw(new), Ordering::SeqCst);
+        synchronize();
+        drop(unsafe { Box::from_raw(old) });
+    }
+}
+
+impl<T> Drop for RcuCell<T> {
+    fn drop(&mut self) {
+        // A reader would borrow self, so there is none left
+        drop(unsafe { Box::from_raw(*self.value.get_mut()) });
+    }
+}
+
+pub struct RcuReadGuard<'a, T> {
+    value: &'a T,
+}
+
+impl<T> Deref for RcuReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T> Drop for RcuReadGuard<'_, T> {
+    fn drop(&mut self) {
+        read_unlock();
+    }
+}
@@ -0,0 +1,63 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::WaitQueue;
+
+// A counting semaphore: `acquire().await` takes one of a number of permits, sleeping until one
+// is available. Permits are given back when the returned `SemaphorePermit` is dropped, or can be
+// added with `add_permits`, e.g. by an interrupt handler signalling that data arrived.
+
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+/// A permit taken from a `Semaphore`, given back when dropped
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Semaphore {
+        Semaphore { permits: AtomicUsize::new(permits), waiters: WaitQueue::new() }
+    }
+
+    /// Waits until a permit is available and takes it
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.waiters.wait_until(|| self.try_acquire()).await
+    }
+
+    /// Takes a permit if one is available
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| permits.checked_sub(1))
+            .ok()
+            .map(|_| SemaphorePermit { semaphore: self })
+    }
+
+    /// Adds `count` permits and wakes as many waiters
+    pub fn add_permits(&self, count: usize) {
+        self.permits.fetch_add(count, Ordering::Release);
+        for _ in 0..count {
+            if !self.waiters.wake_one() {
+                break;
+            }
+        }
+    }
+
+    /// Returns the number of permits currently available
+    pub fn available_permits(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
+
+impl SemaphorePermit<'_> {
+    /// Keeps the permit taken instead of giving it back
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(1);
+    }
+}
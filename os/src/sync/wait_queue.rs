@@ -0,0 +1,150 @@
+use alloc::collections::VecDeque;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use super::IrqSpinLock;
+
+// A queue of tasks waiting for something to happen. Instead of spinning, a waiting future
+// stores its `Waker` in the queue and returns `Poll::Pending`; whoever makes the awaited thing
+// happen wakes one or all of the waiters, which then check again whether they can go on.
+
+pub struct WaitQueue {
+    waiters: IrqSpinLock<VecDeque<(u64, Waker)>>,
+    next_id: AtomicU64,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: IrqSpinLock::new(VecDeque::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns a future that resolves to the value `condition` returns once it returns `Some`
+    ///
+    /// `condition` is checked right away, and then every time the waiter is woken.
+    pub fn wait_until<F, R>(&self, condition: F) -> WaitUntil<'_, F>
+    where
+        F: FnMut() -> Option<R> + Unpin,
+    {
+        WaitUntil { queue: self, condition, id: None }
+    }
+
+    /// Wakes the waiter that has waited longest. Returns false if nobody was waiting
+    pub fn wake_one(&self) -> bool {
+        // Wake outside of the lock, waking may run arbitrary code
+        let waiter = self.waiters.lock().pop_front();
+        match waiter {
+            Some((_, waker)) => {
+                waker.wake();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes all waiters
+    pub fn wake_all(&self) {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        for (_, waker) in waiters {
+            waker.wake();
+        }
+    }
+
+    /// Returns the number of waiters
+    pub fn len(&self) -> usize {
+        self.waiters.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds the waiter with the given ID to the queue, or updates its waker if it is queued
+    fn register(&self, id: &mut Option<u64>, waker: &Waker) {
+        let mut waiters = self.waiters.lock();
+        if let Some(id) = *id {
+            if let Some((_, queued)) = waiters.iter_mut().find(|(queued, _)| *queued == id) {
+                if !queued.will_wake(waker) {
+                    *queued = waker.clone();
+                }
+                return;
+            }
+        }
+        let new_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        waiters.push_back((new_id, waker.clone()));
+        *id = Some(new_id);
+    }
+
+    /// Removes the waiter with the given ID. Returns false if it was not queued (anymore)
+    fn remove(&self, id: u64) -> bool {
+        let mut waiters = self.waiters.lock();
+        match waiters.iter().position(|(queued, _)| *queued == id) {
+            Some(index) => {
+                waiters.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        WaitQueue::new()
+    }
+}
+
+/// The future returned by `WaitQueue::wait_until`
+pub struct WaitUntil<'a, F> {
+    queue: &'a WaitQueue,
+    condition: F,
+    id: Option<u64>, // set while the waiter is (or was) in the queue
+}
+
+impl<F, R> Future for WaitUntil<'_, F>
+where
+    F: FnMut() -> Option<R> + Unpin,
+{
+    type Output = R;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<R> {
+        let this = self.get_mut();
+        if let Some(result) = (this.condition)() {
+            this.finish();
+            return Poll::Ready(result);
+        }
+        this.queue.register(&mut this.id, cx.waker());
+        // The condition may have become true (and the wakeup gone to nobody) between the check
+        // and registering, so check again now that a wakeup can't be missed anymore
+        match (this.condition)() {
+            Some(result) => {
+                this.finish();
+                Poll::Ready(result)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<F> WaitUntil<'_, F> {
+    fn finish(&mut self) {
+        if let Some(id) = self.id.take() {
+            self.queue.remove(id);
+        }
+    }
+}
+
+impl<F> Drop for WaitUntil<'_, F> {
+    fn drop(&mut self) {
+        // A waiter that was woken but dropped before it could go on must pass the wakeup on, or
+        // the others might wait forever
+        if let Some(id) = self.id.take() {
+            if !self.queue.remove(id) {
+                self.queue.wake_one();
+            }
+        }
+    }
+}
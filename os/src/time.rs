@@ -0,0 +1,99 @@
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::port::Port;
+
+// This file programs the Programmable Interval Timer (PIT, Intel 8253/8254) and keeps a monotonic
+// tick count from its interrupts. Channel 0 of the PIT is wired to IRQ 0 (the timer interrupt) and
+// fires every `divisor` cycles of its 1.193182 MHz input clock. The default divisor of 65536 gives
+// the ~18.2 Hz the firmware leaves us with.
+
+/// Frequency of the oscillator driving the PIT, in Hz
+pub const PIT_BASE_FREQUENCY: u32 = 1_193_182;
+
+/// Timer interrupt frequency set up by `init`
+pub const DEFAULT_TICK_HZ: u32 = 1000;
+
+const PIT_CHANNEL_0_PORT: u16 = 0x40;
+const PIT_COMMAND_PORT: u16 = 0x43;
+
+// Command byte: channel 0 (bits 6-7), access low byte then high byte (bits 4-5), mode 2 (rate
+// generator, bits 1-3), 16 bit binary counter (bit 0)
+const PIT_CHANNEL_0_RATE_GENERATOR: u8 = 0x34;
+
+static TICKS: AtomicU64 = AtomicU64::new(0); // timer interrupts since `init`
+static TICK_HZ: AtomicU32 = AtomicU32::new(0); // actual frequency of the timer interrupt
+
+/// Programs PIT channel 0 to fire the timer interrupt `hz` times per second
+///
+/// The PIT can only divide its base frequency by an integer between 1 and 65536, so the
+/// frequency actually used (returned by `tick_hz`) is the closest one the PIT can generate.
+pub fn init(hz: u32) {
+    let divisor = (PIT_BASE_FREQUENCY + hz / 2) / hz.max(1);
+    let divisor = divisor.clamp(1, 65536);
+
+    let mut command: Port<u8> = Port::new(PIT_COMMAND_PORT);
+    let mut channel_0: Port<u8> = Port::new(PIT_CHANNEL_0_PORT);
+    unsafe {
+        command.write(PIT_CHANNEL_0_RATE_GENERATOR);
+        // A divisor of 65536 is written as 0
+        channel_0.write((divisor & 0xff) as u8);
+        channel_0.write(((divisor >> 8) & 0xff) as u8);
+    }
+    TICK_HZ.store(PIT_BASE_FREQUENCY / divisor, Ordering::Relaxed);
+}
+
+/// Advances the tick count. Called from the timer interrupt handler
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of timer interrupts since `init`
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the frequency of the timer interrupt in Hz, or 0 before `init`
+pub fn tick_hz() -> u32 {
+    TICK_HZ.load(Ordering::Relaxed)
+}
+
+/// Converts a tick count into the time it spans
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let hz = u64::from(tick_hz().max(1));
+    Duration::new(ticks / hz, ((ticks % hz) * 1_000_000_000 / hz) as u32)
+}
+
+/// Returns the time since the timer was started, with a resolution of one tick
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks())
+}
+
+/// Halts the CPU until at least `ms` milliseconds have passed
+///
+/// Relies on the timer interrupt to wake the CPU, so interrupts must be enabled.
+pub fn sleep(ms: u64) {
+    let hz = u64::from(tick_hz().max(1));
+    // Round up, and wait one extra tick since the current one may be about to end. Whole
+    // seconds are converted separately so long sleeps don't overflow
+    let wait = (ms / 1000).saturating_mul(hz)
+        .saturating_add((ms % 1000 * hz).div_ceil(1000))
+        .saturating_add(1);
+    let target = ticks().saturating_add(wait);
+    while ticks() < target {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn test_ticks_to_duration() {
+    let hz = u64::from(tick_hz().max(1));
+    assert_eq!(ticks_to_duration(hz), Duration::from_secs(1));
+    assert_eq!(ticks_to_duration(3 * hz / 2), Duration::from_millis(1500));
+}
+
+#[test_case]
+fn test_sleep() { // sleeping advances the uptime by at least the requested time
+    let start = uptime();
+    sleep(20);
+    assert!(uptime() - start >= Duration::from_millis(20));
+}
@@ -0,0 +1,278 @@
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use lazy_static::lazy_static;
+use crate::console_print;
+use crate::sync::{IrqSpinLock, WaitQueue};
+use crate::vga_buffer::{self, NUM_CONSOLES};
+
+// This file is the terminal (TTY) layer between the input devices and the programs reading from
+// a console. Every virtual console has a TTY; the keyboard feeds the characters typed into the
+// TTY of the console that is shown, and programs read them with `read_line` or `read_char`.
+//
+//  keyboard ---> Tty::input ---> line discipline ---> input queue ---> read_line / read_char
+//                                      |
+//                                      +---> echo to the console
+//
+// In canonical mode (the default) the line discipline collects a line and lets it be edited
+// before it is handed to readers when Enter is pressed:
+//  - Backspace erases the last character, Ctrl+U the whole line, Ctrl+W the last word
+//  - Ctrl+C throws the line away and interrupts the reader (`TtyError::Interrupted`)
+//  - escape sequences (arrow keys etc.) are dropped
+// In raw mode every character, including control characters and escape sequences, goes to the
+// readers as it comes in.
+//
+// The line and the input queue together hold at most INPUT_LIMIT characters. What is typed
+// beyond that is dropped, except for the newline that completes the line.
+
+const BACKSPACE: char = '\x08';
+const DELETE: char = '\x7f';
+const CTRL_C: char = '\x03';
+const CTRL_U: char = '\x15';
+const CTRL_W: char = '\x17';
+const ESCAPE: char = '\x1b';
+
+/// How many characters can wait in a TTY for a reader
+pub const INPUT_LIMIT: usize = 4096;
+
+lazy_static! {
+    static ref TTYS: [Tty; NUM_CONSOLES] = core::array::from_fn(Tty::new);
+}
+
+/// Returns the TTY of the console with the given index
+pub fn tty(console: usize) -> &'static Tty {
+    &TTYS[console]
+}
+
+/// Returns the TTY of the console that is currently shown, which receives the keys typed
+pub fn active() -> &'static Tty {
+    tty(vga_buffer::active_console())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtyMode {
+    /// Input is collected and edited line by line
+    Canonical,
+    /// Every character goes to the reader right away
+    Raw,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtyError {
+    /// Ctrl+C was pressed while (or before) reading
+    Interrupted,
+}
+
+pub struct Tty {
+    console: usize,
+    state: IrqSpinLock<TtyState>,
+    readers: WaitQueue,
+}
+
+struct TtyState {
+    mode: TtyMode,
+    echo: bool,
+    line: String, // the line being edited, in canonical mode
+    input: VecDeque<char>, // ready to be read
+    interrupted: bool,
+    escape: Option<usize>, // number of characters of an escape sequence seen after ESC
+}
+
+impl Tty {
+    fn new(console: usize) -> Tty {
+        Tty {
+            console,
+            state: IrqSpinLock::new(TtyState {
+                mode: TtyMode::Canonical,
+                echo: true,
+                line: String::new(),
+                input: VecDeque::new(),
+                interrupted: false,
+                escape: None,
+            }),
+            readers: WaitQueue::new(),
+        }
+    }
+
+    /// Index of the console this TTY belongs to
+    pub fn console(&self) -> usize {
+        self.console
+    }
+
+    pub fn mode(&self) -> TtyMode {
+        self.state.lock().mode
+    }
+
+    /// Switches between canonical and raw mode. A line being edited is dropped
+    pub fn set_mode(&self, mode: TtyMode) {
+        let mut state = self.state.lock();
+        state.mode = mode;
+        state.line.clear();
+        state.escape = None;
+    }
+
+    pub fn echo(&self) -> bool {
+        self.state.lock().echo
+    }
+
+    /// Turns echoing the characters typed to the console on or off
+    pub fn set_echo(&self, echo: bool) {
+        self.state.lock().echo = echo;
+    }
+
+    /// Feeds the characters of `input` into the TTY, as if they were typed
+    pub fn input_str(&self, input: &str) {
+        for character in input.chars() {
+            self.input(character);
+        }
+    }
+
+    /// Feeds a character into the TTY, as if it was typed
+    pub fn input(&self, character: char) {
+        let mut echo = String::new();
+        let wake = {
+            let mut state = self.state.lock();
+            let wake = match state.mode {
+                TtyMode::Canonical => state.input_canonical(character, &mut echo),
+                TtyMode::Raw if state.input.len() >= INPUT_LIMIT => false,
+                TtyMode::Raw => {
+                    state.input.push_back(character);
+                    if character == '\n' || !character.is_control() {
+                        echo.push(character);
+                    }
+                    true
+                }
+            };
+            if !state.echo {
+                echo.clear();
+            }
+            wake
+        };
+        if !echo.is_empty() {
+            console_print!(self.console, "{}", echo);
+        }
+        if wake {
+            self.readers.wake_all();
+        }
+    }
+
+    /// Waits for a line and returns it without the newline
+    ///
+    /// In raw mode, this is whatever comes in up to the next newline.
+    pub async fn read_line(&self) -> Result<String, TtyError> {
+        self.readers.wait_until(|| {
+            let mut state = self.state.lock();
+            if state.interrupted {
+                state.interrupted = false;
+                return Some(Err(TtyError::Interrupted));
+            }
+            let end = state.input.iter().position(|&character| character == '\n')?;
+            let mut line: String = state.input.drain(..=end).collect();
+            line.pop();
+            Some(Ok(line))
+        }).await
+    }
+
+    /// Waits for the next character
+    ///
+    /// In canonical mode, characters only come in once their line is complete.
+    pub async fn read_char(&self) -> char {
+        self.readers.wait_until(|| self.try_read_char()).await
+    }
+
+    /// Returns the next character if one is ready to be read
+    pub fn try_read_char(&self) -> Option<char> {
+        self.state.lock().input.pop_front()
+    }
+
+    /// Returns whether Ctrl+C was pressed since the last call (or the last interrupted read), for
+    /// long-running programs that want to be stoppable
+    pub fn take_interrupt(&self) -> bool {
+        core::mem::take(&mut self.state.lock().interrupted)
+    }
+}
+
+impl TtyState {
+    /// Runs the canonical mode line discipline on a character. Returns whether readers need to be
+    /// woken
+    fn input_canonical(&mut self, character: char, echo: &mut String) -> bool {
+        if let Some(seen) = self.escape {
+            // An escape sequence is ESC [ followed by parameters and ends with a byte in @..~
+            let continues = if seen == 0 {
+                character == '['
+            } else {
+                !('@'..='~').contains(&character)
+            };
+            self.escape = if continues { Some(seen + 1) } else { None };
+            return false;
+        }
+        match character {
+            '\n' | '\r' => {
+                if !self.has_room(1) {
+                    return false;
+                }
+                self.input.extend(self.line.drain(..));
+                self.input.push_back('\n');
+                echo.push('\n');
+                true
+            }
+            BACKSPACE | DELETE => {
+                self.erase(1, echo);
+                false
+            }
+            CTRL_U => {
+                self.erase(self.line.chars().count(), echo);
+                false
+            }
+            CTRL_W => {
+                // The spaces before the cursor, then the word before them
+                let trimmed = self.line.trim_end_matches(' ');
+                let word_start = trimmed.rfind(' ').map_or(0, |space| space + 1);
+                let count = self.line[word_start..].chars().count();
+                self.erase(count, echo);
+                false
+            }
+            CTRL_C => {
+                self.line.clear();
+                self.interrupted = true;
+                echo.push_str("^C\n");
+                true
+            }
+            ESCAPE => {
+                self.escape = Some(0);
+                false
+            }
+            // Leave room for the newline that ends the line
+            _ if !self.has_room(2) => false,
+            '\t' => {
+                self.line.push(character);
+                echo.push(' ');
+                false
+            }
+            character if character.is_control() => false,
+            character => {
+                self.line.push(character);
+                echo.push(character);
+                false
+            }
+        }
+    }
+
+    /// Returns whether `count` more characters fit into the line and the input queue
+    fn has_room(&self, count: usize) -> bool {
+        self.line.chars().count() + self.input.len() + count <= INPUT_LIMIT
+    }
+
+    /// Removes up to `count` characters from the end of the line and from the screen
+    fn erase(&mut self, count: usize, echo: &mut String) {
+        for _ in 0..count {
+            let Some(character) = self.line.pop() else {
+                break;
+            };
+            // Characters outside of ASCII are shown as one placeholder per UTF-8 byte
+            let width = if character.is_ascii() { 1 } else { character.len_utf8() };
+            for _ in 0..width {
+                echo.push_str("\x08 \x08");
+            }
+        }
+    }
+}
@@ -1,7 +1,13 @@
 use volatile::Volatile;
 use core::fmt;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use core::ops::Range;
 use lazy_static::lazy_static;
-use spin::Mutex;
+use crate::sync::IrqSpinLock;
+use crate::framebuffer::{console::TextConsole, Framebuffer};
+use crate::serial::SerialPort;
 
 // This file specifies how to print to console using the VGA Buffer
 
@@ -18,17 +24,35 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// Prints to the virtual console with the given index
+#[macro_export]
+macro_rules! console_print {
+    ($console:expr, $($arg:tt)*) => (
+        $crate::vga_buffer::_print_to($console, format_args!($($arg)*)));
+}
+
+/// Prints to the virtual console with the given index, appending a newline
+#[macro_export]
+macro_rules! console_println {
+    ($console:expr) => ($crate::console_print!($console, "\n"));
+    ($console:expr, $($arg:tt)*) => (
+        $crate::console_print!($console, "{}\n", format_args!($($arg)*)));
+}
+
 // We can use our own _print implementation since we don't need to support 
 // different stdout devices
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
-    // Execute closure in an interrupt-free environment to prevent deadlocks with interrupts trying
-    // to lock print writer mutex
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    // The lock disables interrupts while it is held, so interrupt handlers that print can't
+    // deadlock with the code they interrupted
+    WRITER.lock().write_fmt(args).unwrap();
+}
+
+#[doc(hidden)]
+pub fn _print_to(console: usize, args: fmt::Arguments) {
+    use core::fmt::Write;
+    CONSOLES[console].lock().write_fmt(args).unwrap();
 }
 
 
@@ -56,12 +80,18 @@ pub enum Color {
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
-struct ColorCode(u8);
+pub struct ColorCode(u8);
 
 impl ColorCode {
-    fn new(foreground: Color, background: Color) -> ColorCode {
+    pub const fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    /// Returns the VGA attribute byte: background color in the upper, foreground in the lower
+    /// four bits
+    pub fn as_u8(self) -> u8 {
+        self.0
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -71,8 +101,24 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
+
+/// Number of rows that scrolled off the top of the screen which are kept for Shift+PageUp
+pub const SCROLLBACK_LINES: usize = 500;
+
+/// A single row of the screen, as kept in the scrollback history
+type Row = [ScreenChar; BUFFER_WIDTH];
+
+/// Number of virtual consoles, switched between with Alt+F1..Alt+F6
+pub const NUM_CONSOLES: usize = 6;
+
+/// The console `print!` and `println!` write to
+pub const LOG_CONSOLE: usize = 0;
+
+// How the cell the mouse points at and the cells selected with it are shown
+const POINTER_COLOR: ColorCode = ColorCode::new(Color::White, Color::Red);
+const SELECTION_COLOR: ColorCode = ColorCode::new(Color::Black, Color::LightGray);
 
 #[repr(transparent)]
 struct Buffer {
@@ -82,16 +128,114 @@ struct Buffer {
                                                                   // it away erroneously
 }
 
+/// Where the console that is currently shown is displayed
+enum Screen {
+    Text(&'static mut Buffer), // the VGA text buffer
+    Graphics(Box<TextConsole>), // glyphs rendered to a pixel framebuffer
+}
+
+impl Screen {
+    fn write(&mut self, row: usize, col: usize, character: ScreenChar) {
+        match self {
+            Screen::Text(buffer) => buffer.chars[row][col].write(character),
+            Screen::Graphics(console) => {
+                console.draw_char(row, col, character.ascii_character, character.color_code)
+            }
+        }
+    }
+
+    fn set_cursor(&mut self, row: usize, col: usize) {
+        match self {
+            Screen::Text(_) => set_cursor_position(row, col),
+            Screen::Graphics(console) => console.set_cursor(row, col),
+        }
+    }
+
+    /// Moves the rows on screen up by one to follow `chars`, which already scrolled. Leaves the
+    /// last row as it was
+    fn scroll_up(&mut self, chars: &[Row; BUFFER_HEIGHT]) {
+        match self {
+            Screen::Text(buffer) => {
+                // `Volatile` is transparent, so the rows are contiguous cells and one memmove
+                // moves them
+                let cells = buffer.chars.as_mut_ptr() as *mut Volatile<ScreenChar>;
+                unsafe {
+                    core::ptr::copy(cells.add(BUFFER_WIDTH), cells,
+                                    (BUFFER_HEIGHT - 1) * BUFFER_WIDTH);
+                }
+            }
+            // The console only renders the cells that changed
+            Screen::Graphics(console) => {
+                for (row, chars) in chars.iter().enumerate().take(BUFFER_HEIGHT - 1) {
+                    for (col, character) in chars.iter().enumerate() {
+                        console.draw_char(row, col, character.ascii_character,
+                                          character.color_code);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A virtual console
+///
+/// Every console keeps its contents in an off-screen buffer. Only the console that is currently
+/// shown holds the screen (the VGA buffer or a framebuffer) and mirrors every change to it.
 pub struct Writer {
     column_position: usize, // keeps track of the current position in last row
     color_code: ColorCode, // controls current foreground and background colors
-    buffer: &'static mut Buffer, // Static mutable reference to the VGA buffer
+    chars: [Row; BUFFER_HEIGHT], // off-screen copy of the console contents
+    screen: Option<Screen>, // only held by the console that is currently shown
+    scrollback: Option<VecDeque<Row>>, // rows that scrolled off the top, oldest first. Heap backed
+                                       // so it stays None until `enable_scrollback` is called
+    scroll_offset: usize, // how many rows the view is scrolled back, 0 means live output
+    serial: Option<&'static SerialPort>, // the serial port the output is mirrored to
+    pointer: Option<(usize, usize)>, // the cell of the view the mouse points at
+    selection: Option<((usize, usize), (usize, usize))>, // first and last cell of the view
+                                                          // selected with the mouse
+    selection_generation: u64, // bumped whenever the writer drops the selection by itself
 }
 
 impl Writer {
+    fn new(screen: Option<Screen>) -> Writer {
+        let color_code = ColorCode::new(Color::Yellow, Color::Black);
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code,
+        };
+        let mut writer = Writer {
+            column_position: 0,
+            color_code,
+            chars: [[blank; BUFFER_WIDTH]; BUFFER_HEIGHT],
+            screen,
+            scrollback: None,
+            scroll_offset: 0,
+            serial: None,
+            pointer: None,
+            selection: None,
+            selection_generation: 0,
+        };
+        // Keep whatever is on screen already (e.g. bootloader messages) in the console shown at
+        // boot
+        if let Some(Screen::Text(screen)) = writer.screen.as_ref() {
+            for (row, chars) in writer.chars.iter_mut().enumerate() {
+                for (col, character) in chars.iter_mut().enumerate() {
+                    *character = screen.chars[row][col].read();
+                }
+            }
+        }
+        writer
+    }
+
     pub fn write_byte(&mut self, byte: u8) {
+        self.return_to_live();
         match byte {
             b'\n' => self.new_line(),
+            // Backspace moves back one column (within the row) without erasing
+            0x08 => {
+                self.column_position = self.column_position.saturating_sub(1);
+                self.mirror(&[0x08]);
+            }
             byte => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();
@@ -101,24 +245,55 @@ impl Writer {
                 let col = self.column_position;
 
                 let color_code = self.color_code;
-                self.buffer.chars[row][col].write(ScreenChar {
+                self.put_char(row, col, ScreenChar {
                     ascii_character: byte,
                     color_code,
                 });
                 self.column_position += 1;
+                self.mirror(&[if (0x20..=0x7e).contains(&byte) { byte } else { b'?' }]);
             }
         }
+        self.update_cursor();
+    }
+
+    /// Mirrors this console's output to the serial port, e.g. for using the shell over
+    /// `-serial stdio`
+    pub fn set_serial_mirror(&mut self, port: Option<&'static SerialPort>) {
+        self.serial = port;
+    }
+
+    /// Sends bytes to the serial port if the output is mirrored there
+    fn mirror(&self, bytes: &[u8]) {
+        if let Some(port) = self.serial {
+            // Losing console output on the serial line is better than stopping the console
+            let _ = port.write(bytes);
+        }
     }
 
     fn new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character);
+        if let Some(history) = self.scrollback.as_mut() {
+            if history.len() == SCROLLBACK_LINES {
+                history.pop_front();
+            }
+            history.push_back(self.chars[0]);
+        }
+        // The text under the selection moves away
+        self.discard_selection();
+        self.chars.copy_within(1.., 0);
+        // Only a view scrolled back into the history has to be drawn again from scratch
+        if self.scroll_offset > 0 {
+            self.redraw();
+        } else if let Some(screen) = self.screen.as_mut() {
+            screen.scroll_up(&self.chars);
+            // The pointer moved up with the text it highlighted, but stays where the mouse is
+            if let Some((row, col)) = self.pointer {
+                self.redraw_cell(row.saturating_sub(1), col);
+                self.redraw_cell(row, col);
             }
         }
         self.clear_row(BUFFER_HEIGHT - 1);
         self.column_position = 0;
+        self.mirror(b"\r\n");
     }
 
     fn clear_row(&mut self, row: usize) {
@@ -127,20 +302,450 @@ impl Writer {
             color_code: self.color_code,
         };
         for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+            self.put_char(row, col, blank);
+        }
+    }
+
+    /// Writes a character to the off-screen buffer and, if this console is shown, to the screen
+    fn put_char(&mut self, row: usize, col: usize, character: ScreenChar) {
+        self.chars[row][col] = character;
+        let shown = self.highlighted(row, col, character);
+        if let Some(screen) = self.screen.as_mut() {
+            screen.write(row, col, shown);
+        }
+    }
+
+    /// Returns how `character` is shown at (`row`, `col`) of the view: in the pointer or
+    /// selection colors if the mouse points at the cell or selected it
+    fn highlighted(&self, row: usize, col: usize, character: ScreenChar) -> ScreenChar {
+        let color_code = if self.pointer == Some((row, col)) {
+            POINTER_COLOR
+        } else if matches!(self.selection, Some((first, last))
+                           if first <= (row, col) && (row, col) <= last) {
+            SELECTION_COLOR
+        } else {
+            return character;
+        };
+        ScreenChar { ascii_character: character.ascii_character, color_code }
+    }
+
+    /// Returns the row that is currently displayed at `row`, taking the scrollback view into
+    /// account
+    fn displayed_row(&self, row: usize) -> &Row {
+        match self.scrollback.as_ref() {
+            Some(history) if self.scroll_offset > 0 => {
+                // Line `i` of the view is line `first + i` of the history followed by the live rows
+                let line = history.len() - self.scroll_offset + row;
+                if line < history.len() {
+                    &history[line]
+                } else {
+                    &self.chars[line - history.len()]
+                }
+            }
+            _ => &self.chars[row],
+        }
+    }
+
+    /// Copies everything that should be displayed to the screen, if this console is shown
+    fn redraw(&mut self) {
+        self.redraw_rows(0..BUFFER_HEIGHT);
+    }
+
+    /// Copies the given rows of the view to the screen, if this console is shown
+    fn redraw_rows(&mut self, rows: Range<usize>) {
+        if self.screen.is_none() {
+            return;
+        }
+        for row in rows {
+            let mut chars = *self.displayed_row(row);
+            for (col, character) in chars.iter_mut().enumerate() {
+                *character = self.highlighted(row, col, *character);
+            }
+            if let Some(screen) = self.screen.as_mut() {
+                for (col, character) in chars.iter().enumerate() {
+                    screen.write(row, col, *character);
+                }
+            }
+        }
+    }
+
+    /// Copies one cell of the view to the screen, if this console is shown
+    fn redraw_cell(&mut self, row: usize, col: usize) {
+        let character = self.highlighted(row, col, self.displayed_row(row)[col]);
+        if let Some(screen) = self.screen.as_mut() {
+            screen.write(row, col, character);
+        }
+    }
+
+    /// Highlights the cell of the view the mouse points at, `None` once it left the screen
+    pub fn set_pointer(&mut self, pointer: Option<(usize, usize)>) {
+        let pointer = pointer.filter(|&(row, col)| row < BUFFER_HEIGHT && col < BUFFER_WIDTH);
+        let previous = core::mem::replace(&mut self.pointer, pointer);
+        for (row, col) in previous.into_iter().chain(pointer) {
+            self.redraw_cell(row, col);
+        }
+    }
+
+    /// Highlights the cells of the view from one cell to another in reading order, the way text
+    /// is selected with the mouse. `None` removes the selection
+    pub fn set_selection(&mut self, selection: Option<((usize, usize), (usize, usize))>) {
+        let selection = selection
+            .map(|(from, to)| (from.min(to), from.max(to)))
+            .filter(|&(_, (row, col))| row < BUFFER_HEIGHT && col < BUFFER_WIDTH);
+        let previous = core::mem::replace(&mut self.selection, selection);
+        for (first, last) in previous.into_iter().chain(selection) {
+            self.redraw_rows(first.0..last.0 + 1);
         }
     }
 
+    /// Returns a number that changes whenever the selection is removed by something other than
+    /// `set_selection`, like scrolling, so that whoever is selecting knows to start over
+    pub fn selection_generation(&self) -> u64 {
+        self.selection_generation
+    }
+
+    fn discard_selection(&mut self) {
+        self.set_selection(None);
+        self.selection_generation = self.selection_generation.wrapping_add(1);
+    }
+
+    /// Returns the text of the selected cells, with the rows separated by newlines and the blanks
+    /// at their ends left out
+    pub fn selected_text(&self) -> String {
+        let mut text = String::new();
+        let Some((first, last)) = self.selection else {
+            return text;
+        };
+        for row in first.0..=last.0 {
+            let start = if row == first.0 { first.1 } else { 0 };
+            let end = if row == last.0 { last.1 + 1 } else { BUFFER_WIDTH };
+            let line: String = self.displayed_row(row)[start..end].iter()
+                .map(|character| match character.ascii_character {
+                    byte @ 0x20..=0x7e => char::from(byte),
+                    _ => '?',
+                })
+                .collect();
+            text.push_str(line.trim_end());
+            if row != last.0 {
+                text.push('\n');
+            }
+        }
+        text
+    }
+
+    /// Returns true if this console is the one currently shown on screen
+    pub fn is_visible(&self) -> bool {
+        self.screen.is_some()
+    }
+
+    /// Hands the screen over to another console
+    fn release_screen(&mut self) -> Option<Screen> {
+        self.scroll_offset = 0;
+        self.pointer = None;
+        self.discard_selection();
+        self.screen.take()
+    }
+
+    /// Takes over the screen from another console and shows this console's contents on it
+    fn attach_screen(&mut self, screen: Option<Screen>) {
+        self.screen = screen;
+        self.redraw();
+        self.update_cursor();
+    }
+
+    /// Starts keeping the rows that scroll off the top of the screen
+    ///
+    /// The history lives on the heap, so this must only be called once the heap is initialized.
+    pub fn enable_scrollback(&mut self) {
+        if self.scrollback.is_none() {
+            // Allocated in full now, new_line runs in interrupt handlers that print and must not
+            // allocate
+            self.scrollback = Some(VecDeque::with_capacity(SCROLLBACK_LINES));
+        }
+    }
+
+    /// Scrolls the view `lines` rows back into the history, stopping at the oldest row
+    pub fn scroll_up(&mut self, lines: usize) {
+        let history_len = self.scrollback.as_ref().map_or(0, |history| history.len());
+        self.scroll_offset = (self.scroll_offset + lines).min(history_len);
+        self.discard_selection();
+        self.redraw();
+    }
+
+    /// Scrolls the view `lines` rows towards the live output
+    pub fn scroll_down(&mut self, lines: usize) {
+        if self.scroll_offset == 0 {
+            return;
+        }
+        self.scroll_offset = self.scroll_offset.saturating_sub(lines);
+        self.discard_selection();
+        self.redraw();
+    }
+
+    /// Returns true if the view currently shows the scrollback history instead of live output
+    pub fn is_scrolled_back(&self) -> bool {
+        self.scroll_offset > 0
+    }
+
+    /// Puts the live screen back if the view is scrolled back, so new output is visible
+    fn return_to_live(&mut self) {
+        if self.scroll_offset == 0 {
+            return;
+        }
+        self.scroll_offset = 0;
+        self.discard_selection();
+        self.redraw();
+    }
+
     pub fn write_string(&mut self, s: &str) {
         for byte in s.bytes() {
             match byte {
-                // printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
+                // printable ASCII byte, newline or backspace
+                0x20..=0x7e | b'\n' | 0x08 => self.write_byte(byte),
                 // not part of printable ASCII range
                 _ => self.write_byte(0xfe),
             }
         }
     }
+
+    /// Changes the color used for subsequent `print!` output
+    pub fn set_color_code(&mut self, color_code: ColorCode) {
+        self.color_code = color_code;
+    }
+
+    /// Returns the color used for `print!` output
+    pub fn color_code(&self) -> ColorCode {
+        self.color_code
+    }
+
+    /// Moves the hardware cursor to where the next `print!` character will be written
+    pub fn update_cursor(&mut self) {
+        // Once the last column is filled the next byte wraps to a new line, so keep the cursor on
+        // the last cell instead of letting it point past the end of the row
+        let col = self.column_position.min(BUFFER_WIDTH - 1);
+        if let Some(screen) = self.screen.as_mut() {
+            screen.set_cursor(BUFFER_HEIGHT - 1, col);
+        }
+    }
+
+    /// Returns the column of the last row the next `print!` character will be written to
+    pub fn column(&self) -> usize {
+        self.column_position
+    }
+
+    /// Moves the `print!` position (and the hardware cursor) to `col` of the last row
+    ///
+    /// Together with `clear_to_end_of_row` this lets a line that is being edited be redrawn in
+    /// place instead of appending to the output.
+    pub fn set_column(&mut self, col: usize) {
+        self.return_to_live();
+        self.column_position = col.min(BUFFER_WIDTH);
+        self.update_cursor();
+        if let Some(port) = self.serial {
+            // Back to the start of the line, then right by `col` columns
+            use core::fmt::Write;
+            let _ = match self.column_position {
+                0 => write!(port.writer(), "\r"),
+                col => write!(port.writer(), "\r\x1b[{}C", col),
+            };
+        }
+    }
+
+    /// Blanks the last row from the `print!` position to its end, without moving the position
+    pub fn clear_to_end_of_row(&mut self) {
+        self.return_to_live();
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        };
+        for col in self.column_position..BUFFER_WIDTH {
+            self.put_char(BUFFER_HEIGHT - 1, col, blank);
+        }
+        self.mirror(b"\x1b[K");
+    }
+
+    /// Writes a single raw byte (code page 437) at (`row`, `col`) with the given color
+    ///
+    /// Unlike `write_byte` this does not interpret newlines, does not scroll and does not move
+    /// the `print!` position. Writes outside of the screen are ignored.
+    pub fn write_byte_at(&mut self, row: usize, col: usize, byte: u8, color_code: ColorCode) {
+        self.return_to_live();
+        if row >= BUFFER_HEIGHT || col >= BUFFER_WIDTH {
+            return;
+        }
+        self.put_char(row, col, ScreenChar {
+            ascii_character: byte,
+            color_code,
+        });
+    }
+
+    /// Returns the byte shown at (`row`, `col`), or a blank for cells outside of the screen
+    pub fn read_byte_at(&self, row: usize, col: usize) -> u8 {
+        if row >= BUFFER_HEIGHT || col >= BUFFER_WIDTH {
+            return b' ';
+        }
+        self.displayed_row(row)[col].ascii_character
+    }
+
+    /// Writes `s` starting at (`row`, `col`) with the given color, clipped to the end of the row
+    ///
+    /// Non printable characters are shown as `■`, just like in `write_string`.
+    pub fn write_str_at(&mut self, row: usize, col: usize, s: &str, color_code: ColorCode) {
+        for (i, byte) in s.bytes().enumerate() {
+            let byte = match byte {
+                0x20..=0x7e => byte,
+                _ => 0xfe,
+            };
+            self.write_byte_at(row, col + i, byte, color_code);
+        }
+    }
+
+    /// Fills the `height` x `width` region whose top left corner is (`row`, `col`) with `byte`
+    pub fn fill_region(&mut self, row: usize, col: usize, height: usize, width: usize, byte: u8,
+                       color_code: ColorCode) {
+        let row_end = (row + height).min(BUFFER_HEIGHT);
+        let col_end = (col + width).min(BUFFER_WIDTH);
+        for r in row..row_end {
+            for c in col..col_end {
+                self.write_byte_at(r, c, byte, color_code);
+            }
+        }
+    }
+
+    /// Blanks the `height` x `width` region whose top left corner is (`row`, `col`)
+    pub fn clear_region(&mut self, row: usize, col: usize, height: usize, width: usize,
+                        color_code: ColorCode) {
+        self.fill_region(row, col, height, width, b' ', color_code);
+    }
+
+    /// Blanks the whole screen with the current color and moves the `print!` position to the
+    /// start of the last row
+    pub fn clear_screen(&mut self) {
+        self.return_to_live();
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.column_position = 0;
+        self.update_cursor();
+        self.mirror(b"\x1b[2J\x1b[H");
+    }
+
+    /// Draws the outline of a `height` x `width` box whose top left corner is (`row`, `col`)
+    ///
+    /// The inside of the box is left untouched. Boxes smaller than 2x2 are not drawn.
+    pub fn draw_box(&mut self, row: usize, col: usize, height: usize, width: usize,
+                    style: BoxStyle, color_code: ColorCode) {
+        if height < 2 || width < 2 {
+            return;
+        }
+        let [top_left, top_right, bottom_left, bottom_right, horizontal, vertical] = style.glyphs();
+        let bottom = row + height - 1;
+        let right = col + width - 1;
+
+        for c in col + 1..right {
+            self.write_byte_at(row, c, horizontal, color_code);
+            self.write_byte_at(bottom, c, horizontal, color_code);
+        }
+        for r in row + 1..bottom {
+            self.write_byte_at(r, col, vertical, color_code);
+            self.write_byte_at(r, right, vertical, color_code);
+        }
+        self.write_byte_at(row, col, top_left, color_code);
+        self.write_byte_at(row, right, top_right, color_code);
+        self.write_byte_at(bottom, col, bottom_left, color_code);
+        self.write_byte_at(bottom, right, bottom_right, color_code);
+    }
+}
+
+/// Line styles available for `Writer::draw_box`, using the code page 437 box drawing characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoxStyle {
+    Single,
+    Double,
+}
+
+impl BoxStyle {
+    /// Returns the top left, top right, bottom left, bottom right, horizontal and vertical glyphs
+    fn glyphs(self) -> [u8; 6] {
+        match self {
+            BoxStyle::Single => [0xda, 0xbf, 0xc0, 0xd9, 0xc4, 0xb3],
+            BoxStyle::Double => [0xc9, 0xbb, 0xc8, 0xbc, 0xcd, 0xba],
+        }
+    }
+}
+
+// The hardware cursor is controlled through the CRT Controller (CRTC) registers. The register to
+// access is selected by writing its index to the address port, then read or written through the
+// data port
+const CRTC_ADDRESS_PORT: u16 = 0x3d4;
+const CRTC_DATA_PORT: u16 = 0x3d5;
+
+const CRTC_CURSOR_START: u8 = 0x0a; // bits 0-4: first scanline of the cursor, bit 5: disable
+const CRTC_CURSOR_END: u8 = 0x0b; // bits 0-4: last scanline of the cursor
+const CRTC_CURSOR_LOCATION_HIGH: u8 = 0x0e;
+const CRTC_CURSOR_LOCATION_LOW: u8 = 0x0f;
+
+fn crtc_read(index: u8) -> u8 {
+    use x86_64::instructions::port::Port;
+
+    let mut address: Port<u8> = Port::new(CRTC_ADDRESS_PORT);
+    let mut data: Port<u8> = Port::new(CRTC_DATA_PORT);
+    unsafe {
+        address.write(index);
+        data.read()
+    }
+}
+
+fn crtc_write(index: u8, value: u8) {
+    use x86_64::instructions::port::Port;
+
+    let mut address: Port<u8> = Port::new(CRTC_ADDRESS_PORT);
+    let mut data: Port<u8> = Port::new(CRTC_DATA_PORT);
+    unsafe {
+        address.write(index);
+        data.write(value);
+    }
+}
+
+/// Moves the blinking hardware cursor to (`row`, `col`)
+pub fn set_cursor_position(row: usize, col: usize) {
+    let position = (row.min(BUFFER_HEIGHT - 1) * BUFFER_WIDTH + col.min(BUFFER_WIDTH - 1)) as u16;
+    crtc_write(CRTC_CURSOR_LOCATION_LOW, (position & 0xff) as u8);
+    crtc_write(CRTC_CURSOR_LOCATION_HIGH, (position >> 8) as u8);
+}
+
+/// Returns the (row, col) the hardware cursor is currently shown at
+pub fn cursor_position() -> (usize, usize) {
+    let position = (crtc_read(CRTC_CURSOR_LOCATION_HIGH) as usize) << 8
+        | crtc_read(CRTC_CURSOR_LOCATION_LOW) as usize;
+    (position / BUFFER_WIDTH, position % BUFFER_WIDTH)
+}
+
+/// Shows the hardware cursor, spanning scanlines `start` to `end` of a character cell (0-15)
+///
+/// `enable_cursor(14, 15)` gives the usual underline cursor, `enable_cursor(0, 15)` a block.
+pub fn enable_cursor(start: u8, end: u8) {
+    // Preserve the reserved upper bits of both registers
+    let cursor_start = crtc_read(CRTC_CURSOR_START) & 0xc0;
+    crtc_write(CRTC_CURSOR_START, cursor_start | (start & 0x1f));
+    let cursor_end = crtc_read(CRTC_CURSOR_END) & 0xe0;
+    crtc_write(CRTC_CURSOR_END, cursor_end | (end & 0x1f));
+}
+
+/// Hides the hardware cursor
+pub fn disable_cursor() {
+    crtc_write(CRTC_CURSOR_START, 0x20);
+}
+
+#[cfg(test)]
+impl Writer {
+    /// Reads a character back from the VGA text buffer, panicking if the console is not shown
+    fn text_screen_char(&self, row: usize, col: usize) -> ScreenChar {
+        match self.screen.as_ref() {
+            Some(Screen::Text(buffer)) => buffer.chars[row][col].read(),
+            _ => panic!("console is not shown in text mode"),
+        }
+    }
 }
 
 impl fmt::Write for Writer {
@@ -152,10 +757,65 @@ impl fmt::Write for Writer {
 
 lazy_static! { // the lazy_static macro lazily initializes the static variable (i.e. at first use
                // instead of compile time)
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer)},
+    pub static ref CONSOLES: [IrqSpinLock<Writer>; NUM_CONSOLES] = {
+        // The first console is the one shown at boot, so it starts out owning the VGA buffer
+        let mut screen = Some(Screen::Text(unsafe { &mut *(0xb8000 as *mut Buffer)}));
+        core::array::from_fn(|_| IrqSpinLock::new(Writer::new(screen.take())))
+    };
+
+    /// The console `print!` writes to
+    pub static ref WRITER: &'static IrqSpinLock<Writer> = &CONSOLES[LOG_CONSOLE];
+}
+
+// Index of the console currently shown on screen. Also serializes console switches
+static ACTIVE_CONSOLE: IrqSpinLock<usize> = IrqSpinLock::new(0);
+
+/// Returns the index of the console currently shown on screen
+pub fn active_console() -> usize {
+    *ACTIVE_CONSOLE.lock()
+}
+
+/// Shows the console with the given index on screen
+pub fn switch_console(index: usize) {
+    use x86_64::instructions::interrupts;
+
+    if index >= NUM_CONSOLES {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let mut active = ACTIVE_CONSOLE.lock();
+        if *active == index {
+            return;
+        }
+        let screen = CONSOLES[*active].lock().release_screen();
+        CONSOLES[index].lock().attach_screen(screen);
+        *active = index;
+    });
+}
+
+/// Shows the consoles on `framebuffer` instead of the VGA text buffer from now on
+///
+/// The console state lives on the heap, so this must only be called once the heap is initialized.
+pub fn use_framebuffer(framebuffer: Framebuffer) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let active = ACTIVE_CONSOLE.lock();
+        let screen = Screen::Graphics(Box::new(TextConsole::new(framebuffer)));
+        CONSOLES[*active].lock().attach_screen(Some(screen));
+    });
+}
+
+/// Starts keeping scrollback history on every console
+///
+/// The history lives on the heap, so this must only be called once the heap is initialized.
+pub fn enable_scrollback() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        for console in CONSOLES.iter() {
+            console.lock().enable_scrollback();
+        }
     });
 }
 
@@ -185,8 +845,135 @@ fn test_println_output() { // ensure what was passed to println was actually pri
         for (i, c) in s.chars().enumerate() {
             // println prints to the last screen line and then immediately appends a newline, the
             // string should appear on line BUFFER_HEIGHT - 2
-            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+            let screen_char = writer.text_screen_char(BUFFER_HEIGHT - 2, i);
             assert_eq!(char::from(screen_char.ascii_character), c);
         }
     });
 }
+
+#[test_case]
+fn test_write_str_at() { // text written at an arbitrary position lands there with its own color
+    use x86_64::instructions::interrupts;
+
+    let s = "positioned";
+    let color_code = ColorCode::new(Color::White, Color::Blue);
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_str_at(3, 70, s, color_code);
+        for (i, c) in s.chars().take(BUFFER_WIDTH - 70).enumerate() {
+            let screen_char = writer.chars[3][70 + i];
+            assert_eq!(char::from(screen_char.ascii_character), c);
+            assert_eq!(screen_char.color_code, color_code);
+        }
+    });
+}
+
+#[test_case]
+fn test_draw_box() { // corners and edges of a box are drawn with the box drawing characters
+    use x86_64::instructions::interrupts;
+
+    let color_code = ColorCode::new(Color::LightGray, Color::Black);
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.draw_box(2, 4, 3, 5, BoxStyle::Single, color_code);
+        let glyph = |row: usize, col: usize| writer.read_byte_at(row, col);
+        assert_eq!(glyph(2, 4), 0xda);
+        assert_eq!(glyph(2, 8), 0xbf);
+        assert_eq!(glyph(4, 4), 0xc0);
+        assert_eq!(glyph(4, 8), 0xd9);
+        assert_eq!(glyph(2, 6), 0xc4);
+        assert_eq!(glyph(3, 4), 0xb3);
+    });
+}
+
+#[test_case]
+fn test_cursor_follows_output() { // the hardware cursor sits right after the printed text
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        write!(writer, "\nabc").expect("write failed");
+        assert_eq!(cursor_position(), (BUFFER_HEIGHT - 1, 3));
+    });
+}
+
+#[test_case]
+fn test_redraw_in_place() { // moving the print position back overwrites the row in place
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        write!(writer, "\nabcdef").expect("write failed");
+        writer.set_column(2);
+        write!(writer, "X").expect("write failed");
+        writer.clear_to_end_of_row();
+        assert_eq!(writer.column(), 3);
+        assert_eq!(cursor_position(), (BUFFER_HEIGHT - 1, 3));
+        assert_eq!(writer.read_byte_at(BUFFER_HEIGHT - 1, 1), b'b');
+        assert_eq!(writer.read_byte_at(BUFFER_HEIGHT - 1, 2), b'X');
+        assert_eq!(writer.read_byte_at(BUFFER_HEIGHT - 1, 3), b' ');
+    });
+}
+
+#[test_case]
+fn test_consoles_do_not_interleave() { // output to a hidden console neither shows up on screen
+                                       // nor in the log console
+    use x86_64::instructions::interrupts;
+
+    console_println!(1, "hidden console output");
+    interrupts::without_interrupts(|| {
+        assert!(!CONSOLES[1].lock().is_visible());
+        assert_eq!(CONSOLES[1].lock().read_byte_at(BUFFER_HEIGHT - 2, 0), b'h');
+    });
+    println!("log console output");
+
+    switch_console(1);
+    interrupts::without_interrupts(|| {
+        let console = CONSOLES[1].lock();
+        assert_eq!(console.text_screen_char(BUFFER_HEIGHT - 2, 0).ascii_character, b'h');
+    });
+    switch_console(LOG_CONSOLE);
+    interrupts::without_interrupts(|| {
+        let writer = WRITER.lock();
+        assert_eq!(writer.text_screen_char(BUFFER_HEIGHT - 2, 0).ascii_character, b'l');
+    });
+}
+
+#[test_case]
+fn test_selection_is_highlighted() { // selected cells are shown in the selection colors, the
+                                     // console contents keep their own
+    use x86_64::instructions::interrupts;
+
+    let color_code = ColorCode::new(Color::White, Color::Blue);
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_str_at(5, 78, "ab", color_code);
+        writer.write_str_at(6, 0, "cd", color_code);
+        writer.set_selection(Some(((6, 0), (5, 79))));
+        assert_eq!(writer.text_screen_char(5, 78).color_code, color_code);
+        assert_eq!(writer.text_screen_char(5, 79).color_code, SELECTION_COLOR);
+        assert_eq!(writer.text_screen_char(6, 0).color_code, SELECTION_COLOR);
+        assert_eq!(writer.text_screen_char(6, 1).color_code, color_code);
+        assert_eq!(writer.chars[5][79].color_code, color_code);
+        writer.set_selection(None);
+        assert_eq!(writer.text_screen_char(5, 79).color_code, color_code);
+    });
+}
+
+#[test_case]
+fn test_new_line_discards_selection() { // output drops the selection and tells the mouse task so
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.set_selection(Some(((3, 0), (3, 9))));
+        let generation = writer.selection_generation();
+        writer.new_line();
+        assert_eq!(writer.selection, None);
+        assert_ne!(writer.selection_generation(), generation);
+    });
+}
@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(NeekOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use NeekOS::acpi;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use NeekOS::allocator;
+    use NeekOS::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    NeekOS::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    acpi::init().expect("ACPI tables not found");
+
+    test_main();
+    NeekOS::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    NeekOS::test_panic_handler(info)
+}
+
+#[test_case]
+fn madt_lists_cpu_and_io_apic() {
+    let madt = acpi::tables().unwrap().madt.as_ref().expect("no MADT");
+    assert!(madt.processors.iter().any(|processor| processor.enabled));
+    assert!(!madt.io_apics.is_empty());
+    assert_ne!(madt.local_apic_address, 0);
+}
+
+#[test_case]
+fn fadt_has_power_management_registers() {
+    let fadt = acpi::tables().unwrap().fadt.as_ref().expect("no FADT");
+    assert_ne!(fadt.pm1a_control_block, 0);
+    assert_ne!(fadt.dsdt.as_u64(), 0);
+}
+
+#[test_case]
+fn init_returns_same_tables() {
+    let first = acpi::tables().unwrap() as *const acpi::AcpiTables;
+    assert_eq!(acpi::init().unwrap() as *const acpi::AcpiTables, first);
+}
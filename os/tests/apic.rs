@@ -0,0 +1,45 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(NeekOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use NeekOS::{apic, time};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use NeekOS::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    NeekOS::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    apic::init(&mut mapper, &mut frame_allocator).expect("APIC initialization failed");
+
+    test_main();
+    NeekOS::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    NeekOS::test_panic_handler(info)
+}
+
+#[test_case]
+fn apic_is_enabled() {
+    assert!(apic::is_supported());
+    assert!(apic::is_enabled());
+}
+
+#[test_case]
+fn local_apic_timer_keeps_ticking() {
+    let start = time::ticks();
+    time::sleep(50);
+    assert!(time::ticks() > start);
+}
@@ -0,0 +1,122 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(NeekOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use NeekOS::block::{self, BlockError, SECTOR_SIZE};
+use NeekOS::{ata, driver, interrupts};
+
+entry_point!(main);
+
+// The size of tests/scratch.img, 64 KiB (see the README for how to make it)
+const SCRATCH_SECTORS: u64 = 128;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use NeekOS::allocator;
+    use NeekOS::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    NeekOS::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    driver::init();
+
+    test_main();
+    NeekOS::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    NeekOS::test_panic_handler(info)
+}
+
+// QEMU boots the kernel from a raw disk image on the master drive of the primary IDE channel.
+// The tests only read from it; they write to the scratch drive the test-args in Cargo.toml put
+// behind it as the slave, tests/scratch.img, whose writes are thrown away when QEMU exits
+
+#[test_case]
+fn boot_disk_is_identified() {
+    let info = ata::drives().next().expect("no ATA drive").info().unwrap();
+    assert_eq!(info.model, "QEMU HARDDISK");
+    assert!(info.sectors > 0);
+    let disk = block::find("ata0").expect("ata0 not registered");
+    assert_eq!(disk.sector_count(), info.sectors);
+    assert!(interrupts::has_irq_handler(14));
+    let node = driver::find_device("ata0").unwrap();
+    assert_eq!(node.parent, Some(driver::find_device("ide0").unwrap().id));
+}
+
+#[test_case]
+fn boot_sector_has_its_signature() {
+    let disk = block::find("ata0").unwrap();
+    let mut sector = [0; SECTOR_SIZE];
+    disk.read(0, &mut sector).unwrap();
+    assert_eq!(&sector[510..], &[0x55, 0xaa]);
+}
+
+#[test_case]
+fn multi_sector_reads_match_single_ones() {
+    let disk = block::find("ata0").unwrap();
+    // More than one command's worth
+    let count = 300.min(disk.sector_count() as usize);
+    let mut all = vec![0; count * SECTOR_SIZE];
+    disk.read(0, &mut all).unwrap();
+    for lba in [0, 1, 255, 256, count - 1].iter().copied() {
+        let mut sector = [0; SECTOR_SIZE];
+        disk.read(lba as u64, &mut sector).unwrap();
+        assert_eq!(&all[lba * SECTOR_SIZE..][..SECTOR_SIZE], &sector[..]);
+    }
+}
+
+#[test_case]
+fn scratch_drive_is_found() {
+    let scratch = block::find("ata1").expect("scratch drive not registered");
+    assert_eq!(scratch.sector_count(), SCRATCH_SECTORS);
+}
+
+#[test_case]
+fn written_sectors_read_back() {
+    let disk = block::find("ata1").unwrap();
+    let lba = disk.sector_count() - 2;
+    let pattern: alloc::vec::Vec<u8> = (0..2 * SECTOR_SIZE).map(|i| (i * 7) as u8).collect();
+    disk.write(lba, &pattern).unwrap();
+    disk.flush().unwrap();
+    let mut read_back = vec![0; 2 * SECTOR_SIZE];
+    disk.read(lba, &mut read_back).unwrap();
+    assert_eq!(read_back, pattern);
+}
+
+#[test_case]
+fn bad_requests_are_refused() {
+    let disk = block::find("ata1").unwrap();
+    let end = disk.sector_count();
+    let mut sector = [0; SECTOR_SIZE];
+    assert_eq!(disk.read(end, &mut sector), Err(BlockError::OutOfRange { lba: end, count: 1 }));
+    assert_eq!(disk.read(0, &mut sector[..100]), Err(BlockError::BufferSize(100)));
+    assert_eq!(disk.write(0, &[]), Err(BlockError::BufferSize(0)));
+}
+
+#[test_case]
+fn detached_drives_refuse_requests() {
+    let disk = block::find("ata1").unwrap();
+    let ide0 = driver::find_device("ide0").unwrap().id;
+    assert_eq!(driver::detach(ide0), Ok(()));
+    assert!(block::find("ata1").is_none());
+    let mut sector = [0; SECTOR_SIZE];
+    assert_eq!(disk.read(0, &mut sector), Err(BlockError::NotPresent));
+    assert_eq!(disk.write(0, &sector), Err(BlockError::NotPresent));
+    assert_eq!(disk.flush(), Err(BlockError::NotPresent));
+    assert_eq!(driver::bind(ide0), Ok("ata"));
+    assert_eq!(disk.read(0, &mut sector), Ok(()));
+}
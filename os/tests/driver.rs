@@ -0,0 +1,189 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(NeekOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use NeekOS::driver::{self, Device, DeviceKind, Driver, DriverError, IsaDevice, Match};
+use NeekOS::{acpi, interrupts, pci, serial};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use NeekOS::allocator;
+    use NeekOS::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    NeekOS::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    acpi::init().expect("ACPI tables not found");
+    pci::init(&mut mapper, &mut frame_allocator).expect("PCI scan failed");
+    driver::init();
+
+    test_main();
+    NeekOS::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    NeekOS::test_panic_handler(info)
+}
+
+/// Counts its calls, and only takes devices whose port is even
+struct TestDriver {
+    attached: AtomicUsize,
+    detached: AtomicUsize,
+}
+
+impl Driver for TestDriver {
+    fn name(&self) -> &'static str {
+        "test"
+    }
+
+    fn matches(&self) -> &'static [Match] {
+        &[Match::Isa("test0"), Match::Isa("test1")]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), DriverError> {
+        match device.kind {
+            DeviceKind::Isa(IsaDevice { port, .. }) if port % 2 == 0 => Ok(()),
+            _ => Err(DriverError::NotSupported),
+        }
+    }
+
+    fn attach(&self, _device: &Device) -> Result<(), DriverError> {
+        self.attached.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn detach(&self, _device: &Device) -> Result<(), DriverError> {
+        self.detached.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+static TEST_DRIVER: TestDriver = TestDriver {
+    attached: AtomicUsize::new(0),
+    detached: AtomicUsize::new(0),
+};
+
+/// Fails to let go of devices at port 0x200
+struct StuckDriver;
+
+impl Driver for StuckDriver {
+    fn name(&self) -> &'static str {
+        "stuck"
+    }
+
+    fn matches(&self) -> &'static [Match] {
+        &[Match::Isa("stuck0"), Match::Isa("stuck1")]
+    }
+
+    fn attach(&self, _device: &Device) -> Result<(), DriverError> {
+        Ok(())
+    }
+
+    fn detach(&self, device: &Device) -> Result<(), DriverError> {
+        match device.kind {
+            DeviceKind::Isa(IsaDevice { port: 0x200, .. }) => {
+                Err(DriverError::Device("busy".into()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn isa_device(name: &'static str, port: u16) -> DeviceKind {
+    DeviceKind::Isa(IsaDevice { name, port, irq: None })
+}
+
+#[test_case]
+fn tree_has_the_buses_and_pci_functions() {
+    let devices = driver::devices();
+    assert_eq!(devices[driver::ROOT].name, "system");
+    let pci_bus = driver::find_device("pci").expect("no PCI bus");
+    let functions = devices.iter().filter(|device| device.parent == Some(pci_bus.id)).count();
+    assert_eq!(functions, pci::devices().len());
+    // Parents come before their children
+    assert!(devices.iter().all(|device| device.parent.is_none_or(|parent| parent < device.id)));
+}
+
+#[test_case]
+fn builtin_drivers_are_bound() {
+    assert_eq!(driver::drivers(), ["ps2", "keyboard", "mouse", "serial", "ata"]);
+    let ps2 = driver::find_device("ps2").unwrap();
+    let keyboard = driver::find_device("keyboard").expect("no keyboard");
+    assert_eq!((ps2.driver(), keyboard.driver()), (Some("ps2"), Some("keyboard")));
+    assert_eq!(keyboard.parent, Some(ps2.id));
+    assert_eq!(driver::find_device("com1").unwrap().driver(), Some("serial"));
+    // QEMU only emulates the ports it is given with -serial
+    let com4 = driver::find_device("com4").unwrap();
+    assert_eq!((com4.driver(), com4.error), (None, None));
+    assert!(serial::port("com1").unwrap().is_receiving());
+}
+
+#[test_case]
+fn devices_added_later_are_bound_when_the_driver_is_registered() {
+    let isa = driver::find_device("isa").unwrap().id;
+    let early = driver::add_device(isa, "test0", isa_device("test0", 0x100));
+    assert_eq!(driver::bind(early), Err(DriverError::NotSupported));
+    driver::register(&TEST_DRIVER);
+    assert_eq!(driver::device(early).unwrap().driver(), Some("test"));
+    // Rejected by probe
+    let odd = driver::add_device(isa, "test1", isa_device("test1", 0x101));
+    assert_eq!(driver::device(odd).unwrap().driver(), None);
+    assert_eq!(driver::bind(odd), Err(DriverError::NotSupported));
+    assert_eq!(TEST_DRIVER.attached.load(Ordering::Relaxed), 1);
+}
+
+#[test_case]
+fn detach_and_bind_again() {
+    let id = driver::find_device("test0").unwrap().id;
+    assert_eq!(driver::detach(id), Ok(()));
+    assert_eq!(TEST_DRIVER.detached.load(Ordering::Relaxed), 1);
+    assert_eq!(driver::device(id).unwrap().driver(), None);
+    assert_eq!(driver::bind(id), Ok("test"));
+    assert_eq!(TEST_DRIVER.attached.load(Ordering::Relaxed), 2);
+}
+
+#[test_case]
+fn failed_detach_keeps_the_device_and_its_parent_bound() {
+    let parent = driver::find_device("test0").unwrap().id;
+    driver::register(&StuckDriver);
+    let stuck = driver::add_device(parent, "stuck0", isa_device("stuck0", 0x200));
+    let sibling = driver::add_device(parent, "stuck1", isa_device("stuck1", 0x202));
+    let error = DriverError::Device("busy".into());
+    assert_eq!(driver::detach(parent), Err(vec![(stuck, error.clone())]));
+    // The sibling is detached all the same
+    assert_eq!(driver::device(sibling).unwrap().driver(), None);
+    let stuck = driver::device(stuck).unwrap();
+    assert_eq!((stuck.driver(), stuck.error), (Some("stuck"), Some(error)));
+    assert_eq!(driver::device(parent).unwrap().driver(), Some("test"));
+    assert_eq!(TEST_DRIVER.detached.load(Ordering::Relaxed), 1);
+}
+
+#[test_case]
+fn detaching_the_ps2_controller_detaches_its_devices() {
+    let ps2 = driver::find_device("ps2").unwrap().id;
+    assert_eq!(driver::detach(ps2), Ok(()));
+    assert_eq!(driver::find_device("keyboard").unwrap().driver(), None);
+    assert!(!interrupts::has_irq_handler(1));
+    // Binding it again finds the keyboard it added before rather than adding another one
+    let count = driver::devices().len();
+    assert_eq!(driver::bind(ps2), Ok("ps2"));
+    assert_eq!(driver::devices().len(), count);
+    assert_eq!(driver::find_device("keyboard").unwrap().driver(), Some("keyboard"));
+    assert!(interrupts::has_irq_handler(1));
+}
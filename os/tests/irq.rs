@@ -0,0 +1,89 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(NeekOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use NeekOS::interrupts::{self, IrqError};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use NeekOS::allocator;
+    use NeekOS::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    NeekOS::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    NeekOS::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    NeekOS::test_panic_handler(info)
+}
+
+// IRQ 5 is not wired to any device QEMU emulates by default, so the test raises it in software
+const TEST_IRQ: u8 = 5;
+
+static FIRST_CALLS: AtomicUsize = AtomicUsize::new(0);
+static SECOND_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+fn first_handler() {
+    FIRST_CALLS.fetch_add(1, Ordering::SeqCst);
+}
+
+fn second_handler() {
+    SECOND_CALLS.fetch_add(1, Ordering::SeqCst);
+}
+
+fn raise_test_irq() {
+    unsafe { core::arch::asm!("int {}", const interrupts::PIC_1_OFFSET + TEST_IRQ) };
+}
+
+#[test_case]
+fn shared_irq_calls_all_handlers() {
+    let first = interrupts::register_irq(TEST_IRQ, first_handler).unwrap();
+    let second = interrupts::register_irq(TEST_IRQ, second_handler).unwrap();
+    assert!(interrupts::has_irq_handler(TEST_IRQ));
+    let count = interrupts::irq_count(TEST_IRQ);
+
+    raise_test_irq();
+    assert_eq!(FIRST_CALLS.load(Ordering::SeqCst), 1);
+    assert_eq!(SECOND_CALLS.load(Ordering::SeqCst), 1);
+    assert_eq!(interrupts::irq_count(TEST_IRQ), count + 1);
+
+    assert!(interrupts::unregister_irq(first));
+    assert!(!interrupts::unregister_irq(first));
+    raise_test_irq();
+    assert_eq!(FIRST_CALLS.load(Ordering::SeqCst), 1);
+    assert_eq!(SECOND_CALLS.load(Ordering::SeqCst), 2);
+
+    assert!(interrupts::unregister_irq(second));
+    assert!(!interrupts::has_irq_handler(TEST_IRQ));
+}
+
+#[test_case]
+fn reserved_irqs_are_rejected() {
+    assert_eq!(interrupts::register_irq(0, first_handler), Err(IrqError::Reserved(0)));
+    assert_eq!(interrupts::register_irq(2, first_handler), Err(IrqError::Reserved(2)));
+    assert_eq!(interrupts::register_irq(16, first_handler), Err(IrqError::InvalidIrq(16)));
+}
+
+#[test_case]
+fn timer_interrupts_are_counted() {
+    let start = interrupts::irq_count(0);
+    NeekOS::time::sleep(30);
+    assert!(interrupts::irq_count(0) > start);
+}
@@ -0,0 +1,72 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(NeekOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use NeekOS::{acpi, pci};
+use NeekOS::pci::{Bar, PciAddress};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use NeekOS::allocator;
+    use NeekOS::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    NeekOS::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    acpi::init().expect("ACPI tables not found");
+    pci::init(&mut mapper, &mut frame_allocator).expect("PCI scan failed");
+
+    test_main();
+    NeekOS::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    NeekOS::test_panic_handler(info)
+}
+
+// QEMU's default machine (i440FX): a host bridge at 00:00.0, the PIIX3 ISA bridge and IDE
+// controller at 00:01.x, and the Bochs VGA card
+
+#[test_case]
+fn host_bridge_is_found() {
+    let host = pci::devices().iter().find(|device| device.address == PciAddress::new(0, 0, 0, 0))
+        .expect("nothing at 0000:00:00.0");
+    assert_eq!((host.class, host.subclass), (0x06, 0x00));
+    assert_eq!(host.class_name(), "host bridge");
+}
+
+#[test_case]
+fn config_reads_match_the_scan() {
+    for device in pci::devices() {
+        let id = pci::read_config(device.address, 0);
+        assert_eq!(id, u32::from(device.device_id) << 16 | u32::from(device.vendor_id));
+    }
+    // Nothing answers at the last device of the last bus
+    assert_eq!(pci::read_u16(PciAddress::new(0, 255, 31, 0), 0), 0xffff);
+}
+
+#[test_case]
+fn vga_framebuffer_bar_is_sized() {
+    let vga = pci::find_device(0x1234, 0x1111).expect("no Bochs VGA");
+    match vga.bars[0] {
+        Some(Bar::Memory { address, size, prefetchable, .. }) => {
+            assert_ne!(address, 0);
+            assert!(size.is_power_of_two() && size >= 0x10_0000);
+            assert!(prefetchable);
+        }
+        other => panic!("unexpected BAR0 {:?}", other),
+    }
+    assert_eq!(pci::find_class(0x03, 0x00).count(), 1);
+}
@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(NeekOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use NeekOS::println;
+use NeekOS::vga_buffer::{WRITER, BUFFER_HEIGHT};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use NeekOS::allocator;
+    use NeekOS::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    NeekOS::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    NeekOS::vga_buffer::enable_scrollback();
+
+    test_main();
+    NeekOS::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    NeekOS::test_panic_handler(info)
+}
+
+/// Returns the first character of `row` as currently shown on screen
+fn first_char(row: usize) -> u8 {
+    x86_64::instructions::interrupts::without_interrupts(|| WRITER.lock().read_byte_at(row, 0))
+}
+
+#[test_case]
+fn scroll_up_shows_history() {
+    // Fill the screen with lines starting with 'a', then push them into the history with 'b's.
+    // The last row is always left empty for the next line, so BUFFER_HEIGHT - 1 lines of 'b'
+    // cover the visible screen
+    for _ in 0..BUFFER_HEIGHT {
+        println!("a");
+    }
+    for _ in 0..BUFFER_HEIGHT - 1 {
+        println!("b");
+    }
+    assert_eq!(first_char(0), b'b');
+
+    x86_64::instructions::interrupts::without_interrupts(|| WRITER.lock().scroll_up(1));
+    assert_eq!(first_char(0), b'a');
+    assert_eq!(first_char(1), b'b');
+
+    x86_64::instructions::interrupts::without_interrupts(|| WRITER.lock().scroll_down(1));
+    assert_eq!(first_char(0), b'b');
+}
+
+#[test_case]
+fn new_output_returns_to_live() {
+    for _ in 0..BUFFER_HEIGHT {
+        println!("c");
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        WRITER.lock().scroll_up(BUFFER_HEIGHT)
+    });
+    assert!(x86_64::instructions::interrupts::without_interrupts(|| {
+        WRITER.lock().is_scrolled_back()
+    }));
+
+    println!("d");
+    assert!(!x86_64::instructions::interrupts::without_interrupts(|| {
+        WRITER.lock().is_scrolled_back()
+    }));
+    assert_eq!(first_char(BUFFER_HEIGHT - 2), b'd');
+    assert_eq!(first_char(BUFFER_HEIGHT - 3), b'c');
+}
@@ -0,0 +1,81 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(NeekOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use NeekOS::{interrupts, serial};
+use NeekOS::serial::{FifoTrigger, SerialConfig, SerialError};
+use NeekOS::sync::block_on;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use NeekOS::allocator;
+    use NeekOS::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    NeekOS::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    NeekOS::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    NeekOS::test_panic_handler(info)
+}
+
+#[test_case]
+fn com1_is_found() {
+    let com1 = serial::port("com1").expect("no COM1");
+    assert_eq!((com1.base(), com1.irq()), (0x3f8, 4));
+    assert_eq!(com1.config(), SerialConfig::default());
+    assert!(core::ptr::eq(serial::log_port(), com1));
+    // QEMU only emulates the ports it is given with -serial
+    assert!(serial::port("com4").is_none());
+    assert_eq!(serial::set_log_port("com4"), Err(SerialError::NotPresent));
+}
+
+#[test_case]
+fn start_receiving_registers_the_irq_handler() {
+    assert!(serial::start_receiving().is_ok());
+    assert!(interrupts::has_irq_handler(4));
+}
+
+#[test_case]
+fn received_bytes_come_out_in_order() {
+    let com1 = serial::port("com1").unwrap();
+    while com1.try_receive().is_some() {}
+    for &byte in b"mem\r".iter() {
+        assert!(com1.add_received(byte));
+    }
+    assert_eq!(block_on(com1.receive()), b'm');
+    assert_eq!(com1.try_receive(), Some(b'e'));
+    assert_eq!(block_on(com1.receive()), b'm');
+    assert_eq!(block_on(com1.receive()), b'\r');
+    assert_eq!(com1.try_receive(), None);
+    assert_eq!(com1.console(), None);
+}
+
+#[test_case]
+fn configure_checks_the_baud_rate() {
+    let com1 = serial::port("com1").unwrap();
+    let config = SerialConfig { baud: 7000, ..SerialConfig::default() };
+    assert_eq!(com1.configure(config), Err(SerialError::InvalidBaud(7000)));
+    // The test output goes out over COM1, so it has to stay usable
+    let config = SerialConfig { baud: 115_200, fifo_trigger: FifoTrigger::Bytes8,
+                                ..SerialConfig::default() };
+    assert_eq!(com1.configure(config), Ok(()));
+    assert_eq!(com1.config(), config);
+    assert_eq!(com1.configure(SerialConfig::default()), Ok(()));
+}
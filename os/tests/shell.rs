@@ -0,0 +1,159 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(NeekOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use alloc::string::String;
+use NeekOS::shell::{self, LineEditor, Shell, ShellError};
+use NeekOS::sync::block_on;
+use NeekOS::tty::{self, TtyError, TtyMode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use NeekOS::allocator;
+    use NeekOS::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    NeekOS::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    NeekOS::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    NeekOS::test_panic_handler(info)
+}
+
+// A console that is not shown, so the output stays off screen
+fn test_shell() -> Shell {
+    Shell::new(1)
+}
+
+#[test_case]
+fn commands_are_sorted_and_found() {
+    let names: alloc::vec::Vec<&str> =
+        shell::commands().iter().map(|command| command.name).collect();
+    assert!(names.windows(2).all(|pair| pair[0] < pair[1]));
+    assert!(shell::find_command("help").is_some());
+    assert!(shell::find_command("nope").is_none());
+}
+
+#[test_case]
+fn parse_number_accepts_hex_and_decimal() {
+    assert_eq!(shell::parse_number("0xb8000"), Ok(0xb8000));
+    assert_eq!(shell::parse_number("1_000"), Ok(1000));
+    assert!(shell::parse_number("0xg").is_err());
+}
+
+#[test_case]
+fn informational_commands_run() {
+    let shell = test_shell();
+    for line in ["help", "help peek", "mem", "irq", "pci", "devices", "disks", "uptime",
+                 "pagemap 0xb8000", ""].iter() {
+        assert_eq!(shell.execute(line), Ok(()));
+    }
+}
+
+#[test_case]
+fn poke_and_peek_memory() {
+    static mut TARGET: [u8; 4] = [0; 4];
+    let shell = test_shell();
+    let address = (&raw const TARGET) as u64;
+    let line = alloc::format!("poke {:#x} 0x2a", address + 1);
+    assert_eq!(shell.execute(&line), Ok(()));
+    assert_eq!(unsafe { TARGET[1] }, 0x2a);
+    assert_eq!(shell.execute(&alloc::format!("peek {:#x} 4", address)), Ok(()));
+    assert!(matches!(shell.execute("poke 0x1234 0x100"), Err(ShellError::InvalidNumber(_))));
+}
+
+#[test_case]
+fn bad_input_is_reported() {
+    let shell = test_shell();
+    assert_eq!(shell.execute("frobnicate"),
+               Err(ShellError::UnknownCommand("frobnicate".into())));
+    assert!(matches!(shell.execute("color"), Err(ShellError::Usage(_))));
+    assert!(matches!(shell.execute("color plaid black"), Err(ShellError::UnknownColor(_))));
+    // Right below the canonical hole, nothing is mapped there
+    assert_eq!(shell.execute("peek 0x7fff_ffff_f000"),
+               Err(ShellError::NotMapped(0x7fff_ffff_f000)));
+    assert_eq!(shell.execute("color white blue"), Ok(()));
+}
+
+// Types `input` on the hidden console and lets the editor read a line from it. The TTY is put in
+// raw mode first, so the keys reach the editor untouched
+fn read_line(editor: &mut LineEditor, input: &str) -> Result<String, TtyError> {
+    let tty = tty::tty(1);
+    tty.set_mode(TtyMode::Raw);
+    tty.input_str(input);
+    let line = block_on(editor.read_line("> ", &shell::complete));
+    tty.set_mode(TtyMode::Canonical);
+    line
+}
+
+#[test_case]
+fn line_editing_moves_the_cursor() {
+    let mut editor = LineEditor::new(1);
+    // Left, Home, Delete and End
+    assert_eq!(read_line(&mut editor, "hep\x1b[Dl\x1b[H\x1b[3~x\x1b[F!\r"),
+               Ok("xelp!".into()));
+    // Ctrl+W erases the word before the cursor, Backspace the character
+    assert_eq!(read_line(&mut editor, "peek 0x10\x17\x08 0x20\r"), Ok("peek 0x20".into()));
+    assert_eq!(read_line(&mut editor, "mem\x03"), Err(TtyError::Interrupted));
+}
+
+#[test_case]
+fn history_is_navigated_with_the_arrows() {
+    let mut editor = LineEditor::new(1);
+    assert_eq!(read_line(&mut editor, "one\r"), Ok("one".into()));
+    assert_eq!(read_line(&mut editor, "two\r"), Ok("two".into()));
+    assert_eq!(read_line(&mut editor, "\x1b[A\x1b[A\r"), Ok("one".into()));
+    assert_eq!(read_line(&mut editor, "\x1b[A\x1b[A\x1b[A\x1b[B\r"), Ok("two".into()));
+    // Going past the newest line brings back what was being typed
+    assert_eq!(read_line(&mut editor, "dr\x1b[A\x1b[B\r"), Ok("dr".into()));
+    assert_eq!(editor.history().collect::<alloc::vec::Vec<_>>(),
+               ["one", "two", "one", "two", "dr"]);
+
+    for number in 0..shell::HISTORY_SIZE + 1 {
+        editor.add_history(&alloc::format!("line {}", number));
+    }
+    assert_eq!(editor.history().count(), shell::HISTORY_SIZE);
+    assert_eq!(editor.history().next(), Some("line 1"));
+}
+
+#[test_case]
+fn reverse_search_finds_older_matches() {
+    let mut editor = LineEditor::new(1);
+    for line in ["peek 0x1", "poke 0x2", "help"].iter() {
+        editor.add_history(line);
+    }
+    assert_eq!(read_line(&mut editor, "\x12p\r"), Ok("help".into()));
+    assert_eq!(read_line(&mut editor, "\x12pe\x12\r"), Ok("peek 0x1".into()));
+    // Any other key keeps the match for editing, Ctrl+G gives up on it
+    assert_eq!(read_line(&mut editor, "\x12pok\x1b[D\x08\r"), Ok("poke 02".into()));
+    assert_eq!(read_line(&mut editor, "mem\x12pe\x07\r"), Ok("mem".into()));
+}
+
+#[test_case]
+fn tab_completes_against_the_commands() {
+    let mut editor = LineEditor::new(1);
+    assert_eq!(read_line(&mut editor, "he\t\r"), Ok("help ".into()));
+    assert_eq!(read_line(&mut editor, "help reb\t\r"), Ok("help reboot ".into()));
+    // Ambiguous: the first Tab lists pagemap, peek and poke
+    assert_eq!(read_line(&mut editor, "p\to\t\r"), Ok("poke ".into()));
+    assert_eq!(read_line(&mut editor, "color light\tb\t\r"), Ok("color lightblue ".into()));
+    assert_eq!(shell::complete("peek "), alloc::vec::Vec::<String>::new());
+}
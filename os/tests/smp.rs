@@ -0,0 +1,59 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(NeekOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use NeekOS::{acpi, apic, smp};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use NeekOS::allocator;
+    use NeekOS::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    NeekOS::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    acpi::init().expect("ACPI tables not found");
+    apic::init(&mut mapper, &mut frame_allocator).expect("APIC initialization failed");
+    smp::init(&boot_info.memory_map, &mut mapper, &mut frame_allocator)
+        .expect("starting the other CPUs failed");
+
+    test_main();
+    NeekOS::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    NeekOS::test_panic_handler(info)
+}
+
+#[test_case]
+fn all_cpus_online() {
+    let madt = acpi::tables().unwrap().madt.as_ref().unwrap();
+    let enabled = madt.processors.iter().filter(|processor| processor.enabled).count();
+    assert_eq!(smp::cpu_count(), enabled);
+}
+
+#[test_case]
+fn cpus_have_own_per_cpu_data() {
+    use NeekOS::percpu;
+    assert_eq!(percpu::cpu_id(), 0);
+    for id in 0..smp::cpu_count() {
+        let cpu = percpu::cpu(id);
+        assert_eq!(cpu.id(), id);
+        assert!(!cpu.scratch_stack_top().is_null());
+        for other in 0..id {
+            assert_ne!(percpu::cpu(other).apic_id(), cpu.apic_id());
+        }
+    }
+}
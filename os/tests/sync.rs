@@ -0,0 +1,154 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(NeekOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
+use NeekOS::sync::{block_on, ArrayQueue, AtomicWaker, CondVar, Mutex, RcuCell, Semaphore};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use NeekOS::allocator;
+    use NeekOS::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    NeekOS::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    NeekOS::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    NeekOS::test_panic_handler(info)
+}
+
+/// Polls `future` once, without anybody to wake it
+fn poll_once<F: Future>(future: core::pin::Pin<&mut F>) -> Poll<F::Output> {
+    future.poll(&mut Context::from_waker(Waker::noop()))
+}
+
+#[test_case]
+fn mutex_lock_and_try_lock() {
+    let mutex = Mutex::new(1);
+    let mut guard = block_on(mutex.lock());
+    *guard += 1;
+    assert!(mutex.try_lock().is_none());
+    drop(guard);
+    assert_eq!(*mutex.try_lock().unwrap(), 2);
+}
+
+#[test_case]
+fn mutex_waiter_gets_lock_after_unlock() {
+    let mutex = Mutex::new(());
+    let guard = mutex.try_lock().unwrap();
+    let mut waiter = pin!(mutex.lock());
+    assert!(poll_once(waiter.as_mut()).is_pending());
+    drop(guard);
+    assert!(poll_once(waiter.as_mut()).is_ready());
+}
+
+#[test_case]
+fn semaphore_counts_permits() {
+    let semaphore = Semaphore::new(2);
+    let first = block_on(semaphore.acquire());
+    let _second = block_on(semaphore.acquire());
+    assert!(semaphore.try_acquire().is_none());
+    drop(first);
+    assert_eq!(semaphore.available_permits(), 1);
+    semaphore.try_acquire().unwrap().forget();
+    assert_eq!(semaphore.available_permits(), 0);
+}
+
+#[test_case]
+fn condvar_wakes_waiter() {
+    let mutex = Mutex::new(false);
+    let condvar = CondVar::new();
+    let guard = mutex.try_lock().unwrap();
+    let mut waiter = pin!(condvar.wait(guard));
+    assert!(poll_once(waiter.as_mut()).is_pending());
+    // The waiter released the mutex while it waits
+    *mutex.try_lock().unwrap() = true;
+    condvar.notify_one();
+    let notified = match poll_once(waiter.as_mut()) {
+        Poll::Ready(guard) => *guard,
+        Poll::Pending => panic!("waiter was not woken"),
+    };
+    assert!(notified);
+}
+
+#[test_case]
+fn block_on_polls_again_after_interrupts() {
+    // Nobody wakes this future, it relies on the timer interrupt waking the halted CPU
+    let target = NeekOS::time::ticks() + 2;
+    let ticks = block_on(core::future::poll_fn(|_| {
+        let now = NeekOS::time::ticks();
+        if now >= target { Poll::Ready(now) } else { Poll::Pending }
+    }));
+    assert!(ticks >= target);
+}
+
+#[test_case]
+fn array_queue_moves_owned_values() {
+    use alloc::boxed::Box;
+    let queue: ArrayQueue<Box<u32>, 2> = ArrayQueue::new();
+    queue.push(Box::new(1)).unwrap();
+    queue.push(Box::new(2)).unwrap();
+    assert_eq!(queue.push(Box::new(3)).map_err(|value| *value), Err(3));
+    assert_eq!(queue.pop().map(|value| *value), Some(1));
+    // The value still queued is dropped with the queue
+}
+
+#[test_case]
+fn atomic_waker_wakes_registered_task() {
+    use alloc::sync::Arc;
+    use alloc::task::Wake;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    struct Flag(AtomicBool);
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let atomic_waker = AtomicWaker::new();
+    atomic_waker.wake(); // nobody registered yet
+    atomic_waker.register(&Waker::from(flag.clone()));
+    assert!(!flag.0.load(Ordering::SeqCst));
+    atomic_waker.wake();
+    assert!(flag.0.load(Ordering::SeqCst));
+    assert!(atomic_waker.take().is_none());
+}
+
+#[test_case]
+fn rcu_cell_readers_keep_old_value() {
+    use alloc::vec;
+    let cell = RcuCell::new(vec![1, 2]);
+    {
+        let reader = cell.read();
+        assert_eq!(*reader, [1, 2]);
+        // Nested reads, like an interrupt handler reading during a read, see the same value
+        assert_eq!(cell.read().len(), 2);
+    }
+    cell.update(|old| old.iter().map(|value| value * 10).collect());
+    assert_eq!(*cell.read(), [10, 20]);
+    cell.replace(vec![]);
+    assert!(cell.read().is_empty());
+}
@@ -0,0 +1,96 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(NeekOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use NeekOS::sync::block_on;
+use NeekOS::tty::{self, Tty, TtyError, TtyMode, INPUT_LIMIT};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use NeekOS::allocator;
+    use NeekOS::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    NeekOS::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe {memory::init(phys_mem_offset)};
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    NeekOS::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    NeekOS::test_panic_handler(info)
+}
+
+// A console that is not shown, so the echo stays off screen
+fn test_tty() -> &'static Tty {
+    tty::tty(1)
+}
+
+#[test_case]
+fn canonical_mode_edits_line() {
+    let tty = test_tty();
+    tty.input_str("helo\x08lo wrld\x17world\n");
+    assert_eq!(block_on(tty.read_line()).as_deref(), Ok("hello world"));
+    tty.input_str("discarded\x15kept\r");
+    assert_eq!(block_on(tty.read_line()).as_deref(), Ok("kept"));
+}
+
+#[test_case]
+fn canonical_mode_drops_escape_sequences() {
+    let tty = test_tty();
+    tty.input_str("a\x1b[Ab\x1b[3~c\n");
+    assert_eq!(block_on(tty.read_line()).as_deref(), Ok("abc"));
+}
+
+#[test_case]
+fn ctrl_c_interrupts_reader() {
+    let tty = test_tty();
+    tty.input_str("unfinished\x03");
+    assert_eq!(block_on(tty.read_line()), Err(TtyError::Interrupted));
+    assert!(!tty.take_interrupt());
+    // Nothing of the interrupted line is left
+    tty.input_str("next\n");
+    assert_eq!(block_on(tty.read_line()).as_deref(), Ok("next"));
+}
+
+#[test_case]
+fn raw_mode_passes_everything() {
+    let tty = test_tty();
+    tty.set_mode(TtyMode::Raw);
+    tty.set_echo(false);
+    tty.input_str("\x03\x1b[A");
+    assert_eq!(block_on(tty.read_char()), '\x03');
+    assert_eq!(block_on(tty.read_char()), '\x1b');
+    assert_eq!(tty.try_read_char(), Some('['));
+    assert_eq!(tty.try_read_char(), Some('A'));
+    assert_eq!(tty.try_read_char(), None);
+    tty.set_mode(TtyMode::Canonical);
+    tty.set_echo(true);
+}
+
+#[test_case]
+fn input_beyond_the_limit_is_dropped() {
+    let tty = test_tty();
+    tty.set_echo(false);
+    for _ in 0..INPUT_LIMIT + 10 {
+        tty.input('x');
+    }
+    // The newline still fits, so the line can be read
+    tty.input('\n');
+    let line = block_on(tty.read_line()).unwrap();
+    assert_eq!(line.len(), INPUT_LIMIT - 1);
+    tty.set_echo(true);
+}